@@ -0,0 +1,562 @@
+//! Runtime configuration of the pad
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Channels, MAX_CHANNELS};
+
+/// Upper bound for the serialized length of a `PadConfig`
+pub const MAX_CONFIG_LEN: usize = 2304;
+
+/// Upper bound for the length of a USB string in bytes
+pub const MAX_USB_STRING_LEN: usize = 32;
+
+/// A string in the USB device descriptors
+pub type UsbString = heapless::String<MAX_USB_STRING_LEN>;
+
+/// Press and release levels of one channel, in raw ADC counts above the channel's baseline, or in
+/// grams for a channel with a `ForceModel`
+///
+/// The baseline is the idle level of the sensor, captured at power-on and tracked while the channel
+/// is released. A released channel becomes pressed once its value reaches `press` above the
+/// baseline, and a pressed channel becomes released once it drops below `release` above it.
+/// Values in between keep the previous state, so that a foot resting near a single threshold does
+/// not cause chattering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Thresholds {
+    pub press: u16,
+    pub release: u16,
+}
+
+impl Thresholds {
+    pub const DEFAULT: Self = Self {
+        press: 512,
+        release: 448,
+    };
+
+    /// A release level above the press level would release a channel the moment it is pressed
+    pub fn is_valid(&self) -> bool {
+        self.release <= self.press
+    }
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Longest window of `Filter::MovingAverage` and `Filter::Median`
+pub const MAX_WINDOW: u8 = 16;
+
+/// Longest time constant of `Filter::Ema`, as a power of two
+pub const MAX_EMA_SHIFT: u8 = 8;
+
+/// Smoothing applied to the samples of one channel before anything else looks at them
+///
+/// Every filter delays the response to a change in force. `latency` bounds that delay, and is
+/// documented for each variant as the number of samples, after the first sample of a full-scale
+/// step, until the output has moved at least halfway.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Filter {
+    /// Raw samples. No latency.
+    #[default]
+    None,
+    /// Mean of the last `len` samples. Latency of `ceil(len / 2) - 1` samples.
+    MovingAverage { len: u8 },
+    /// Exponential moving average, weighing each new sample by 1/2^`shift`. Latency of at most
+    /// `3/4 * 2^shift` samples.
+    Ema { shift: u8 },
+    /// Median of the last `len` samples, which removes impulses of up to `(len - 1) / 2` samples
+    /// altogether. Latency of `ceil(len / 2) - 1` samples.
+    Median { len: u8 },
+    /// Holds back a sample that jumps by more than `max_step` from the previous output, until the
+    /// next sample confirms the jump. Latency of one sample for larger steps, none for smaller.
+    SpikeReject { max_step: u16 },
+}
+
+impl Filter {
+    /// Upper bound for the number of samples between a full-scale step and the output moving
+    /// halfway, as documented for each variant
+    pub fn latency(&self) -> u16 {
+        match *self {
+            Filter::None => 0,
+            Filter::MovingAverage { len } | Filter::Median { len } => {
+                u16::from(len).div_ceil(2) - 1
+            }
+            Filter::Ema { shift } => (3 << shift) / 4,
+            Filter::SpikeReject { .. } => 1,
+        }
+    }
+
+    /// Windows must be non-empty and bounded so that the filter state has a fixed size
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Filter::None => true,
+            Filter::MovingAverage { len } | Filter::Median { len } => {
+                (1..=MAX_WINDOW).contains(&len)
+            }
+            Filter::Ema { shift } => shift <= MAX_EMA_SHIFT,
+            Filter::SpikeReject { max_step } => max_step > 0,
+        }
+    }
+}
+
+/// How a panel combines the force on its sensors into its press state
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregation {
+    /// Pressed while any of its sensors is pressed, each by its own `PadConfig::thresholds`
+    #[default]
+    Any,
+    /// The sum of the force on its sensors, against the thresholds of the panel
+    Sum,
+    /// The largest force on any of its sensors, against the thresholds of the panel
+    Max,
+    /// The mean of the force on its sensors weighted by their `PadConfig::weights`, against the
+    /// thresholds of the panel
+    WeightedAverage,
+}
+
+/// Dynamic actuation, like the rapid trigger of Hall effect keyboards, for fast jacks and trills
+///
+/// A pressed panel releases once its force falls `fall` below its peak since the press, and then
+/// presses again once the force rises `rise` above its trough since the release, wherever the
+/// thresholds are. Only a force below the release threshold ends this, after which the next press
+/// is at the press threshold again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RapidTrigger {
+    pub rise: u16,
+    pub fall: u16,
+}
+
+impl RapidTrigger {
+    /// A panel would release the moment it is pressed without a fall, and chatter without a rise
+    pub fn is_valid(&self) -> bool {
+        self.rise > 0 && self.fall > 0
+    }
+}
+
+/// A button of the pad, pressed by the force on one or more sensors
+///
+/// Faulty sensors masked by the `FaultPolicy` drop out of the panel, so that it keeps working on
+/// its other sensors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Panel {
+    /// Bit `n` set if sensor channel `n` is under the panel
+    pub sensors: u32,
+    pub rule: Aggregation,
+    /// Press and release levels of the combined force, for every rule but `Aggregation::Any`
+    pub thresholds: Thresholds,
+    /// Dynamic actuation on the combined force, or on every sensor for `Aggregation::Any`
+    pub rapid: Option<RapidTrigger>,
+}
+
+impl Panel {
+    /// A panel over the single sensor channel `channel`
+    pub const fn single(channel: usize) -> Self {
+        Self {
+            sensors: 1 << channel,
+            rule: Aggregation::Any,
+            thresholds: Thresholds::DEFAULT,
+            rapid: None,
+        }
+    }
+
+    /// A panel needs at least one sensor
+    pub fn is_valid(&self) -> bool {
+        self.sensors != 0
+            && self.thresholds.is_valid()
+            && self.rapid.as_ref().is_none_or(RapidTrigger::is_valid)
+    }
+}
+
+/// Most points in a `ForceTable`
+pub const MAX_FORCE_POINTS: usize = 8;
+
+/// Most `ForceTable`s in a configuration
+pub const MAX_FORCE_TABLES: usize = 4;
+
+/// A point of a measured force curve: the conductance of the FSR, in µS, under `grams`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForcePoint {
+    pub microsiemens: u32,
+    pub grams: u16,
+}
+
+/// Force curve of a kind of FSR, through points in increasing order of conductance
+///
+/// The force is interpolated linearly between points, scaled from zero below the first one and
+/// held at the last one above it. Conductance rather than resistance keeps the curve of most FSRs
+/// close to a straight line, so that a few points do.
+pub type ForceTable = heapless::Vec<ForcePoint, MAX_FORCE_POINTS>;
+
+/// How force follows the conductance of an FSR
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ForceCurve {
+    /// Proportional to the conductance, as for most FSRs past their break force, in grams per mS
+    Linear { grams_per_ms: u16 },
+    /// Through the points of `PadConfig::force_tables[table]`
+    Table { table: u8 },
+}
+
+/// Estimates the force on an FSR from its reading, so that thresholds are in grams and carry over
+/// between pads
+///
+/// The FSR sits between the supply and the ADC pin, with a resistor of `divider_ohms` from the pin
+/// to ground. The reading gives the voltage across that resistor, and from it the resistance of the
+/// FSR, which `curve` turns into a force. Readings are taken to be of the nominal 3.3 V supply,
+/// which holds for a divider fed from the supply of the ADC.
+///
+/// The estimate saturates at 65535 g, while the pressure axes span 4095 g above the baseline,
+/// wherever the baseline rests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForceModel {
+    pub divider_ohms: u32,
+    pub curve: ForceCurve,
+}
+
+impl ForceModel {
+    /// The divider needs a resistor, and a table curve one of the `tables`
+    pub fn is_valid(&self, tables: &[ForceTable]) -> bool {
+        self.divider_ohms > 0
+            && match self.curve {
+                ForceCurve::Linear { .. } => true,
+                ForceCurve::Table { table } => tables.get(usize::from(table)).is_some(),
+            }
+    }
+}
+
+/// A table needs a point, in strictly increasing order of conductance and not decreasing in force
+fn is_valid_table(table: &ForceTable) -> bool {
+    !table.is_empty()
+        && table.windows(2).all(|pair| {
+            pair[0].microsiemens < pair[1].microsiemens && pair[0].grams <= pair[1].grams
+        })
+}
+
+/// Counts of an HX711 per kg of a load cell until it is calibrated, about those of a 50 kg half
+/// bridge cell
+pub const DEFAULT_COUNTS_PER_KG: i32 = 21_000;
+
+/// Most entries in `PadConfig::crosstalk`, enough for 4 panels of 4 sensors each leaking into all
+/// 12 sensors of the other panels
+pub const MAX_CROSSTALK: usize = 48;
+
+/// A share of the force on one panel that shows up on a sensor channel outside it, e.g. as the
+/// frame of the pad flexes under a stomp
+///
+/// The share is taken of the force on all the sensors of the panel together, and subtracted from
+/// the force on `to` before it is compared against any threshold, so that a neighbouring panel is
+/// not pressed by a stomp next to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Crosstalk {
+    /// Panel the force is on
+    pub panel: u8,
+    /// Channel it shows up on
+    pub to: u8,
+    /// Share of the force on `panel` that shows up on `to`, in 1/256
+    pub gain: u8,
+}
+
+/// What the analog axes of the HID report carry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalogMode {
+    /// All axes rest at zero
+    #[default]
+    Off,
+    /// The pressure on each sensor, on an axis of its own. An axis spans what is left of the ADC
+    /// above the baseline, or 4095 g for a sensor with a `ForceModel`.
+    Pressure,
+    /// The force-weighted mean of the `positions` of the pressed sensors, on X and Y
+    CenterOfPressure,
+}
+
+/// Where a sensor sits on the pad, with x pointing right and y pointing towards the player
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub x: i8,
+    pub y: i8,
+}
+
+impl Position {
+    /// Default positions of the first sensors, in the usual left, down, up, right panel order
+    pub const ARROWS: [Position; 4] = [
+        Position { x: -127, y: 0 },
+        Position { x: 0, y: 127 },
+        Position { x: 0, y: -127 },
+        Position { x: 127, y: 0 },
+    ];
+}
+
+/// The kind of USB device the pad presents itself as
+///
+/// The personality only takes effect when the pad enumerates. A `SetConfig` that changes it saves
+/// the configuration and restarts the pad.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Personality {
+    /// A joystick with a button per sensor, and analog axes as set by `AnalogMode`
+    #[default]
+    Joystick,
+    /// An NKRO keyboard, compatible with the boot protocol, that presses the `keys` of the pressed
+    /// sensors
+    Keyboard,
+}
+
+/// HID keyboard usage IDs of the arrow keys, in the usual left, down, up, right panel order
+pub const ARROW_KEYS: [u8; 4] = [0x50, 0x51, 0x52, 0x4f];
+
+/// Corrections for the conditions the pad runs in, measured through the internal channels of the
+/// ADC
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compensation {
+    /// Scale readings to a nominal 3.3 V supply, as measured through VREFINT
+    ///
+    /// Only for sensors fed from something other than the supply of the ADC, e.g. from USB 5V. A
+    /// divider fed from the ADC supply is ratiometric, so supply droop cancels out on its own.
+    pub supply: bool,
+    /// Change of the sensor baselines per degree Celsius of die temperature, in 1/16 ADC counts.
+    /// Baselines follow temperature this way even while a panel is held down. 0 disables it.
+    /// Channels with a `ForceModel` are not moved.
+    pub temperature: i16,
+}
+
+/// What becomes of a sensor the pad finds faulty, see `SensorHealth`
+///
+/// Faults are reported to the host and shown on the status LED whatever the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FaultPolicy {
+    /// Keep using the sensor as if nothing was wrong
+    Report,
+    /// Treat the sensor as released for as long as the fault lasts, so that e.g. a sensor stuck
+    /// at full scale does not hold its panel down
+    #[default]
+    Mask,
+}
+
+/// Which way the internal resistor of a switch input pulls the pin
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pull {
+    /// For switches with a resistor of their own
+    None,
+    /// For switches to ground
+    #[default]
+    Up,
+    /// For switches to the supply
+    Down,
+}
+
+/// A digital input next to the sensors, such as a tape switch, microswitch or arcade button
+///
+/// Switches press buttons of their own, numbered after those of the panels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Switch {
+    pub pull: Pull,
+    /// Pressed while the pin is low, as for a switch to ground
+    pub active_low: bool,
+    /// Time in ms after the switch changes state in which it ignores its pin, so that contact
+    /// bounce neither repeats nor cuts short a press
+    pub debounce_ms: u8,
+}
+
+impl Switch {
+    /// A switch to ground, pulled up, with a debounce long enough for most mechanical switches
+    pub const DEFAULT: Self = Self {
+        pull: Pull::Up,
+        active_low: true,
+        debounce_ms: 5,
+    };
+}
+
+impl Default for Switch {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Overrides for the strings the pad identifies itself with over USB
+///
+/// Strings left at `None` keep the firmware defaults. The default serial number is derived from the
+/// unique ID of the chip, so that the host can tell several pads apart. Like the `Personality`,
+/// the strings only take effect when the pad enumerates.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsbStrings {
+    pub manufacturer: Option<UsbString>,
+    pub product: Option<UsbString>,
+    pub serial_number: Option<UsbString>,
+}
+
+impl UsbStrings {
+    /// Whether every override is non-empty
+    pub fn is_valid(&self) -> bool {
+        [&self.manufacturer, &self.product, &self.serial_number]
+            .into_iter()
+            .flatten()
+            .all(|s| !s.is_empty())
+    }
+}
+
+/// Settings that can be changed while the pad is running
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PadConfig {
+    /// One entry per sensor channel
+    pub thresholds: Channels<Thresholds>,
+    /// One entry per sensor channel
+    pub filters: Channels<Filter>,
+    /// One entry per sensor channel
+    pub positions: Channels<Position>,
+    /// The buttons of the pad, at most one per sensor channel
+    pub panels: Channels<Panel>,
+    /// Relative weight of each sensor channel in `Aggregation::WeightedAverage`
+    pub weights: Channels<u8>,
+    pub analog: AnalogMode,
+    pub personality: Personality,
+    /// HID keyboard usage ID sent for each button in the `Keyboard` personality, or 0 for none. One
+    /// entry per sensor channel, of which those past the last panel are unused, followed by one
+    /// per switch.
+    pub keys: Channels<u8>,
+    pub usb: UsbStrings,
+    pub compensation: Compensation,
+    pub faults: FaultPolicy,
+    /// One entry per switch of the pad
+    pub switches: Channels<Switch>,
+    /// One entry per sensor channel, `None` to leave the channel in raw ADC counts
+    pub force: Channels<Option<ForceModel>>,
+    /// Measured curves for `ForceCurve::Table`
+    pub force_tables: heapless::Vec<ForceTable, MAX_FORCE_TABLES>,
+    /// Shares of the force on a panel that leak into sensor channels outside it, at most one per
+    /// panel and channel, in any order
+    pub crosstalk: heapless::Vec<Crosstalk, MAX_CROSSTALK>,
+    /// Counts of the HX711 per kg of the load cell on each sensor channel, negative for a cell
+    /// mounted upside down. One entry per sensor channel, unused on pads without load cells.
+    pub scales: Channels<i32>,
+}
+
+impl PadConfig {
+    /// Schema version of the persisted configuration, bumped whenever the fields or their meaning
+    /// change
+    ///
+    /// Version 2 made thresholds relative to the baseline, version 3 added filters, version 4 added
+    /// positions and the analog mode, version 5 the personality and keys, version 6 the USB strings,
+    /// version 7 compensation, version 8 the fault policy, version 9 panels, version 10 switches,
+    /// version 11 force models, version 12 rapid trigger, version 13 crosstalk and version 14 the
+    /// scales of the load cells.
+    pub const VERSION: u16 = 14;
+
+    /// Default configuration for a pad with `channels` sensor channels, each under a panel of its
+    /// own, and no switches
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channels` is greater than `MAX_CHANNELS`.
+    pub fn new(channels: usize) -> Self {
+        assert!(
+            channels <= MAX_CHANNELS,
+            "channel count exceeds MAX_CHANNELS"
+        );
+        let mut thresholds = Channels::new();
+        thresholds.resize(channels, Thresholds::DEFAULT).unwrap();
+        let mut filters = Channels::new();
+        filters.resize(channels, Filter::None).unwrap();
+        let mut positions = Channels::new();
+        positions.resize(channels, Position::default()).unwrap();
+        for (position, arrow) in positions.iter_mut().zip(Position::ARROWS) {
+            *position = arrow;
+        }
+        let panels = (0..channels).map(Panel::single).collect();
+        let mut weights = Channels::new();
+        weights.resize(channels, 1).unwrap();
+        let mut keys = Channels::new();
+        keys.resize(channels, 0).unwrap();
+        let mut force = Channels::new();
+        force.resize(channels, None).unwrap();
+        let mut scales = Channels::new();
+        scales.resize(channels, DEFAULT_COUNTS_PER_KG).unwrap();
+        for (key, arrow) in keys.iter_mut().zip(ARROW_KEYS) {
+            *key = arrow;
+        }
+        Self {
+            thresholds,
+            filters,
+            positions,
+            panels,
+            weights,
+            analog: AnalogMode::Off,
+            personality: Personality::Joystick,
+            keys,
+            usb: UsbStrings::default(),
+            compensation: Compensation::default(),
+            faults: FaultPolicy::default(),
+            switches: Channels::new(),
+            force,
+            force_tables: heapless::Vec::new(),
+            crosstalk: heapless::Vec::new(),
+            scales,
+        }
+    }
+
+    /// Adds `switches` switches with the default settings and no key
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than `MAX_CHANNELS` switches, or if the buttons of the sensor
+    /// channels and the switches add up to more than `MAX_CHANNELS`.
+    pub fn with_switches(mut self, switches: usize) -> Self {
+        for _ in 0..switches {
+            self.switches
+                .push(Switch::DEFAULT)
+                .expect("switch count exceeds MAX_CHANNELS");
+            self.keys
+                .push(0)
+                .expect("button count exceeds MAX_CHANNELS");
+        }
+        self
+    }
+
+    /// Whether every per-channel setting has an entry for exactly `channels` channels, the panels
+    /// and crosstalk use no others, and there are exactly `switches` switches
+    ///
+    /// Crosstalk must also come from a panel that exists, and not leak into one of its own
+    /// sensors.
+    pub fn has_inputs(&self, channels: usize, switches: usize) -> bool {
+        self.thresholds.len() == channels
+            && self.filters.len() == channels
+            && self.positions.len() == channels
+            && self.weights.len() == channels
+            && self.force.len() == channels
+            && self.scales.len() == channels
+            && self.switches.len() == switches
+            && self.keys.len() == channels + switches
+            && self.panels.len() <= channels
+            && self
+                .panels
+                .iter()
+                .all(|panel| panel.sensors.checked_shr(channels as u32).unwrap_or(0) == 0)
+            && self
+                .crosstalk
+                .iter()
+                .all(|c| match self.panels.get(usize::from(c.panel)) {
+                    Some(panel) => usize::from(c.to) < channels && panel.sensors & (1 << c.to) == 0,
+                    None => false,
+                })
+    }
+
+    /// Checks the settings for consistency, regardless of the pad they are applied to
+    pub fn is_valid(&self) -> bool {
+        self.thresholds.iter().all(Thresholds::is_valid)
+            && self.filters.iter().all(Filter::is_valid)
+            && self.panels.iter().all(Panel::is_valid)
+            && self.usb.is_valid()
+            && self.force_tables.iter().all(is_valid_table)
+            && self.scales.iter().all(|scale| *scale != 0)
+            && self
+                .force
+                .iter()
+                .flatten()
+                .all(|model| model.is_valid(&self.force_tables))
+    }
+
+    /// Whether the pad enumerates the same with `other` as with this configuration, so that
+    /// switching between them needs no restart
+    pub fn enumerates_like(&self, other: &PadConfig) -> bool {
+        self.personality == other.personality && self.usb == other.usb
+    }
+}
@@ -0,0 +1,121 @@
+//! Framing of `Command` and `Response` messages on a byte stream
+//!
+//! Each message is prefixed with `PROTOCOL_VERSION`, serialized with postcard and COBS-encoded,
+//! so that a frame never contains a zero byte except for the delimiter at its end. A receiver can
+//! thus resynchronize on the next zero byte after garbage or a dropped frame.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{config::MAX_CONFIG_LEN, PROTOCOL_VERSION};
+
+/// Upper bound for the length of an encoded frame, including the delimiter
+///
+/// Leaves room for a whole `PadConfig` along with the version, message tag and COBS overhead.
+pub const MAX_FRAME_LEN: usize = MAX_CONFIG_LEN + 16;
+
+const DELIMITER: u8 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The output buffer is too small for the encoded message
+    BufferFull,
+    /// The frame is not valid COBS or does not hold the expected message type
+    Malformed,
+    /// The frame did not fit in the receive buffer and was dropped
+    Overflow,
+    /// The frame was encoded with another protocol version
+    Version(u16),
+}
+
+/// Encodes `msg` into `buf` as a single delimited frame
+///
+/// Returns the part of `buf` holding the frame.
+pub fn encode<'a, T: Serialize>(msg: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], FrameError> {
+    postcard::to_slice_cobs(&(PROTOCOL_VERSION, msg), buf).map_err(|e| match e {
+        postcard::Error::SerializeBufferFull => FrameError::BufferFull,
+        _ => FrameError::Malformed,
+    })
+}
+
+/// Encodes `msg` as a single delimited frame
+#[cfg(feature = "host")]
+pub fn encode_vec<T: Serialize>(msg: &T) -> Vec<u8> {
+    // Serializing into a growable buffer only fails on types postcard does not support, none of
+    // which are part of the protocol
+    postcard::to_stdvec_cobs(&(PROTOCOL_VERSION, msg)).expect("protocol types are serializable")
+}
+
+/// Decodes a single frame, with or without its trailing delimiter
+///
+/// The frame is decoded in place, so its contents are clobbered even when decoding fails.
+pub fn decode<T: DeserializeOwned>(frame: &mut [u8]) -> Result<T, FrameError> {
+    let len = cobs::decode_in_place(frame).map_err(|_| FrameError::Malformed)?;
+    let (version, body) =
+        postcard::take_from_bytes::<u16>(&frame[..len]).map_err(|_| FrameError::Malformed)?;
+    if version != PROTOCOL_VERSION {
+        return Err(FrameError::Version(version));
+    }
+    postcard::from_bytes(body).map_err(|_| FrameError::Malformed)
+}
+
+/// A frame completed by `FrameBuffer::push`, or the reason it was dropped
+pub type Received<'a> = Result<&'a mut [u8], FrameError>;
+
+/// Collects bytes from a stream until a whole frame has been received
+///
+/// # Type arguments
+///
+/// * `N` - the longest frame that can be received, excluding the delimiter.
+pub struct FrameBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> FrameBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Appends `bytes` to the buffer, up to and including the first delimiter
+    ///
+    /// Returns `None` if `bytes` did not complete a frame; they have all been consumed.
+    /// Otherwise returns the completed frame, which can be passed to `decode`, along with the bytes
+    /// that were not consumed yet. Frames that do not fit in the buffer are reported as
+    /// `FrameError::Overflow`.
+    pub fn push<'a>(&mut self, bytes: &'a [u8]) -> Option<(Received<'_>, &'a [u8])> {
+        let (body, rest) = match bytes.iter().position(|b| *b == DELIMITER) {
+            Some(idx) => (&bytes[..idx], Some(&bytes[idx + 1..])),
+            None => (bytes, None),
+        };
+
+        if !self.overflow {
+            match self.buf.get_mut(self.len..self.len + body.len()) {
+                Some(dst) => {
+                    dst.copy_from_slice(body);
+                    self.len += body.len();
+                }
+                None => self.overflow = true,
+            }
+        }
+
+        let rest = rest?;
+        let len = core::mem::take(&mut self.len);
+        let frame = if core::mem::take(&mut self.overflow) {
+            Err(FrameError::Overflow)
+        } else {
+            Ok(&mut self.buf[..len])
+        };
+        Some((frame, rest))
+    }
+}
+
+impl<const N: usize> Default for FrameBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,12 +1,431 @@
-#![cfg_attr(feature = "device", no_std)]
+#![cfg_attr(not(any(feature = "host", test)), no_std)]
 
-/// ADC values in millivolts (16-bit)
+mod config;
+mod frame;
+mod store;
+
+pub use config::{
+    Aggregation, AnalogMode, Compensation, Crosstalk, FaultPolicy, Filter, ForceCurve, ForceModel,
+    ForcePoint, ForceTable, PadConfig, Panel, Personality, Position, Pull, RapidTrigger, Switch,
+    Thresholds, UsbString, UsbStrings, ARROW_KEYS, DEFAULT_COUNTS_PER_KG, MAX_CONFIG_LEN,
+    MAX_CROSSTALK, MAX_EMA_SHIFT, MAX_FORCE_POINTS, MAX_FORCE_TABLES, MAX_USB_STRING_LEN,
+    MAX_WINDOW,
+};
+pub use store::{ConfigStore, StoreError};
+
+#[cfg(feature = "host")]
+pub use frame::encode_vec;
+pub use frame::{decode, encode, FrameBuffer, FrameError, Received, MAX_FRAME_LEN};
+
+use serde::{Deserialize, Serialize};
+
+/// A value per sensor channel, in raw ADC counts or, for a channel with a `ForceModel`, in grams
 ///
 /// # Type arguments
 ///
 /// * `N` - number of supported ADC channels and values.
 pub type AdcValues<const N: usize> = [u16; N];
 
-pub enum Command {}
+/// Version of the wire protocol, bumped whenever `Command` or `Response` change shape
+pub const PROTOCOL_VERSION: u16 = 9;
+
+/// Upper bound for the number of sensor channels carried in a single message
+pub const MAX_CHANNELS: usize = 32;
+
+/// Per-channel values carried over the wire, one entry per sensor channel
+pub type Channels<T> = heapless::Vec<T, MAX_CHANNELS>;
+
+/// USB vendor ID of the pad, see <https://pid.codes>
+pub const USB_VID: u16 = 0x1209;
+
+/// USB product ID of the pad
+pub const USB_PID: u16 = 0x0001;
+
+/// A request sent from the host to the pad
+// There is no allocator on the pad to box the configuration into
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Query firmware and protocol information
+    GetInfo,
+    /// Read the press and release thresholds of every channel
+    GetThresholds,
+    /// Set the press and release thresholds of one channel
+    SetThresholds { channel: u8, thresholds: Thresholds },
+    /// Read the latest filtered value of every channel, in ADC counts or, for a channel with a
+    /// `ForceModel`, in grams
+    GetValues,
+    /// Read the idle level of every channel, which thresholds are relative to
+    GetBaselines,
+    /// Read the USB error counters
+    GetUsbErrors,
+    /// Read the latency statistics of the reports sent since power-on or the last `ResetLatency`
+    GetLatency,
+    /// Start over collecting latency statistics
+    ResetLatency,
+    /// Read the supply voltage and die temperature
+    GetConditions,
+    /// Read the health of every sensor
+    GetHealth,
+    /// Read the whole active configuration
+    GetConfig,
+    /// Replace the whole active configuration. It is not persisted until `SaveConfig`.
+    SetConfig(PadConfig),
+    /// Write the active configuration to persistent storage
+    SaveConfig,
+    /// Replace the active configuration with the persisted one
+    LoadConfig,
+    /// Reset the device. The response is sent before the reset takes place.
+    Reboot,
+    /// Take the weight on every load cell over the next samples as its weight when unloaded. The
+    /// cells must be unloaded until their values come back. Pads without load cells answer with
+    /// `Error::Unsupported`.
+    Tare,
+}
+
+/// A reply sent from the pad to the host, one per `Command`
+// There is no allocator on the pad to box the configuration into
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    Info(FirmwareInfo),
+    Thresholds(Channels<Thresholds>),
+    Values(Channels<u16>),
+    /// Idle level of every channel, in ADC counts or, for a channel with a `ForceModel`, in grams.
+    /// Empty until the baselines have been captured after power-on.
+    Baselines(Channels<u16>),
+    UsbErrors(UsbErrors),
+    Latency(Latency),
+    Conditions(Conditions),
+    Health(Channels<SensorHealth>),
+    Config(PadConfig),
+    /// The command was carried out and has nothing to return
+    Ok,
+    Error(Error),
+}
+
+/// Identifies the firmware running on the pad
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareInfo {
+    pub protocol_version: u16,
+    /// Cargo package version of the firmware, e.g. "0.1.0"
+    pub firmware_version: heapless::String<16>,
+    /// Number of sensor channels sampled by the firmware
+    pub channels: u8,
+}
+
+/// Supply voltage and temperature the pad runs at, as measured through the internal channels of
+/// the ADC
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conditions {
+    /// Supply voltage of the ADC in millivolts
+    pub vdda_mv: u16,
+    /// Die temperature in tenths of a degree Celsius
+    pub temperature: i16,
+}
+
+/// What the pad makes of the readings of one sensor
+///
+/// A fault lasts for as long as its cause, and clears with the first reading that contradicts it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SensorHealth {
+    #[default]
+    Healthy,
+    /// Reading 0 for seconds on end, as a disconnected sensor or a short to ground does
+    StuckLow,
+    /// Reading full scale for seconds on end, as a sensor shorted to the supply does
+    StuckHigh,
+    /// Reading the very same value for seconds on end, which the noise of a connected sensor rules
+    /// out
+    Flat,
+    /// Idling so close to full scale that the press threshold is out of reach
+    BaselineOutOfRange,
+}
+
+impl SensorHealth {
+    pub fn is_faulty(&self) -> bool {
+        *self != SensorHealth::Healthy
+    }
+}
+
+/// Problems the pad ran into on the USB bus since power-on
+///
+/// The pad recovers from a run of failed writes by resetting the HID class, and if that does not
+/// help by detaching from the bus, so that the host enumerates it afresh.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsbErrors {
+    /// Report writes put off because the host had not picked up the previous report yet
+    pub dropped_reports: u32,
+    /// Reports the USB peripheral failed to send
+    pub bus: u32,
+    /// Reports that did not fit the report descriptor
+    pub serialization: u32,
+    /// Failed reads and writes on the configuration channel
+    pub serial: u32,
+    /// Recoveries by resetting the HID class
+    pub class_resets: u32,
+    /// Recoveries by detaching from the bus
+    pub device_resets: u32,
+}
+
+/// Time from the sample that changed the state of the pad to the report carrying that state
+/// being handed to the USB peripheral, in microseconds
+///
+/// The host picks the report up with its next poll of the endpoint, at most one frame later.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Latency {
+    /// Number of reports the statistics cover
+    pub reports: u32,
+    pub min_us: u32,
+    pub mean_us: u32,
+    pub max_us: u32,
+}
+
+/// Reasons for the pad to refuse a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Error {
+    /// The frame could not be decoded as a `Command`
+    Malformed,
+    /// The frame was encoded with a different protocol version
+    UnsupportedVersion,
+    /// The command referred to a channel the pad does not have
+    InvalidChannel,
+    /// A value was outside the range accepted by the pad
+    InvalidValue,
+    /// Persistent storage could not be read or written
+    Storage,
+    /// The pad has nothing to carry the command out on, e.g. `Tare` on a pad without load cells
+    Unsupported,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T>(msg: T)
+    where
+        T: Serialize + for<'de> Deserialize<'de> + PartialEq + core::fmt::Debug,
+    {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let frame = encode(&msg, &mut buf).unwrap();
+        assert_eq!(frame.last(), Some(&0), "frame must end in a delimiter");
+        assert!(
+            !frame[..frame.len() - 1].contains(&0),
+            "frame must not contain a delimiter before its end"
+        );
+        assert_eq!(decode::<T>(frame).unwrap(), msg);
+    }
+
+    fn channels(vals: &[u16]) -> Channels<u16> {
+        Channels::from_slice(vals).unwrap()
+    }
+
+    #[test]
+    fn command_round_trip() {
+        for cmd in [
+            Command::GetInfo,
+            Command::GetThresholds,
+            Command::SetThresholds {
+                channel: 3,
+                thresholds: Thresholds {
+                    press: 4095,
+                    release: 0,
+                },
+            },
+            Command::GetValues,
+            Command::GetBaselines,
+            Command::GetUsbErrors,
+            Command::GetLatency,
+            Command::ResetLatency,
+            Command::GetConditions,
+            Command::GetHealth,
+            Command::GetConfig,
+            Command::SetConfig(PadConfig::new(4).with_switches(2)),
+            Command::SaveConfig,
+            Command::LoadConfig,
+            Command::Reboot,
+            Command::Tare,
+        ] {
+            round_trip(cmd);
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        for resp in [
+            Response::Info(FirmwareInfo {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: "0.1.0".try_into().unwrap(),
+                channels: 4,
+            }),
+            Response::Thresholds(PadConfig::new(4).thresholds),
+            Response::Values(channels(&[0, 1, u16::MAX, 3300])),
+            Response::Values(Channels::new()),
+            Response::Baselines(channels(&[310, 295, 4095, 0])),
+            Response::Baselines(Channels::new()),
+            Response::UsbErrors(UsbErrors {
+                dropped_reports: 12,
+                bus: 3,
+                class_resets: 1,
+                ..Default::default()
+            }),
+            Response::Conditions(Conditions {
+                vdda_mv: 3289,
+                temperature: -105,
+            }),
+            Response::Health(
+                Channels::from_slice(&[
+                    SensorHealth::Healthy,
+                    SensorHealth::StuckLow,
+                    SensorHealth::StuckHigh,
+                    SensorHealth::Flat,
+                    SensorHealth::BaselineOutOfRange,
+                ])
+                .unwrap(),
+            ),
+            Response::Latency(Latency {
+                reports: 1000,
+                min_us: 10,
+                mean_us: 120,
+                max_us: 990,
+            }),
+            Response::Config(PadConfig::new(4)),
+            Response::Ok,
+            Response::Error(Error::Malformed),
+            Response::Error(Error::UnsupportedVersion),
+            Response::Error(Error::InvalidChannel),
+            Response::Error(Error::InvalidValue),
+            Response::Error(Error::Storage),
+            Response::Error(Error::Unsupported),
+        ] {
+            round_trip(resp);
+        }
+    }
+
+    /// A configuration with every channel in use and every field at its longest encoding
+    /// A switch takes far fewer bytes than a sensor channel, so the largest pad has nothing but
+    /// sensors
+    fn largest_config() -> PadConfig {
+        let mut config = PadConfig::new(MAX_CHANNELS);
+        config.thresholds.fill(Thresholds {
+            press: u16::MAX,
+            release: u16::MAX,
+        });
+        config
+            .filters
+            .fill(Filter::SpikeReject { max_step: u16::MAX });
+        config.analog = AnalogMode::CenterOfPressure;
+        config.personality = Personality::Keyboard;
+        config.compensation = Compensation {
+            supply: true,
+            temperature: i16::MIN,
+        };
+        config.panels.fill(Panel {
+            sensors: u32::MAX,
+            rule: Aggregation::WeightedAverage,
+            thresholds: Thresholds {
+                press: u16::MAX,
+                release: u16::MAX,
+            },
+            rapid: Some(RapidTrigger {
+                rise: u16::MAX,
+                fall: u16::MAX,
+            }),
+        });
+        config.weights.fill(u8::MAX);
+        config.keys.fill(u8::MAX);
+        config.faults = FaultPolicy::Report;
+        config.force.fill(Some(ForceModel {
+            divider_ohms: u32::MAX,
+            curve: ForceCurve::Linear {
+                grams_per_ms: u16::MAX,
+            },
+        }));
+        let table: ForceTable = (0..MAX_FORCE_POINTS)
+            .map(|_| ForcePoint {
+                microsiemens: u32::MAX,
+                grams: u16::MAX,
+            })
+            .collect();
+        config.force_tables = (0..MAX_FORCE_TABLES).map(|_| table.clone()).collect();
+        config.crosstalk = (0..MAX_CROSSTALK)
+            .map(|_| Crosstalk {
+                panel: u8::MAX,
+                to: u8::MAX,
+                gain: u8::MAX,
+            })
+            .collect();
+        config.scales.fill(i32::MIN);
+        let longest =
+            || Some(UsbString::try_from("x".repeat(MAX_USB_STRING_LEN).as_str()).unwrap());
+        config.usb = UsbStrings {
+            manufacturer: longest(),
+            product: longest(),
+            serial_number: longest(),
+        };
+        config
+    }
+
+    #[test]
+    fn largest_messages_fit_in_a_frame() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let full = Response::Values(Channels::from_slice(&[u16::MAX; MAX_CHANNELS]).unwrap());
+        assert!(encode(&full, &mut buf).is_ok());
+        assert!(encode(&Response::Config(largest_config()), &mut buf).is_ok());
+        assert!(encode(&Command::SetConfig(largest_config()), &mut buf).is_ok());
+
+        let mut buf = [0u8; MAX_CONFIG_LEN];
+        assert!(postcard::to_slice(&largest_config(), &mut buf).is_ok());
+    }
+
+    #[test]
+    fn version_mismatch_is_rejected() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let frame =
+            postcard::to_slice_cobs(&(PROTOCOL_VERSION + 1, Command::GetInfo), &mut buf).unwrap();
+        assert_eq!(
+            decode::<Command>(frame),
+            Err(FrameError::Version(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn frame_buffer_splits_stream() {
+        let mut stream = [0u8; 2 * MAX_FRAME_LEN];
+        let mut len = 0;
+        for cmd in [Command::GetValues, Command::Reboot] {
+            len += encode(&cmd, &mut stream[len..]).unwrap().len();
+        }
+
+        let mut rx = FrameBuffer::<MAX_FRAME_LEN>::new();
+        let mut received = std::vec::Vec::new();
+        // Feed in small chunks, as a USB endpoint would
+        for chunk in stream[..len].chunks(3) {
+            let mut rest = chunk;
+            while let Some((frame, tail)) = rx.push(rest) {
+                received.push(decode::<Command>(frame.unwrap()).unwrap());
+                rest = tail;
+            }
+        }
+        assert_eq!(received, [Command::GetValues, Command::Reboot]);
+    }
+
+    #[test]
+    fn frame_buffer_drops_oversized_frame() {
+        let mut rx = FrameBuffer::<8>::new();
+        assert!(rx.push(&[1; 20]).is_none());
+
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let frame = encode(&Command::GetInfo, &mut buf).unwrap();
+        let (frame, _) = rx.push(frame).unwrap();
+        assert_eq!(
+            frame.err(),
+            Some(FrameError::Overflow),
+            "the remainder of an oversized frame must not be decoded"
+        );
 
-pub enum Response {}
+        let frame = encode(&Command::GetInfo, &mut buf).unwrap();
+        let (frame, _) = rx.push(frame).unwrap();
+        assert_eq!(decode::<Command>(frame.unwrap()), Ok(Command::GetInfo));
+    }
+}
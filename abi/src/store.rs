@@ -0,0 +1,402 @@
+//! Persistent storage of `PadConfig` in NOR flash
+//!
+//! The configuration is kept as an append-only log of records in a region of flash that spans one
+//! or more erase blocks. Saving appends a new record after the previous ones and loading picks the
+//! last intact record, so the region only needs to be erased once it is full. This matters on
+//! parts like the STM32F411, where a sector survives only about 10k erase cycles and erasing one
+//! stalls the CPU for up to a couple of seconds.
+//!
+//! Each record is laid out as follows, padded with erased bytes to a multiple of `ALIGN`:
+//!
+//! | Field     | Size | Notes                                             |
+//! | --------- | ---- | ------------------------------------------------- |
+//! | magic     | 4    | `MAGIC`, little-endian                            |
+//! | version   | 2    | `PadConfig::VERSION` at the time of writing       |
+//! | len       | 2    | length of the payload                             |
+//! | payload   | len  | postcard-serialized `PadConfig`                   |
+//! | crc       | 4    | CRC-32 of version, len and payload, little-endian |
+//!
+//! Records with a bad CRC, e.g. from a write interrupted by a power loss, and records of another
+//! schema version are skipped when loading.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::{config::MAX_CONFIG_LEN, PadConfig};
+
+/// Marks the start of a record
+const MAGIC: u32 = 0xDA9C_0F16;
+
+/// Value of erased NOR flash
+const ERASED: u8 = 0xFF;
+
+const HEADER_LEN: usize = 8;
+const CRC_LEN: usize = 4;
+
+/// Records start at offsets that are a multiple of this
+pub const ALIGN: usize = 4;
+
+const MAX_RECORD_LEN: usize = record_len(MAX_CONFIG_LEN);
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Length of a record holding a payload of `len` bytes, including padding
+const fn record_len(len: usize) -> usize {
+    (HEADER_LEN + len + CRC_LEN).next_multiple_of(ALIGN)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreError<E> {
+    /// The flash driver failed
+    Flash(E),
+    /// The configuration does not serialize into `MAX_CONFIG_LEN` bytes
+    TooLarge,
+}
+
+impl<E> From<E> for StoreError<E> {
+    fn from(e: E) -> Self {
+        StoreError::Flash(e)
+    }
+}
+
+/// Outcome of scanning the log
+struct Scan {
+    /// Offset and payload length of the last intact record of the current schema
+    latest: Option<(u32, usize)>,
+    /// Offset right after the last record
+    end: u32,
+}
+
+/// A configuration log in the flash region `start..end`
+///
+/// Offsets are relative to the start of the flash device, as with `NorFlash`. The region must be
+/// aligned to erase blocks, and not be used for anything else.
+#[derive(Clone, Copy, Debug)]
+pub struct ConfigStore {
+    start: u32,
+    end: u32,
+}
+
+impl ConfigStore {
+    pub const fn new(start: u32, end: u32) -> Self {
+        Self { start, end }
+    }
+
+    /// Loads the most recently saved configuration
+    ///
+    /// Returns `None` if nothing has been saved, or if no intact record of the current schema
+    /// version is left. The caller is expected to fall back to defaults in that case.
+    pub fn load<F: ReadNorFlash>(&self, flash: &mut F) -> Result<Option<PadConfig>, F::Error> {
+        let Some((offset, len)) = self.scan(flash)?.latest else {
+            return Ok(None);
+        };
+
+        let mut buf = [0u8; MAX_CONFIG_LEN];
+        let payload = &mut buf[..len];
+        flash.read(offset + HEADER_LEN as u32, payload)?;
+        // The CRC matched, so this only fails if the schema changed without bumping the version
+        Ok(postcard::from_bytes(payload).ok())
+    }
+
+    /// Appends `config` to the log, erasing the region first if the log is full
+    pub fn save<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        config: &PadConfig,
+    ) -> Result<(), StoreError<F::Error>> {
+        debug_assert_eq!(ALIGN % F::WRITE_SIZE, 0);
+
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        let len = postcard::to_slice(config, &mut buf[HEADER_LEN..HEADER_LEN + MAX_CONFIG_LEN])
+            .map_err(|_| StoreError::TooLarge)?
+            .len();
+        buf[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..6].copy_from_slice(&PadConfig::VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        let crc = CRC.checksum(&buf[4..HEADER_LEN + len]);
+        buf[HEADER_LEN + len..HEADER_LEN + len + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        let record = &buf[..record_len(len)];
+
+        let mut offset = self.scan(flash)?.end;
+        // The space after the log may hold the remains of an interrupted write
+        if offset + record.len() as u32 > self.end
+            || !self.is_erased(flash, offset, record.len())?
+        {
+            flash.erase(self.start, self.end)?;
+            offset = self.start;
+        }
+        flash.write(offset, record)?;
+        Ok(())
+    }
+
+    fn scan<F: ReadNorFlash>(&self, flash: &mut F) -> Result<Scan, F::Error> {
+        let mut latest = None;
+        let mut offset = self.start;
+
+        while offset as usize + HEADER_LEN <= self.end as usize {
+            let mut header = [0u8; HEADER_LEN];
+            flash.read(offset, &mut header)?;
+            let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+            let len = u16::from_le_bytes(header[6..8].try_into().unwrap()) as usize;
+
+            // Anything but a well-formed header ends the log. A torn header is caught by `save`,
+            // which does not find erased space after it.
+            if magic != MAGIC
+                || len > MAX_CONFIG_LEN
+                || offset as usize + record_len(len) > self.end as usize
+            {
+                break;
+            }
+
+            if version == PadConfig::VERSION && self.crc_matches(flash, offset, &header, len)? {
+                latest = Some((offset, len));
+            }
+            offset += record_len(len) as u32;
+        }
+
+        Ok(Scan {
+            latest,
+            end: offset,
+        })
+    }
+
+    fn crc_matches<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        offset: u32,
+        header: &[u8; HEADER_LEN],
+        len: usize,
+    ) -> Result<bool, F::Error> {
+        let mut digest = CRC.digest();
+        digest.update(&header[4..]);
+
+        let mut chunk = [0u8; 32];
+        let mut pos = offset + HEADER_LEN as u32;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(chunk.len());
+            flash.read(pos, &mut chunk[..n])?;
+            digest.update(&chunk[..n]);
+            pos += n as u32;
+            remaining -= n;
+        }
+
+        let mut crc = [0u8; CRC_LEN];
+        flash.read(pos, &mut crc)?;
+        Ok(digest.finalize() == u32::from_le_bytes(crc))
+    }
+
+    fn is_erased<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        offset: u32,
+        len: usize,
+    ) -> Result<bool, F::Error> {
+        let mut chunk = [0u8; 32];
+        let mut pos = offset;
+        let end = offset + len as u32;
+        while pos < end {
+            let n = ((end - pos) as usize).min(chunk.len());
+            flash.read(pos, &mut chunk[..n])?;
+            if chunk[..n].iter().any(|b| *b != ERASED) {
+                return Ok(false);
+            }
+            pos += n as u32;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind};
+
+    use super::*;
+    use crate::Thresholds;
+
+    const SECTOR: usize = 1024;
+
+    /// RAM-backed NOR flash, where writes can only clear bits
+    struct RamFlash {
+        mem: Vec<u8>,
+        erases: usize,
+    }
+
+    impl RamFlash {
+        fn new(sectors: usize) -> Self {
+            Self {
+                mem: vec![ERASED; sectors * SECTOR],
+                erases: 0,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct OutOfBounds;
+
+    impl NorFlashError for OutOfBounds {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::OutOfBounds
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = OutOfBounds;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let src = self
+                .mem
+                .get(offset as usize..offset as usize + bytes.len())
+                .ok_or(OutOfBounds)?;
+            bytes.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            assert_eq!(from as usize % SECTOR, 0);
+            assert_eq!(to as usize % SECTOR, 0);
+            self.mem
+                .get_mut(from as usize..to as usize)
+                .ok_or(OutOfBounds)?
+                .fill(ERASED);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let dst = self
+                .mem
+                .get_mut(offset as usize..offset as usize + bytes.len())
+                .ok_or(OutOfBounds)?;
+            for (d, b) in dst.iter_mut().zip(bytes) {
+                *d &= b;
+            }
+            Ok(())
+        }
+    }
+
+    /// A log in the second sector, so that offsets relative to the region are exercised
+    const STORE: ConfigStore = ConfigStore::new(SECTOR as u32, 2 * SECTOR as u32);
+
+    fn config(press: u16) -> PadConfig {
+        let mut config = PadConfig::new(4);
+        config.thresholds[1] = Thresholds {
+            press,
+            release: press / 2,
+        };
+        config
+    }
+
+    fn record_len_of(config: &PadConfig) -> usize {
+        let mut buf = [0u8; MAX_CONFIG_LEN];
+        record_len(postcard::to_slice(config, &mut buf).unwrap().len())
+    }
+
+    #[test]
+    fn empty_store_loads_nothing() {
+        let mut flash = RamFlash::new(2);
+        assert_eq!(STORE.load(&mut flash).unwrap(), None);
+    }
+
+    #[test]
+    fn loads_latest_save() {
+        let mut flash = RamFlash::new(2);
+        for press in [600, 700, 800] {
+            STORE.save(&mut flash, &config(press)).unwrap();
+            assert_eq!(STORE.load(&mut flash).unwrap(), Some(config(press)));
+        }
+        assert_eq!(flash.erases, 0, "appending must not erase");
+        assert!(
+            flash.mem[..SECTOR].iter().all(|b| *b == ERASED),
+            "writes must stay within the region"
+        );
+    }
+
+    #[test]
+    fn erases_only_when_full() {
+        let mut flash = RamFlash::new(2);
+        let per_sector = SECTOR / record_len_of(&config(1000));
+        for press in 0..per_sector as u16 {
+            STORE.save(&mut flash, &config(1000 + press)).unwrap();
+        }
+        assert_eq!(flash.erases, 0);
+
+        STORE.save(&mut flash, &config(2000)).unwrap();
+        assert_eq!(flash.erases, 1);
+        assert_eq!(STORE.load(&mut flash).unwrap(), Some(config(2000)));
+    }
+
+    #[test]
+    fn corrupt_record_falls_back_to_previous() {
+        let mut flash = RamFlash::new(2);
+        STORE.save(&mut flash, &config(600)).unwrap();
+        STORE.save(&mut flash, &config(700)).unwrap();
+
+        // Flip a bit in the payload of the second record
+        let second = SECTOR + record_len_of(&config(600));
+        flash.mem[second + HEADER_LEN + 1] ^= 0x01;
+        assert_eq!(STORE.load(&mut flash).unwrap(), Some(config(600)));
+
+        // The corrupt record stays in the log, new ones go after it
+        STORE.save(&mut flash, &config(800)).unwrap();
+        assert_eq!(STORE.load(&mut flash).unwrap(), Some(config(800)));
+        assert_eq!(flash.erases, 0);
+    }
+
+    #[test]
+    fn older_schema_is_ignored() {
+        let mut flash = RamFlash::new(2);
+        STORE.save(&mut flash, &config(600)).unwrap();
+
+        // Rewrite the version as an older one and fix up the CRC
+        let record = &mut flash.mem[SECTOR..];
+        let len = u16::from_le_bytes(record[6..8].try_into().unwrap()) as usize;
+        record[4..6].copy_from_slice(&(PadConfig::VERSION - 1).to_le_bytes());
+        let crc = CRC.checksum(&record[4..HEADER_LEN + len]);
+        record[HEADER_LEN + len..HEADER_LEN + len + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(STORE.load(&mut flash).unwrap(), None);
+    }
+
+    #[test]
+    fn torn_write_is_erased_before_reuse() {
+        let mut flash = RamFlash::new(2);
+        STORE.save(&mut flash, &config(600)).unwrap();
+
+        // A write that was cut off before the magic was complete
+        let end = SECTOR + record_len_of(&config(600));
+        flash.mem[end] = 0x16;
+        flash.mem[end + 5] = 0x00;
+        assert_eq!(STORE.load(&mut flash).unwrap(), Some(config(600)));
+
+        STORE.save(&mut flash, &config(700)).unwrap();
+        assert_eq!(flash.erases, 1);
+        assert_eq!(STORE.load(&mut flash).unwrap(), Some(config(700)));
+    }
+
+    #[test]
+    fn largest_config_fits() {
+        let mut flash = RamFlash::new(2);
+        let mut config = PadConfig::new(crate::MAX_CHANNELS);
+        config.thresholds.fill(Thresholds {
+            press: u16::MAX,
+            release: u16::MAX,
+        });
+        assert!(STORE.save(&mut flash, &config).is_ok());
+        assert_eq!(STORE.load(&mut flash).unwrap(), Some(config));
+    }
+}
@@ -0,0 +1,319 @@
+//! Host-side access to a pad over its CDC-ACM configuration channel
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use abi::{
+    Command, Conditions, FirmwareInfo, FrameBuffer, FrameError, Latency, PadConfig, Response,
+    SensorHealth, Thresholds, UsbErrors, MAX_FRAME_LEN, USB_PID, USB_VID,
+};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+
+/// How long to wait for the pad to answer a command
+pub const TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Frame(FrameError),
+    /// The pad refused the command
+    Device(abi::Error),
+    /// The pad answered with a response that does not belong to the command
+    Unexpected(Box<Response>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Frame(FrameError::Version(v)) => write!(
+                f,
+                "pad speaks protocol version {v}, expected {}",
+                abi::PROTOCOL_VERSION
+            ),
+            Error::Frame(e) => write!(f, "invalid frame from pad: {e:?}"),
+            Error::Device(e) => write!(f, "pad refused the command: {e:?}"),
+            Error::Unexpected(resp) => write!(f, "unexpected response from pad: {resp:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(e: FrameError) -> Self {
+        Error::Frame(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A pad found on the USB bus
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PadPort {
+    /// Path of the serial port, e.g. `/dev/ttyACM0` or `COM3`
+    pub path: String,
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+}
+
+/// Lists the serial ports that belong to pads
+pub fn find_pads() -> Result<Vec<PadPort>> {
+    Ok(pads(serialport::available_ports()?))
+}
+
+/// Picks the ports of `ports` that have the pad's USB VID and PID
+pub fn pads(ports: Vec<SerialPortInfo>) -> Vec<PadPort> {
+    ports
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) if usb.vid == USB_VID && usb.pid == USB_PID => {
+                Some(PadPort {
+                    path: port.port_name,
+                    serial_number: usb.serial_number,
+                    product: usb.product,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// A connection to a pad
+///
+/// # Type arguments
+///
+/// * `P` - the byte stream to the pad, usually a serial port.
+pub struct Pad<P> {
+    port: P,
+    rx: FrameBuffer<MAX_FRAME_LEN>,
+    /// Bytes received after the last frame
+    pending: Vec<u8>,
+}
+
+impl Pad<Box<dyn SerialPort>> {
+    /// Opens the serial port at `path`
+    pub fn open(path: &str) -> Result<Self> {
+        // CDC-ACM ignores the baud rate, but the OS wants one
+        let port = serialport::new(path, 115_200).timeout(TIMEOUT).open()?;
+        Ok(Self::new(port))
+    }
+}
+
+impl<P: Read + Write> Pad<P> {
+    /// Talks to a pad over `port`, which should time out reads after `TIMEOUT` or so
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            rx: FrameBuffer::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sends `cmd` and waits for the pad to respond
+    ///
+    /// `Response::Error` is turned into `Error::Device`.
+    pub fn request(&mut self, cmd: &Command) -> Result<Response> {
+        self.port.write_all(&abi::encode_vec(cmd))?;
+        self.port.flush()?;
+        match self.receive()? {
+            Response::Error(e) => Err(Error::Device(e)),
+            resp => Ok(resp),
+        }
+    }
+
+    fn receive(&mut self) -> Result<Response> {
+        let mut buf = [0u8; 64];
+        loop {
+            if self.pending.is_empty() {
+                let count = self.port.read(&mut buf)?;
+                if count == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                self.pending.extend_from_slice(&buf[..count]);
+            }
+
+            let pending = std::mem::take(&mut self.pending);
+            if let Some((frame, rest)) = self.rx.push(&pending) {
+                self.pending = rest.to_vec();
+                return Ok(abi::decode(frame?)?);
+            }
+        }
+    }
+
+    /// Sends a command that is answered with `Response::Ok`
+    fn execute(&mut self, cmd: &Command) -> Result<()> {
+        match self.request(cmd)? {
+            Response::Ok => Ok(()),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    pub fn info(&mut self) -> Result<FirmwareInfo> {
+        match self.request(&Command::GetInfo)? {
+            Response::Info(info) => Ok(info),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    pub fn thresholds(&mut self) -> Result<Vec<Thresholds>> {
+        match self.request(&Command::GetThresholds)? {
+            Response::Thresholds(thresholds) => Ok(thresholds.to_vec()),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    pub fn set_thresholds(&mut self, channel: u8, thresholds: Thresholds) -> Result<()> {
+        self.execute(&Command::SetThresholds {
+            channel,
+            thresholds,
+        })
+    }
+
+    /// Latest filtered value of every channel, in ADC counts or, for a channel with a
+    /// `ForceModel`, in grams
+    pub fn values(&mut self) -> Result<Vec<u16>> {
+        match self.request(&Command::GetValues)? {
+            Response::Values(values) => Ok(values.to_vec()),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    /// Idle level of every channel, empty while the pad is still capturing it after power-on
+    pub fn baselines(&mut self) -> Result<Vec<u16>> {
+        match self.request(&Command::GetBaselines)? {
+            Response::Baselines(baselines) => Ok(baselines.to_vec()),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    /// USB errors the pad ran into since power-on
+    pub fn usb_errors(&mut self) -> Result<UsbErrors> {
+        match self.request(&Command::GetUsbErrors)? {
+            Response::UsbErrors(errors) => Ok(errors),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    /// Latency statistics of the reports sent since power-on or the last `reset_latency`
+    pub fn latency(&mut self) -> Result<Latency> {
+        match self.request(&Command::GetLatency)? {
+            Response::Latency(latency) => Ok(latency),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    pub fn reset_latency(&mut self) -> Result<()> {
+        self.execute(&Command::ResetLatency)
+    }
+
+    /// Supply voltage and die temperature of the pad
+    pub fn conditions(&mut self) -> Result<Conditions> {
+        match self.request(&Command::GetConditions)? {
+            Response::Conditions(conditions) => Ok(conditions),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    pub fn health(&mut self) -> Result<Vec<SensorHealth>> {
+        match self.request(&Command::GetHealth)? {
+            Response::Health(health) => Ok(health.to_vec()),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    pub fn config(&mut self) -> Result<PadConfig> {
+        match self.request(&Command::GetConfig)? {
+            Response::Config(config) => Ok(config),
+            resp => Err(Error::Unexpected(Box::new(resp))),
+        }
+    }
+
+    /// Replaces the active configuration, without persisting it
+    pub fn set_config(&mut self, config: PadConfig) -> Result<()> {
+        self.execute(&Command::SetConfig(config))
+    }
+
+    /// Persists the active configuration
+    pub fn save(&mut self) -> Result<()> {
+        self.execute(&Command::SaveConfig)
+    }
+
+    /// Replaces the active configuration with the persisted one
+    pub fn load(&mut self) -> Result<()> {
+        self.execute(&Command::LoadConfig)
+    }
+
+    pub fn reboot(&mut self) -> Result<()> {
+        self.execute(&Command::Reboot)
+    }
+
+    /// Tares the load cells of the pad, which must be unloaded while it takes their weight
+    pub fn tare(&mut self) -> Result<()> {
+        self.execute(&Command::Tare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::UsbPortInfo;
+
+    use super::*;
+
+    fn usb_port(path: &str, vid: u16, pid: u16, serial: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: path.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial.to_string()),
+                manufacturer: None,
+                product: Some("Rusty Joystick".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn finds_pads_by_vid_pid() {
+        let ports = vec![
+            usb_port("/dev/ttyACM0", 0x16c0, 0x27dd, "OTHER"),
+            usb_port("/dev/ttyACM1", USB_VID, USB_PID, "A"),
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+            usb_port("/dev/ttyACM2", USB_VID, USB_PID, "B"),
+        ];
+
+        let found = pads(ports);
+        assert_eq!(
+            found
+                .iter()
+                .map(|pad| (pad.path.as_str(), pad.serial_number.as_deref()))
+                .collect::<Vec<_>>(),
+            [("/dev/ttyACM1", Some("A")), ("/dev/ttyACM2", Some("B"))]
+        );
+    }
+}
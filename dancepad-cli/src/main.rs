@@ -0,0 +1,951 @@
+use std::{fs, io, path::PathBuf, thread, time::Duration};
+
+use abi::{
+    Aggregation, AnalogMode, Crosstalk, FaultPolicy, Filter, ForceCurve, ForceModel, ForcePoint,
+    ForceTable, PadConfig, Panel, Personality, Pull, RapidTrigger, SensorHealth, Thresholds,
+    UsbString, MAX_CROSSTALK, MAX_EMA_SHIFT, MAX_FORCE_POINTS, MAX_FORCE_TABLES,
+    MAX_USB_STRING_LEN, MAX_WINDOW,
+};
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use dancepad_cli::{find_pads, Pad, PadPort};
+use serialport::SerialPort;
+
+/// Configure and monitor a Rusty Dancepad
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Serial port of the pad, e.g. /dev/ttyACM0. Found by USB VID/PID when omitted.
+    #[arg(short, long, global = true)]
+    port: Option<String>,
+
+    /// USB serial number of the pad to use when several are connected
+    #[arg(short, long, global = true, conflicts_with = "port")]
+    serial: Option<String>,
+
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// List connected pads
+    List,
+    /// Show firmware information
+    Info,
+    /// Show the press and release thresholds of every channel, relative to its baseline
+    Thresholds,
+    /// Set the press and release thresholds of one channel
+    SetThresholds {
+        channel: u8,
+        press: u16,
+        release: u16,
+    },
+    /// Show the filter of every channel
+    Filters,
+    /// Set the filter of one channel
+    SetFilter {
+        channel: u8,
+        /// One of none, average:LEN, ema:SHIFT, median:LEN or spike:MAX_STEP
+        #[arg(value_parser = parse_filter)]
+        filter: Filter,
+    },
+    /// Show how the force on every channel is estimated, and the measured force curves
+    Forces,
+    /// Estimate the force on one channel from its FSR, so that its thresholds are in grams
+    SetForce {
+        channel: u8,
+        /// One of linear:GRAMS_PER_MS (of conductance) or table:N (of the force tables)
+        #[arg(value_parser = parse_curve)]
+        curve: ForceCurve,
+        /// Resistor between the ADC pin and ground, in ohms. Kept when omitted.
+        #[arg(long)]
+        divider: Option<u32>,
+    },
+    /// Leave one channel in raw ADC counts, and its thresholds with it
+    ClearForce { channel: u8 },
+    /// Set the points of one force curve, or add a curve by giving the number one past the last
+    SetForceTable {
+        table: u8,
+        /// Conductance in µS and force in grams of every point, e.g. 100:200,300:1000,1000:5000
+        #[arg(value_parser = parse_points)]
+        points: ForceTable,
+    },
+    /// Show the scale of the load cell on every channel, in counts of its HX711 per kg
+    Scales,
+    /// Set the scale of the load cell on one channel. With W grams on a cell that reads R grams
+    /// above its baseline, the right scale is the current one times R / W.
+    SetScale {
+        channel: u8,
+        /// Counts of the HX711 per kg, negative for a cell mounted upside down
+        #[arg(allow_negative_numbers = true)]
+        counts_per_kg: i32,
+    },
+    /// Take the weight on every load cell as its weight when unloaded. Keep off the pad for a
+    /// second afterwards.
+    Tare,
+    /// Show the sensors under every panel and how they are combined
+    Panels,
+    /// Set the sensors under one panel and how they are combined, or add a panel by giving the
+    /// number one past the last
+    SetPanel {
+        panel: u8,
+        /// Sensor channels under the panel, e.g. 0,1
+        #[arg(value_parser = parse_sensors)]
+        sensors: u32,
+        /// One of any (sensor over its own thresholds), sum, max or weighted (average)
+        #[arg(value_parser = parse_rule, default_value = "any")]
+        rule: Aggregation,
+        /// Press level of the combined force, for every rule but any
+        #[arg(long)]
+        press: Option<u16>,
+        /// Release level of the combined force, for every rule but any
+        #[arg(long)]
+        release: Option<u16>,
+    },
+    /// Release one panel once its force falls FALL below its peak, and press it again once the
+    /// force rises RISE above its trough, or turn this off when no amounts are given
+    SetRapidTrigger {
+        panel: u8,
+        #[arg(requires = "fall")]
+        rise: Option<u16>,
+        fall: Option<u16>,
+    },
+    /// Remove one panel, moving the panels after it and their keys down by one
+    RemovePanel { panel: u8 },
+    /// Set the relative weight of one channel in weighted panels
+    SetWeight { channel: u8, weight: u8 },
+    /// Set what the analog axes report: off, pressure (one axis per sensor) or center (of pressure,
+    /// on X and Y)
+    SetAnalog {
+        #[arg(value_parser = parse_analog)]
+        mode: AnalogMode,
+    },
+    /// Set the kind of USB device the pad presents itself as: joystick or keyboard. The pad saves
+    /// its configuration and restarts when this changes.
+    SetPersonality {
+        #[arg(value_parser = parse_personality)]
+        personality: Personality,
+    },
+    /// Show the key of every panel in the keyboard personality
+    Keys,
+    /// Set the HID keyboard usage ID one panel presses in the keyboard personality, 0 for none
+    SetKey {
+        panel: u8,
+        #[arg(value_parser = parse_key)]
+        key: u8,
+    },
+    /// Show the digital switches of the pad, how they are read and their keys
+    Switches,
+    /// Set how one switch is read, and the key it presses in the keyboard personality
+    SetSwitch {
+        switch: u8,
+        /// One of up, down or none, the internal resistor the pin is pulled through
+        #[arg(long, value_parser = parse_pull)]
+        pull: Option<Pull>,
+        /// Level of the pin while the switch is pressed, low or high
+        #[arg(long, value_parser = parse_active)]
+        active: Option<bool>,
+        /// Time the switch ignores its pin for after every change, in milliseconds
+        #[arg(long)]
+        debounce: Option<u8>,
+        /// HID keyboard usage ID the switch presses, 0 for none
+        #[arg(long, value_parser = parse_key)]
+        key: Option<u8>,
+    },
+    /// Override a string the pad identifies itself with over USB, or restore its default when no
+    /// value is given. The pad saves its configuration and restarts when this changes.
+    SetUsbString {
+        field: UsbField,
+        value: Option<String>,
+    },
+    /// Show the share of the force on each panel that shows up on the channels of others, and is
+    /// taken out of them
+    Crosstalk,
+    /// Measure the crosstalk between the panels by pressing each of them alone when asked. The pad
+    /// keeps at most 48 shares, one for each panel and channel outside it that the panel leaks
+    /// into, which covers 4 panels of 4 sensors each leaking into all 12 sensors of the others.
+    CalibrateCrosstalk {
+        /// Samples averaged while each panel is pressed
+        #[arg(short = 'n', long, default_value_t = 20)]
+        samples: usize,
+    },
+    /// Stop compensating crosstalk
+    ClearCrosstalk,
+    /// Show the idle level of every channel
+    Baselines,
+    /// Show the supply voltage and die temperature of the pad
+    Conditions,
+    /// Set how readings are corrected for the supply voltage and temperature
+    SetCompensation {
+        /// Scale readings to a nominal 3.3 V supply. Only for sensors fed from something other than
+        /// the 3.3 V rail.
+        #[arg(long)]
+        supply: Option<bool>,
+        /// Baseline change per degree Celsius, in 1/16 ADC counts, 0 to disable
+        #[arg(long, allow_negative_numbers = true)]
+        temperature: Option<i16>,
+    },
+    /// Show whether every sensor reads like a connected one, and what is wrong with those that do
+    /// not
+    Health,
+    /// Set whether faulty sensors are masked, i.e. treated as released, or only reported
+    SetFaultPolicy {
+        #[arg(value_parser = parse_fault_policy)]
+        policy: FaultPolicy,
+    },
+    /// Show the USB errors the pad ran into since power-on, e.g. to diagnose a flaky cable or hub
+    UsbErrors,
+    /// Show how long state changes take from the sample to the report handed to USB
+    Latency {
+        /// Start over collecting, after showing what was collected so far
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Write the active configuration as JSON to FILE, or to stdout
+    Dump { file: Option<PathBuf> },
+    /// Replace the active configuration with the JSON in FILE
+    Restore {
+        file: PathBuf,
+        /// Also persist the restored configuration
+        #[arg(long)]
+        save: bool,
+    },
+    /// Persist the active configuration
+    Save,
+    /// Discard unsaved changes by reloading the persisted configuration
+    Load,
+    /// Print the live value of every channel, one line per sample
+    Monitor {
+        /// Time between samples in milliseconds
+        #[arg(short, long, default_value_t = 50)]
+        interval: u64,
+        /// Stop after this many samples
+        #[arg(short = 'n', long)]
+        count: Option<usize>,
+    },
+    /// Reset the pad
+    Reboot,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UsbField {
+    Manufacturer,
+    Product,
+    /// Defaults to the unique ID of the chip
+    Serial,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Cmd::List => {
+            for pad in find_pads()? {
+                println!(
+                    "{}\t{}\t{}",
+                    pad.path,
+                    pad.serial_number.as_deref().unwrap_or("-"),
+                    pad.product.as_deref().unwrap_or("-")
+                );
+            }
+            Ok(())
+        }
+        cmd => {
+            let path = match cli.port {
+                Some(path) => path,
+                None => select_pad(find_pads()?, cli.serial.as_deref())?.path,
+            };
+            let pad = Pad::open(&path).with_context(|| format!("failed to open {path}"))?;
+            run(pad, cmd)
+        }
+    }
+}
+
+fn run(mut pad: Pad<Box<dyn SerialPort>>, cmd: Cmd) -> anyhow::Result<()> {
+    match cmd {
+        Cmd::List => unreachable!("handled without opening a pad"),
+        Cmd::Info => {
+            let info = pad.info()?;
+            println!("firmware version: {}", info.firmware_version);
+            println!("protocol version: {}", info.protocol_version);
+            println!("channels:         {}", info.channels);
+        }
+        Cmd::Thresholds => {
+            println!("channel\tpress\trelease");
+            for (channel, t) in pad.thresholds()?.iter().enumerate() {
+                println!("{channel}\t{}\t{}", t.press, t.release);
+            }
+        }
+        Cmd::SetThresholds {
+            channel,
+            press,
+            release,
+        } => {
+            let thresholds = Thresholds { press, release };
+            if !thresholds.is_valid() {
+                bail!("release level must not be above the press level");
+            }
+            pad.set_thresholds(channel, thresholds)?;
+        }
+        Cmd::Filters => {
+            println!("channel\tfilter\tlatency");
+            for (channel, filter) in pad.config()?.filters.iter().enumerate() {
+                println!("{channel}\t{}\t{}", format_filter(filter), filter.latency());
+            }
+        }
+        Cmd::SetFilter { channel, filter } => {
+            if !filter.is_valid() {
+                bail!(
+                    "windows must be 1 to {MAX_WINDOW} samples long and EMA shifts at most \
+                     {MAX_EMA_SHIFT}"
+                );
+            }
+            let mut config = pad.config()?;
+            let Some(entry) = config.filters.get_mut(channel as usize) else {
+                bail!("the pad has no channel {channel}");
+            };
+            *entry = filter;
+            pad.set_config(config)?;
+        }
+        Cmd::Forces => {
+            let config = pad.config()?;
+            println!("channel\tdivider\tcurve");
+            for (channel, model) in config.force.iter().enumerate() {
+                match model {
+                    Some(model) => println!(
+                        "{channel}\t{} Ω\t{}",
+                        model.divider_ohms,
+                        format_curve(&model.curve)
+                    ),
+                    None => println!("{channel}\t-\tcounts"),
+                }
+            }
+            if !config.force_tables.is_empty() {
+                println!("table\tpoints");
+            }
+            for (table, points) in config.force_tables.iter().enumerate() {
+                let points: Vec<_> = points
+                    .iter()
+                    .map(|point| format!("{}:{}", point.microsiemens, point.grams))
+                    .collect();
+                println!("{table}\t{}", points.join(","));
+            }
+        }
+        Cmd::SetForce {
+            channel,
+            curve,
+            divider,
+        } => {
+            let mut config = pad.config()?;
+            let Some(entry) = config.force.get_mut(channel as usize) else {
+                bail!("the pad has no channel {channel}");
+            };
+            let Some(divider_ohms) = divider.or(entry.map(|model| model.divider_ohms)) else {
+                bail!("give the resistor of the divider with --divider");
+            };
+            *entry = Some(ForceModel {
+                divider_ohms,
+                curve,
+            });
+            if !config.is_valid() {
+                bail!("the divider needs a resistor, and table curves an existing table");
+            }
+            pad.set_config(config)?;
+        }
+        Cmd::ClearForce { channel } => {
+            let mut config = pad.config()?;
+            let Some(entry) = config.force.get_mut(channel as usize) else {
+                bail!("the pad has no channel {channel}");
+            };
+            *entry = None;
+            pad.set_config(config)?;
+        }
+        Cmd::SetForceTable { table, points } => {
+            let mut config = pad.config()?;
+            let idx = table as usize;
+            if idx == config.force_tables.len() {
+                if config.force_tables.push(points).is_err() {
+                    bail!("the pad can have at most {MAX_FORCE_TABLES} force tables");
+                }
+            } else if let Some(entry) = config.force_tables.get_mut(idx) {
+                *entry = points;
+            } else {
+                bail!("the pad has no force table {table}");
+            }
+            if !config.is_valid() {
+                bail!("points must increase in conductance, and not decrease in force");
+            }
+            pad.set_config(config)?;
+        }
+        Cmd::Scales => {
+            println!("channel\tscale");
+            for (channel, scale) in pad.config()?.scales.iter().enumerate() {
+                println!("{channel}\t{scale}");
+            }
+        }
+        Cmd::SetScale {
+            channel,
+            counts_per_kg,
+        } => {
+            if counts_per_kg == 0 {
+                bail!("a load cell needs a scale other than 0");
+            }
+            let mut config = pad.config()?;
+            let Some(entry) = config.scales.get_mut(channel as usize) else {
+                bail!("the pad has no channel {channel}");
+            };
+            *entry = counts_per_kg;
+            pad.set_config(config)?;
+        }
+        Cmd::Tare => pad.tare()?,
+        Cmd::Panels => {
+            let config = pad.config()?;
+            println!("panel\tsensors\trule\tpress\trelease\tweights\trapid");
+            for (idx, panel) in config.panels.iter().enumerate() {
+                let (sensors, weights): (Vec<_>, Vec<_>) = config
+                    .weights
+                    .iter()
+                    .enumerate()
+                    .filter(|(channel, _)| panel.sensors & (1 << channel) != 0)
+                    .map(|(channel, weight)| (channel.to_string(), weight.to_string()))
+                    .unzip();
+                let (press, release) = match panel.rule {
+                    Aggregation::Any => ("-".to_string(), "-".to_string()),
+                    _ => (
+                        panel.thresholds.press.to_string(),
+                        panel.thresholds.release.to_string(),
+                    ),
+                };
+                let rapid = match panel.rapid {
+                    Some(rapid) => format!("{}:{}", rapid.rise, rapid.fall),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "{idx}\t{}\t{}\t{press}\t{release}\t{}\t{rapid}",
+                    sensors.join(","),
+                    format_rule(&panel.rule),
+                    weights.join(","),
+                );
+            }
+        }
+        Cmd::SetPanel {
+            panel,
+            sensors,
+            rule,
+            press,
+            release,
+        } => {
+            let mut config = pad.config()?;
+            let channels = config.weights.len();
+            let idx = panel as usize;
+            let mut entry = match config.panels.get(idx) {
+                Some(entry) => *entry,
+                None if idx == config.panels.len() && idx < channels => Panel::single(0),
+                None => bail!("the pad has no panel {panel}, and can have at most {channels}"),
+            };
+            entry.sensors = sensors;
+            entry.rule = rule;
+            entry.thresholds.press = press.unwrap_or(entry.thresholds.press);
+            entry.thresholds.release = release.unwrap_or(entry.thresholds.release);
+            if !entry.thresholds.is_valid() {
+                bail!("release level must not be above the press level");
+            }
+            if idx == config.panels.len() {
+                config.panels.push(entry).unwrap();
+            } else {
+                config.panels[idx] = entry;
+            }
+            if !config.has_inputs(channels, config.switches.len()) {
+                bail!("the pad has only {channels} channels");
+            }
+            pad.set_config(config)?;
+        }
+        Cmd::SetRapidTrigger { panel, rise, fall } => {
+            let rapid = rise
+                .zip(fall)
+                .map(|(rise, fall)| RapidTrigger { rise, fall });
+            if rapid.is_some_and(|rapid| !rapid.is_valid()) {
+                bail!("the rise and fall must be above 0");
+            }
+            let mut config = pad.config()?;
+            let Some(entry) = config.panels.get_mut(panel as usize) else {
+                bail!("the pad has no panel {panel}");
+            };
+            entry.rapid = rapid;
+            pad.set_config(config)?;
+        }
+        Cmd::RemovePanel { panel } => {
+            let mut config = pad.config()?;
+            if panel as usize >= config.panels.len() {
+                bail!("the pad has no panel {panel}");
+            }
+            config.panels.remove(panel as usize);
+            config.crosstalk.retain(|c| c.panel != panel);
+            for c in &mut config.crosstalk {
+                if c.panel > panel {
+                    c.panel -= 1;
+                }
+            }
+            // The keys of the switches follow those of all channels
+            config.keys.remove(panel as usize);
+            config.keys.insert(config.weights.len() - 1, 0).unwrap();
+            pad.set_config(config)?;
+        }
+        Cmd::SetWeight { channel, weight } => {
+            let mut config = pad.config()?;
+            let Some(entry) = config.weights.get_mut(channel as usize) else {
+                bail!("the pad has no channel {channel}");
+            };
+            *entry = weight;
+            pad.set_config(config)?;
+        }
+        Cmd::SetAnalog { mode } => {
+            let mut config = pad.config()?;
+            config.analog = mode;
+            pad.set_config(config)?;
+        }
+        Cmd::SetPersonality { personality } => {
+            let mut config = pad.config()?;
+            config.personality = personality;
+            pad.set_config(config)?;
+        }
+        Cmd::Keys => {
+            let config = pad.config()?;
+            println!("panel\tkey");
+            for (panel, key) in config.keys.iter().take(config.panels.len()).enumerate() {
+                println!("{panel}\t{key:#04x}");
+            }
+        }
+        Cmd::SetKey { panel, key } => {
+            let mut config = pad.config()?;
+            let panels = config.panels.len();
+            let Some(entry) = config.keys[..panels].get_mut(panel as usize) else {
+                bail!("the pad has no panel {panel}");
+            };
+            *entry = key;
+            pad.set_config(config)?;
+        }
+        Cmd::Switches => {
+            let config = pad.config()?;
+            println!("switch\tpull\tactive\tdebounce\tkey");
+            let keys = &config.keys[config.weights.len()..];
+            for (idx, (switch, key)) in config.switches.iter().zip(keys).enumerate() {
+                println!(
+                    "{idx}\t{}\t{}\t{} ms\t{key:#04x}",
+                    format_pull(&switch.pull),
+                    if switch.active_low { "low" } else { "high" },
+                    switch.debounce_ms,
+                );
+            }
+        }
+        Cmd::SetSwitch {
+            switch,
+            pull,
+            active,
+            debounce,
+            key,
+        } => {
+            let mut config = pad.config()?;
+            let channels = config.weights.len();
+            let Some(entry) = config.switches.get_mut(switch as usize) else {
+                bail!("the pad has no switch {switch}");
+            };
+            entry.pull = pull.unwrap_or(entry.pull);
+            entry.active_low = active.unwrap_or(entry.active_low);
+            entry.debounce_ms = debounce.unwrap_or(entry.debounce_ms);
+            if let Some(key) = key {
+                config.keys[channels + switch as usize] = key;
+            }
+            pad.set_config(config)?;
+        }
+        Cmd::SetUsbString { field, value } => {
+            let value = match value {
+                Some(value) if value.is_empty() => bail!("USB strings must not be empty"),
+                Some(value) => Some(UsbString::try_from(value.as_str()).map_err(|_| {
+                    anyhow::anyhow!("USB strings must be at most {MAX_USB_STRING_LEN} bytes long")
+                })?),
+                None => None,
+            };
+            let mut config = pad.config()?;
+            *match field {
+                UsbField::Manufacturer => &mut config.usb.manufacturer,
+                UsbField::Product => &mut config.usb.product,
+                UsbField::Serial => &mut config.usb.serial_number,
+            } = value;
+            pad.set_config(config)?;
+        }
+        Cmd::Crosstalk => {
+            println!("panel\tto\tshare");
+            for c in pad.config()?.crosstalk {
+                println!("{}\t{}\t{}", c.panel, c.to, format_gain(c.gain));
+            }
+        }
+        Cmd::CalibrateCrosstalk { samples } => calibrate_crosstalk(&mut pad, samples.max(1))?,
+        Cmd::ClearCrosstalk => {
+            let mut config = pad.config()?;
+            config.crosstalk.clear();
+            pad.set_config(config)?;
+        }
+        Cmd::Baselines => {
+            let baselines = pad.baselines()?;
+            if baselines.is_empty() {
+                bail!("the pad is still capturing baselines, try again in a moment");
+            }
+            println!("channel\tbaseline");
+            for (channel, baseline) in baselines.iter().enumerate() {
+                println!("{channel}\t{baseline}");
+            }
+        }
+        Cmd::Conditions => {
+            let conditions = pad.conditions()?;
+            println!("supply:      {} mV", conditions.vdda_mv);
+            println!(
+                "temperature: {:.1} °C",
+                f32::from(conditions.temperature) / 10.0
+            );
+        }
+        Cmd::SetCompensation {
+            supply,
+            temperature,
+        } => {
+            let mut config = pad.config()?;
+            if let Some(supply) = supply {
+                config.compensation.supply = supply;
+            }
+            if let Some(temperature) = temperature {
+                config.compensation.temperature = temperature;
+            }
+            pad.set_config(config)?;
+        }
+        Cmd::Health => {
+            println!("channel\thealth");
+            for (channel, health) in pad.health()?.iter().enumerate() {
+                println!("{channel}\t{}", format_health(health));
+            }
+        }
+        Cmd::SetFaultPolicy { policy } => {
+            let mut config = pad.config()?;
+            config.faults = policy;
+            pad.set_config(config)?;
+        }
+        Cmd::UsbErrors => {
+            let errors = pad.usb_errors()?;
+            println!("dropped reports: {}", errors.dropped_reports);
+            println!("bus errors:      {}", errors.bus);
+            println!("serialization:   {}", errors.serialization);
+            println!("serial errors:   {}", errors.serial);
+            println!("class resets:    {}", errors.class_resets);
+            println!("device resets:   {}", errors.device_resets);
+        }
+        Cmd::Latency { reset } => {
+            let latency = pad.latency()?;
+            println!("reports: {}", latency.reports);
+            println!("min:     {} us", latency.min_us);
+            println!("mean:    {} us", latency.mean_us);
+            println!("max:     {} us", latency.max_us);
+            if reset {
+                pad.reset_latency()?;
+            }
+        }
+        Cmd::Dump { file } => {
+            let json = serde_json::to_string_pretty(&pad.config()?)?;
+            match file {
+                Some(file) => fs::write(&file, json + "\n")
+                    .with_context(|| format!("failed to write {}", file.display()))?,
+                None => println!("{json}"),
+            }
+        }
+        Cmd::Restore { file, save } => {
+            let json = fs::read_to_string(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+            let config: PadConfig = serde_json::from_str(&json)
+                .with_context(|| format!("invalid configuration in {}", file.display()))?;
+            pad.set_config(config)?;
+            if save {
+                pad.save()?;
+            }
+        }
+        Cmd::Save => pad.save()?,
+        Cmd::Load => pad.load()?,
+        Cmd::Monitor { interval, count } => {
+            for _ in 0..count.unwrap_or(usize::MAX) {
+                let values = pad.values()?;
+                let line = values
+                    .iter()
+                    .map(u16::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\t");
+                println!("{line}");
+                thread::sleep(Duration::from_millis(interval));
+            }
+        }
+        Cmd::Reboot => pad.reboot()?,
+    }
+
+    Ok(())
+}
+
+/// Asks for every panel to be pressed alone, measures the share of its force that shows up on the
+/// channels outside it, and sets the crosstalk to that
+fn calibrate_crosstalk(pad: &mut Pad<Box<dyn SerialPort>>, samples: usize) -> anyhow::Result<()> {
+    let mut config = pad.config()?;
+    let baselines = pad.baselines()?;
+    if baselines.is_empty() {
+        bail!("the pad is still capturing baselines, try again in a moment");
+    }
+    println!("Keep off the pad but for the panel asked for, and hold it down until the next one.");
+    let mut crosstalk = Vec::new();
+    for (idx, panel) in config.panels.iter().enumerate() {
+        println!("Press panel {idx} alone, then hit Enter");
+        if io::stdin().read_line(&mut String::new())? == 0 {
+            bail!("calibration aborted");
+        }
+        // Against the baselines from before the press, which the pad stops tracking on the
+        // pressed sensors but not on the others
+        let mut sums = vec![0u64; baselines.len()];
+        for _ in 0..samples {
+            for (sum, (value, baseline)) in
+                sums.iter_mut().zip(pad.values()?.iter().zip(&baselines))
+            {
+                *sum += u64::from(value.saturating_sub(*baseline));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let under = |channel: usize| panel.sensors & (1 << channel) != 0;
+        let total: u64 = (0..sums.len()).filter(|c| under(*c)).map(|c| sums[c]).sum();
+        if total == 0 {
+            bail!("panel {idx} read no force, is it connected?");
+        }
+        for to in (0..sums.len()).filter(|c| !under(*c)) {
+            let gain = ((sums[to] * 256 + total / 2) / total).min(u8::MAX.into()) as u8;
+            if gain != 0 {
+                crosstalk.push(Crosstalk {
+                    panel: idx as u8,
+                    to: to as u8,
+                    gain,
+                });
+            }
+        }
+    }
+    if crosstalk.len() > MAX_CROSSTALK {
+        bail!(
+            "the pad can compensate at most {MAX_CROSSTALK} leaks from a panel into a channel, not {}",
+            crosstalk.len()
+        );
+    }
+    config.crosstalk = crosstalk.iter().copied().collect();
+    pad.set_config(config)?;
+    println!("panel\tto\tshare");
+    for c in &crosstalk {
+        println!("{}\t{}\t{}", c.panel, c.to, format_gain(c.gain));
+    }
+    println!("Save the configuration to keep the compensation after a restart.");
+    Ok(())
+}
+
+/// Formats a crosstalk gain in 1/256 as a percentage
+fn format_gain(gain: u8) -> String {
+    format!("{:.1}%", f32::from(gain) * 100.0 / 256.0)
+}
+
+/// Parses a filter as written by `format_filter`
+fn parse_filter(s: &str) -> Result<Filter, String> {
+    let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+    let number = || {
+        arg.parse()
+            .map_err(|_| format!("{kind} needs a number, e.g. {kind}:4"))
+    };
+    Ok(match kind {
+        "none" => Filter::None,
+        "average" => Filter::MovingAverage { len: number()? },
+        "ema" => Filter::Ema { shift: number()? },
+        "median" => Filter::Median { len: number()? },
+        "spike" => Filter::SpikeReject {
+            max_step: arg
+                .parse()
+                .map_err(|_| "spike needs a number, e.g. spike:200")?,
+        },
+        _ => return Err(format!("unknown filter {kind}")),
+    })
+}
+
+fn format_filter(filter: &Filter) -> String {
+    match filter {
+        Filter::None => "none".to_string(),
+        Filter::MovingAverage { len } => format!("average:{len}"),
+        Filter::Ema { shift } => format!("ema:{shift}"),
+        Filter::Median { len } => format!("median:{len}"),
+        Filter::SpikeReject { max_step } => format!("spike:{max_step}"),
+    }
+}
+
+/// Parses a force curve as written by `format_curve`
+fn parse_curve(s: &str) -> Result<ForceCurve, String> {
+    let (kind, arg) = s.split_once(':').unwrap_or((s, ""));
+    Ok(match kind {
+        "linear" => ForceCurve::Linear {
+            grams_per_ms: arg
+                .parse()
+                .map_err(|_| "linear needs grams per mS, e.g. linear:3000")?,
+        },
+        "table" => ForceCurve::Table {
+            table: arg
+                .parse()
+                .map_err(|_| "table needs a table number, e.g. table:0")?,
+        },
+        _ => return Err(format!("unknown curve {kind}")),
+    })
+}
+
+fn format_curve(curve: &ForceCurve) -> String {
+    match curve {
+        ForceCurve::Linear { grams_per_ms } => format!("linear:{grams_per_ms}"),
+        ForceCurve::Table { table } => format!("table:{table}"),
+    }
+}
+
+/// Parses a comma-separated list of conductance:grams points
+fn parse_points(s: &str) -> Result<ForceTable, String> {
+    let mut table = ForceTable::new();
+    for point in s.split(',') {
+        let (microsiemens, grams) = point
+            .trim()
+            .split_once(':')
+            .and_then(|(us, grams)| Some((us.parse().ok()?, grams.parse().ok()?)))
+            .ok_or_else(|| format!("invalid point {point}, expected e.g. 300:1000"))?;
+        table
+            .push(ForcePoint {
+                microsiemens,
+                grams,
+            })
+            .map_err(|_| format!("a table has at most {MAX_FORCE_POINTS} points"))?;
+    }
+    Ok(table)
+}
+
+/// Parses a comma-separated list of sensor channels into a bit mask
+fn parse_sensors(s: &str) -> Result<u32, String> {
+    let mut sensors = 0u32;
+    for channel in s.split(',') {
+        let channel: u32 = channel
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid channel {channel}, expected e.g. 0,1"))?;
+        sensors |= 1u32
+            .checked_shl(channel)
+            .ok_or_else(|| format!("channel {channel} is out of range"))?;
+    }
+    Ok(sensors)
+}
+
+fn parse_rule(s: &str) -> Result<Aggregation, String> {
+    match s {
+        "any" => Ok(Aggregation::Any),
+        "sum" => Ok(Aggregation::Sum),
+        "max" => Ok(Aggregation::Max),
+        "weighted" => Ok(Aggregation::WeightedAverage),
+        _ => Err(format!("unknown rule {s}")),
+    }
+}
+
+fn format_rule(rule: &Aggregation) -> &'static str {
+    match rule {
+        Aggregation::Any => "any",
+        Aggregation::Sum => "sum",
+        Aggregation::Max => "max",
+        Aggregation::WeightedAverage => "weighted",
+    }
+}
+
+fn parse_analog(s: &str) -> Result<AnalogMode, String> {
+    match s {
+        "off" => Ok(AnalogMode::Off),
+        "pressure" => Ok(AnalogMode::Pressure),
+        "center" => Ok(AnalogMode::CenterOfPressure),
+        _ => Err(format!("unknown analog mode {s}")),
+    }
+}
+
+fn parse_personality(s: &str) -> Result<Personality, String> {
+    match s {
+        "joystick" => Ok(Personality::Joystick),
+        "keyboard" => Ok(Personality::Keyboard),
+        _ => Err(format!("unknown personality {s}")),
+    }
+}
+
+fn parse_fault_policy(s: &str) -> Result<FaultPolicy, String> {
+    match s {
+        "report" => Ok(FaultPolicy::Report),
+        "mask" => Ok(FaultPolicy::Mask),
+        _ => Err(format!("unknown fault policy {s}")),
+    }
+}
+
+fn parse_pull(s: &str) -> Result<Pull, String> {
+    match s {
+        "none" => Ok(Pull::None),
+        "up" => Ok(Pull::Up),
+        "down" => Ok(Pull::Down),
+        _ => Err(format!("unknown pull {s}")),
+    }
+}
+
+fn format_pull(pull: &Pull) -> &'static str {
+    match pull {
+        Pull::None => "none",
+        Pull::Up => "up",
+        Pull::Down => "down",
+    }
+}
+
+/// Parses the level of a pressed switch into whether it is active low
+fn parse_active(s: &str) -> Result<bool, String> {
+    match s {
+        "low" => Ok(true),
+        "high" => Ok(false),
+        _ => Err(format!("unknown level {s}, expected low or high")),
+    }
+}
+
+fn format_health(health: &SensorHealth) -> &'static str {
+    match health {
+        SensorHealth::Healthy => "healthy",
+        SensorHealth::StuckLow => "stuck at 0, disconnected or shorted to ground?",
+        SensorHealth::StuckHigh => "stuck at full scale, shorted to the supply?",
+        SensorHealth::Flat => "not changing at all",
+        SensorHealth::BaselineOutOfRange => "idles too high to reach the press threshold",
+    }
+}
+
+/// Parses a usage ID from the HID keyboard page, in decimal or as 0x-prefixed hex
+fn parse_key(s: &str) -> Result<u8, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid key {s}, expected a usage ID such as 0x04 for A"))
+}
+
+/// Picks the pad with the given serial number, or the only pad if no serial number is given
+fn select_pad(pads: Vec<PadPort>, serial: Option<&str>) -> anyhow::Result<PadPort> {
+    let mut candidates: Vec<_> = pads
+        .into_iter()
+        .filter(|pad| serial.is_none() || pad.serial_number.as_deref() == serial)
+        .collect();
+
+    match (candidates.len(), serial) {
+        (1, _) => Ok(candidates.remove(0)),
+        (0, Some(serial)) => bail!("no pad with serial number {serial} found"),
+        (0, None) => bail!("no pad found, is it plugged in?"),
+        (_, _) => bail!(
+            "several pads found, pick one with --serial: {}",
+            candidates
+                .iter()
+                .map(|pad| pad.serial_number.as_deref().unwrap_or("?"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
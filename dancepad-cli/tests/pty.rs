@@ -0,0 +1,688 @@
+//! Runs the tool against a stand-in pad on the other end of a pseudo-terminal
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    process::{Command as Process, Output, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use abi::{
+    Aggregation, AnalogMode, Channels, Command, Compensation, Conditions, Crosstalk, FaultPolicy,
+    Filter, FirmwareInfo, ForceCurve, ForceModel, FrameBuffer, Latency, PadConfig, Personality,
+    Pull, RapidTrigger, Response, SensorHealth, Switch, Thresholds, UsbErrors, MAX_FRAME_LEN,
+    PROTOCOL_VERSION,
+};
+use dancepad_cli::{Error, Pad};
+use serialport::{SerialPort, TTYPort};
+
+const CHANNELS: usize = 4;
+
+/// State of the stand-in pad
+struct FakePad {
+    config: PadConfig,
+    saved: Option<PadConfig>,
+    values: Vec<u16>,
+    baselines: Vec<u16>,
+    usb_errors: UsbErrors,
+    latency: Latency,
+    health: Vec<SensorHealth>,
+    tares: usize,
+}
+
+impl FakePad {
+    fn handle(&mut self, cmd: Command) -> Response {
+        match cmd {
+            Command::GetInfo => Response::Info(FirmwareInfo {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: "0.1.0".try_into().unwrap(),
+                channels: CHANNELS as u8,
+            }),
+            Command::GetThresholds => Response::Thresholds(self.config.thresholds.clone()),
+            Command::SetThresholds {
+                channel,
+                thresholds,
+            } => match self.config.thresholds.get_mut(channel as usize) {
+                Some(t) => {
+                    *t = thresholds;
+                    Response::Ok
+                }
+                None => Response::Error(abi::Error::InvalidChannel),
+            },
+            Command::GetValues => {
+                // Every sample differs from the previous one, like a live sensor
+                self.values.iter_mut().for_each(|v| *v += 1);
+                Response::Values(Channels::from_slice(&self.values).unwrap())
+            }
+            Command::GetBaselines => {
+                Response::Baselines(Channels::from_slice(&self.baselines).unwrap())
+            }
+            Command::GetUsbErrors => Response::UsbErrors(self.usb_errors),
+            Command::GetLatency => Response::Latency(self.latency),
+            Command::ResetLatency => {
+                self.latency = Latency::default();
+                Response::Ok
+            }
+            Command::GetConditions => Response::Conditions(Conditions {
+                vdda_mv: 3264,
+                temperature: 315,
+            }),
+            Command::GetHealth => Response::Health(Channels::from_slice(&self.health).unwrap()),
+            Command::GetConfig => Response::Config(self.config.clone()),
+            Command::SetConfig(config) => {
+                self.config = config;
+                Response::Ok
+            }
+            Command::SaveConfig => {
+                self.saved = Some(self.config.clone());
+                Response::Ok
+            }
+            Command::LoadConfig => match &self.saved {
+                Some(saved) => {
+                    self.config = saved.clone();
+                    Response::Ok
+                }
+                None => Response::Error(abi::Error::Storage),
+            },
+            Command::Reboot => Response::Ok,
+            Command::Tare => {
+                self.tares += 1;
+                Response::Ok
+            }
+        }
+    }
+}
+
+/// Serves commands from `port` until the other end goes away
+fn serve(mut port: TTYPort, pad: Arc<Mutex<FakePad>>) {
+    let mut rx = FrameBuffer::<MAX_FRAME_LEN>::new();
+    let mut buf = [0u8; 64];
+    loop {
+        let count = match port.read(&mut buf) {
+            Ok(count) => count,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(_) => return,
+        };
+
+        let mut bytes = &buf[..count];
+        while let Some((frame, rest)) = rx.push(bytes) {
+            let response = match abi::decode::<Command>(frame.unwrap()) {
+                Ok(cmd) => pad.lock().unwrap().handle(cmd),
+                Err(_) => Response::Error(abi::Error::Malformed),
+            };
+            port.write_all(&abi::encode_vec(&response)).unwrap();
+            bytes = rest;
+        }
+    }
+}
+
+/// Starts a stand-in pad and returns its state and the terminal the tool should connect to
+fn fake_pad() -> (Arc<Mutex<FakePad>>, TTYPort) {
+    let (master, slave) = TTYPort::pair().unwrap();
+    let pad = Arc::new(Mutex::new(FakePad {
+        config: PadConfig::new(CHANNELS),
+        saved: None,
+        values: vec![100, 200, 300, 400],
+        baselines: vec![90, 190, 290, 390],
+        usb_errors: UsbErrors::default(),
+        latency: Latency::default(),
+        health: vec![SensorHealth::Healthy; CHANNELS],
+        tares: 0,
+    }));
+    let served = pad.clone();
+    thread::spawn(move || serve(master, served));
+    (pad, slave)
+}
+
+/// Runs the tool binary against the terminal `tty`
+fn cli(tty: &TTYPort, args: &[&str]) -> Output {
+    let output = Process::new(env!("CARGO_BIN_EXE_dancepad-cli"))
+        .args(["--port", tty.name().unwrap().as_str()])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn reads_info_and_thresholds() {
+    let (_, tty) = fake_pad();
+    let mut pad = Pad::new(tty);
+
+    let info = pad.info().unwrap();
+    assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(info.channels as usize, CHANNELS);
+    assert_eq!(pad.thresholds().unwrap(), [Thresholds::DEFAULT; CHANNELS]);
+}
+
+#[test]
+fn sets_filters() {
+    let (state, tty) = fake_pad();
+    cli(&tty, &["set-filter", "1", "median:5"]);
+    cli(&tty, &["set-filter", "3", "spike:200"]);
+    assert_eq!(
+        state.lock().unwrap().config.filters,
+        [
+            Filter::None,
+            Filter::Median { len: 5 },
+            Filter::None,
+            Filter::SpikeReject { max_step: 200 }
+        ]
+    );
+
+    let output = cli(&tty, &["filters"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "channel\tfilter\tlatency",
+            "0\tnone\t0",
+            "1\tmedian:5\t2",
+            "2\tnone\t0",
+            "3\tspike:200\t1"
+        ]
+    );
+}
+
+#[test]
+fn sets_analog_mode() {
+    let (state, tty) = fake_pad();
+    cli(&tty, &["set-analog", "center"]);
+    assert_eq!(
+        state.lock().unwrap().config.analog,
+        AnalogMode::CenterOfPressure
+    );
+}
+
+#[test]
+fn sets_personality_and_keys() {
+    let (state, tty) = fake_pad();
+    cli(&tty, &["set-personality", "keyboard"]);
+    cli(&tty, &["set-key", "0", "0x04"]);
+    cli(&tty, &["set-key", "3", "0"]);
+    let config = state.lock().unwrap().config.clone();
+    assert_eq!(config.personality, Personality::Keyboard);
+    assert_eq!(config.keys, [0x04, 0x51, 0x52, 0]);
+
+    let output = cli(&tty, &["keys"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        ["panel\tkey", "0\t0x04", "1\t0x51", "2\t0x52", "3\t0x00"]
+    );
+}
+
+#[test]
+fn maps_sensors_to_panels() {
+    let (state, tty) = fake_pad();
+    // Two sensors under each of two panels
+    cli(
+        &tty,
+        &[
+            "set-panel",
+            "0",
+            "0,1",
+            "sum",
+            "--press",
+            "900",
+            "--release",
+            "800",
+        ],
+    );
+    cli(
+        &tty,
+        &["set-panel", "1", "2,3", "weighted", "--press", "600"],
+    );
+    cli(&tty, &["remove-panel", "3"]);
+    cli(&tty, &["remove-panel", "2"]);
+    cli(&tty, &["set-weight", "3", "2"]);
+    cli(&tty, &["set-rapid-trigger", "0", "100", "150"]);
+    cli(&tty, &["set-rapid-trigger", "1", "50", "50"]);
+    cli(&tty, &["set-rapid-trigger", "1"]);
+    let config = state.lock().unwrap().config.clone();
+    assert_eq!(config.panels.len(), 2);
+    assert_eq!(config.panels[0].sensors, 0b0011);
+    assert_eq!(config.panels[0].rule, Aggregation::Sum);
+    assert_eq!(config.weights, [1, 1, 1, 2]);
+    assert_eq!(
+        config.panels[0].rapid,
+        Some(RapidTrigger {
+            rise: 100,
+            fall: 150
+        })
+    );
+    assert_eq!(config.panels[1].rapid, None);
+
+    let output = cli(&tty, &["panels"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "panel\tsensors\trule\tpress\trelease\tweights\trapid",
+            "0\t0,1\tsum\t900\t800\t1,1\t100:150",
+            "1\t2,3\tweighted\t600\t448\t1,2\t-"
+        ]
+    );
+    let output = cli(&tty, &["keys"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        ["panel\tkey", "0\t0x50", "1\t0x51"]
+    );
+}
+
+#[test]
+fn sets_switches() {
+    let (state, tty) = fake_pad();
+    state.lock().unwrap().config = PadConfig::new(CHANNELS).with_switches(2);
+    cli(
+        &tty,
+        &[
+            "set-switch",
+            "1",
+            "--pull",
+            "down",
+            "--active",
+            "high",
+            "--debounce",
+            "10",
+            "--key",
+            "0x29",
+        ],
+    );
+    // The keys of the switches stay after those of the channels
+    cli(&tty, &["remove-panel", "0"]);
+    let config = state.lock().unwrap().config.clone();
+    assert_eq!(
+        config.switches[1],
+        Switch {
+            pull: Pull::Down,
+            active_low: false,
+            debounce_ms: 10,
+        }
+    );
+    assert_eq!(config.keys, [0x51, 0x52, 0x4f, 0, 0, 0x29]);
+
+    let output = cli(&tty, &["switches"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "switch\tpull\tactive\tdebounce\tkey",
+            "0\tup\tlow\t5 ms\t0x00",
+            "1\tdown\thigh\t10 ms\t0x29"
+        ]
+    );
+}
+
+#[test]
+fn estimates_force() {
+    let (state, tty) = fake_pad();
+    cli(
+        &tty,
+        &["set-force-table", "0", "100:200,300:1000,1000:5000"],
+    );
+    cli(
+        &tty,
+        &["set-force", "1", "linear:3000", "--divider", "10000"],
+    );
+    cli(&tty, &["set-force", "2", "table:0", "--divider", "4700"]);
+    cli(&tty, &["set-force", "2", "table:0"]);
+    cli(
+        &tty,
+        &["set-force", "3", "linear:1000", "--divider", "1000"],
+    );
+    cli(&tty, &["clear-force", "3"]);
+    let config = state.lock().unwrap().config.clone();
+    assert_eq!(
+        config.force[2],
+        Some(ForceModel {
+            divider_ohms: 4700,
+            curve: ForceCurve::Table { table: 0 },
+        })
+    );
+    assert_eq!(config.force[3], None);
+
+    let output = cli(&tty, &["forces"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "channel\tdivider\tcurve",
+            "0\t-\tcounts",
+            "1\t10000 Ω\tlinear:3000",
+            "2\t4700 Ω\ttable:0",
+            "3\t-\tcounts",
+            "table\tpoints",
+            "0\t100:200,300:1000,1000:5000"
+        ]
+    );
+}
+
+#[test]
+fn sets_scales_and_tares() {
+    let (state, tty) = fake_pad();
+    cli(&tty, &["set-scale", "1", "19500"]);
+    cli(&tty, &["set-scale", "3", "-22000"]);
+    cli(&tty, &["tare"]);
+    let pad = state.lock().unwrap();
+    assert_eq!(pad.config.scales, [21_000, 19_500, 21_000, -22_000]);
+    assert_eq!(pad.tares, 1);
+    drop(pad);
+
+    let output = cli(&tty, &["scales"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "channel\tscale",
+            "0\t21000",
+            "1\t19500",
+            "2\t21000",
+            "3\t-22000"
+        ]
+    );
+}
+
+/// Runs `calibrate-crosstalk`, pressing each panel alone with the force `above` the baselines of
+/// its entry in `presses`, and returns what the tool printed after the prompts
+fn calibrate(state: &Mutex<FakePad>, tty: &TTYPort, presses: &[[u16; CHANNELS]]) -> Vec<String> {
+    let mut child = Process::new(env!("CARGO_BIN_EXE_dancepad-cli"))
+        .args(["--port", tty.name().unwrap().as_str()])
+        .args(["calibrate-crosstalk", "--samples", "2"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    // Every sample read from the stand-in pad is one count higher than the last
+    for (panel, above) in presses.iter().enumerate() {
+        let prompt = lines
+            .by_ref()
+            .map(Result::unwrap)
+            .find(|line| line.starts_with("Press panel"))
+            .unwrap();
+        assert!(prompt.contains(&panel.to_string()), "{prompt}");
+        let mut pad = state.lock().unwrap();
+        pad.values = pad
+            .baselines
+            .iter()
+            .zip(above)
+            .map(|(b, a)| b + a)
+            .collect();
+        drop(pad);
+        writeln!(stdin).unwrap();
+    }
+    let output = lines.map(Result::unwrap).collect();
+    assert!(child.wait().unwrap().success());
+    output
+}
+
+const fn leak(panel: u8, to: u8, gain: u8) -> Crosstalk {
+    Crosstalk { panel, to, gain }
+}
+
+#[test]
+fn calibrates_crosstalk() {
+    let (state, tty) = fake_pad();
+    let output = calibrate(
+        &state,
+        &tty,
+        &[
+            [1000, 250, 0, 0],
+            [100, 2000, 500, 0],
+            [0, 0, 1000, 0],
+            [0, 0, 0, 1000],
+        ],
+    );
+    assert_eq!(
+        state.lock().unwrap().config.crosstalk,
+        [leak(0, 1, 64), leak(1, 0, 13), leak(1, 2, 64)]
+    );
+    assert_eq!(
+        output[..4],
+        [
+            "panel\tto\tshare",
+            "0\t1\t25.0%",
+            "1\t0\t5.1%",
+            "1\t2\t25.0%"
+        ]
+    );
+
+    cli(&tty, &["clear-crosstalk"]);
+    let output = cli(&tty, &["crosstalk"]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "panel\tto\tshare\n"
+    );
+}
+
+#[test]
+fn calibrates_crosstalk_of_panels_with_several_sensors() {
+    let (state, tty) = fake_pad();
+    // Two sensors under the first panel, and one under each of the other two
+    cli(&tty, &["set-panel", "0", "0,1", "sum", "--press", "900"]);
+    cli(&tty, &["remove-panel", "1"]);
+    calibrate(
+        &state,
+        &tty,
+        &[[600, 400, 250, 0], [0, 0, 1000, 0], [100, 100, 0, 1000]],
+    );
+    // One share for each panel and channel outside it, taken of the force on the whole panel
+    assert_eq!(
+        state.lock().unwrap().config.crosstalk,
+        [leak(0, 2, 64), leak(2, 0, 26), leak(2, 1, 26)]
+    );
+
+    // The shares of a removed panel go with it
+    cli(&tty, &["remove-panel", "0"]);
+    assert_eq!(
+        state.lock().unwrap().config.crosstalk,
+        [leak(1, 0, 26), leak(1, 1, 26)]
+    );
+}
+
+#[test]
+fn sets_usb_strings() {
+    let (state, tty) = fake_pad();
+    cli(&tty, &["set-usb-string", "serial", "P1"]);
+    cli(&tty, &["set-usb-string", "product", "Left pad"]);
+    cli(&tty, &["set-usb-string", "product"]);
+    let usb = state.lock().unwrap().config.usb.clone();
+    assert_eq!(usb.serial_number.as_deref(), Some("P1"));
+    assert_eq!(usb.product, None);
+    assert_eq!(usb.manufacturer, None);
+
+    let too_long = "x".repeat(abi::MAX_USB_STRING_LEN + 1);
+    let output = Process::new(env!("CARGO_BIN_EXE_dancepad-cli"))
+        .args(["--port", tty.name().unwrap().as_str()])
+        .args(["set-usb-string", "serial", &too_long])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn reads_baselines() {
+    let (state, tty) = fake_pad();
+    let output = cli(&tty, &["baselines"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        ["channel\tbaseline", "0\t90", "1\t190", "2\t290", "3\t390"]
+    );
+
+    // Still capturing after power-on
+    state.lock().unwrap().baselines.clear();
+    let mut pad = Pad::new(tty);
+    assert_eq!(pad.baselines().unwrap(), []);
+}
+
+#[test]
+fn reads_usb_errors() {
+    let (state, tty) = fake_pad();
+    state.lock().unwrap().usb_errors = UsbErrors {
+        dropped_reports: 17,
+        bus: 9,
+        class_resets: 1,
+        ..Default::default()
+    };
+    let output = cli(&tty, &["usb-errors"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "dropped reports: 17",
+            "bus errors:      9",
+            "serialization:   0",
+            "serial errors:   0",
+            "class resets:    1",
+            "device resets:   0"
+        ]
+    );
+}
+
+#[test]
+fn reads_and_resets_latency() {
+    let (state, tty) = fake_pad();
+    let latency = Latency {
+        reports: 40,
+        min_us: 12,
+        mean_us: 150,
+        max_us: 980,
+    };
+    state.lock().unwrap().latency = latency;
+    let output = cli(&tty, &["latency", "--reset"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "reports: 40",
+            "min:     12 us",
+            "mean:    150 us",
+            "max:     980 us"
+        ]
+    );
+    assert_eq!(state.lock().unwrap().latency, Latency::default());
+}
+
+#[test]
+fn reads_conditions_and_sets_compensation() {
+    let (state, tty) = fake_pad();
+    let output = cli(&tty, &["conditions"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        ["supply:      3264 mV", "temperature: 31.5 °C"]
+    );
+
+    cli(&tty, &["set-compensation", "--supply", "true"]);
+    cli(&tty, &["set-compensation", "--temperature", "-24"]);
+    assert_eq!(
+        state.lock().unwrap().config.compensation,
+        Compensation {
+            supply: true,
+            temperature: -24,
+        }
+    );
+}
+
+#[test]
+fn reads_health_and_sets_fault_policy() {
+    let (state, tty) = fake_pad();
+    state.lock().unwrap().health[1] = SensorHealth::StuckLow;
+    let output = cli(&tty, &["health"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "channel\thealth",
+            "0\thealthy",
+            "1\tstuck at 0, disconnected or shorted to ground?",
+            "2\thealthy",
+            "3\thealthy"
+        ]
+    );
+
+    cli(&tty, &["set-fault-policy", "report"]);
+    assert_eq!(state.lock().unwrap().config.faults, FaultPolicy::Report);
+}
+
+#[test]
+fn sets_thresholds() {
+    let (state, tty) = fake_pad();
+    let mut pad = Pad::new(tty);
+
+    let t = Thresholds {
+        press: 900,
+        release: 700,
+    };
+    pad.set_thresholds(2, t).unwrap();
+    assert_eq!(pad.thresholds().unwrap()[2], t);
+    assert_eq!(state.lock().unwrap().config.thresholds[2], t);
+}
+
+#[test]
+fn reports_device_errors() {
+    let (_, tty) = fake_pad();
+    let mut pad = Pad::new(tty);
+
+    assert!(matches!(
+        pad.set_thresholds(CHANNELS as u8, Thresholds::DEFAULT),
+        Err(Error::Device(abi::Error::InvalidChannel))
+    ));
+    assert!(matches!(
+        pad.load(),
+        Err(Error::Device(abi::Error::Storage))
+    ));
+    // The connection stays usable after an error
+    assert!(pad.info().is_ok());
+}
+
+#[test]
+fn dump_and_restore_round_trip() {
+    let (state, tty) = fake_pad();
+    let dir = std::env::temp_dir().join(format!("dancepad-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("config.json");
+    let file = file.to_str().unwrap();
+
+    let mut custom = PadConfig::new(CHANNELS);
+    custom.thresholds[0] = Thresholds {
+        press: 1234,
+        release: 1000,
+    };
+    state.lock().unwrap().config = custom.clone();
+    cli(&tty, &["dump", file]);
+
+    state.lock().unwrap().config = PadConfig::new(CHANNELS);
+    cli(&tty, &["restore", "--save", file]);
+
+    let state = state.lock().unwrap();
+    assert_eq!(state.config, custom);
+    assert_eq!(state.saved.as_ref(), Some(&custom));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn monitor_streams_values() {
+    let (_, tty) = fake_pad();
+    let output = cli(&tty, &["monitor", "--interval", "0", "--count", "3"]);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "101\t201\t301\t401",
+            "102\t202\t302\t402",
+            "103\t203\t303\t403"
+        ]
+    );
+}
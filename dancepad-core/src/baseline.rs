@@ -0,0 +1,222 @@
+//! Idle level of every channel, and its drift over time
+
+use abi::AdcValues;
+
+use crate::{samples_in, ADC_MAX};
+
+/// Time the samples averaged into the initial baseline are taken over, in ms
+pub const CAPTURE_MS: u32 = 256;
+
+/// Fractional bits of the tracked baselines, so that slow tracking does not round away
+pub(crate) const FRAC: u32 = 16;
+
+/// Time constant of the tracking while a sensor reads above its baseline, in ms. About 8 s is slow
+/// enough for a resting foot not to be tracked away.
+const RISE_MS: u32 = 8192;
+
+/// Time constant of the tracking while a sensor reads below its baseline, in ms. Readings below the
+/// idle level mean that the baseline was captured under load, e.g. with a foot on the panel at
+/// power-on, so it follows within about 64 ms.
+const FALL_MS: u32 = 64;
+
+/// Captures the idle level of every channel at power-on, then tracks it while the channel is
+/// released
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels.
+#[derive(Clone, Debug)]
+pub struct Baseline<const N: usize> {
+    /// Sum of the samples while capturing, the baseline in 1/2^`FRAC` counts afterwards. Values
+    /// in grams reach all of `u16`, which leaves no room for the fraction in an `i32`.
+    levels: [i64; N],
+    captured: u16,
+    /// Samples in `CAPTURE_MS`
+    capture: u16,
+    /// Tracking rates, as power-of-two divisors of the difference to the sample, which round the
+    /// time constants down to a power of two samples
+    rise_shift: u32,
+    fall_shift: u32,
+}
+
+impl<const N: usize> Baseline<N> {
+    /// Tracks channels sampled at `sample_hz`
+    pub const fn new(sample_hz: u32) -> Self {
+        Self {
+            levels: [0; N],
+            captured: 0,
+            capture: samples_in(CAPTURE_MS, sample_hz),
+            rise_shift: samples_in(RISE_MS, sample_hz).ilog2(),
+            fall_shift: samples_in(FALL_MS, sample_hz).ilog2(),
+        }
+    }
+
+    /// Whether the initial capture is complete
+    pub fn is_ready(&self) -> bool {
+        self.captured >= self.capture
+    }
+
+    /// Takes in a new sample of every channel
+    ///
+    /// Only channels with their bit clear in `pressed` are tracked, so that a press is never
+    /// mistaken for drift.
+    pub fn update(&mut self, vals: &AdcValues<N>, pressed: u32) {
+        if !self.is_ready() {
+            for (level, val) in self.levels.iter_mut().zip(vals) {
+                *level += i64::from(*val);
+            }
+            self.captured += 1;
+            if self.is_ready() {
+                for level in self.levels.iter_mut() {
+                    *level = (*level / i64::from(self.capture)) << FRAC;
+                }
+            }
+            return;
+        }
+
+        for (idx, (level, val)) in self.levels.iter_mut().zip(vals).enumerate() {
+            if pressed & (1 << idx) != 0 {
+                continue;
+            }
+            let diff = (i64::from(*val) << FRAC) - *level;
+            let shift = if diff > 0 {
+                self.rise_shift
+            } else {
+                self.fall_shift
+            };
+            *level += diff >> shift;
+        }
+    }
+
+    /// Moves the baseline of every channel with its bit set in `channels` by `delta` 1/2^16 ADC
+    /// counts, pressed or not
+    ///
+    /// Only channels in ADC counts can be shifted, as the change of a count in grams depends on
+    /// the whole reading. Does nothing until `is_ready`, as the capture already averages over the
+    /// conditions at power-on.
+    pub fn shift(&mut self, delta: i32, channels: u32) {
+        if !self.is_ready() {
+            return;
+        }
+        for (idx, level) in self.levels.iter_mut().enumerate() {
+            if channels & (1 << idx) != 0 {
+                *level = (*level + i64::from(delta)).clamp(0, i64::from(ADC_MAX) << FRAC);
+            }
+        }
+    }
+
+    /// The baseline of every channel, all zero until `is_ready`
+    pub fn levels(&self) -> AdcValues<N> {
+        if !self.is_ready() {
+            return [0; N];
+        }
+        self.levels.map(|level| (level >> FRAC) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_HZ: u32 = 1000;
+
+    const CAPTURE_SAMPLES: u16 = samples_in(CAPTURE_MS, SAMPLE_HZ);
+
+    /// A baseline captured from a constant `level` on every channel
+    fn captured<const N: usize>(level: u16) -> Baseline<N> {
+        let mut baseline = Baseline::new(SAMPLE_HZ);
+        for _ in 0..CAPTURE_SAMPLES {
+            baseline.update(&[level; N], 0);
+        }
+        baseline
+    }
+
+    #[test]
+    fn captures_average_at_power_on() {
+        let mut baseline = Baseline::<2>::new(SAMPLE_HZ);
+        for idx in 0..CAPTURE_SAMPLES {
+            assert!(!baseline.is_ready());
+            assert_eq!(baseline.levels(), [0, 0]);
+            // Noise around 300 and 1000
+            let noise = if idx % 2 == 0 { 10 } else { 0 };
+            baseline.update(&[295 + noise, 1000], 0);
+        }
+        assert!(baseline.is_ready());
+        assert_eq!(baseline.levels(), [300, 1000]);
+    }
+
+    #[test]
+    fn rises_slowly() {
+        let mut baseline = captured::<1>(300);
+        for _ in 0..1000 {
+            baseline.update(&[1300], 0);
+        }
+        // 1 - e^(-1000/8192) of the way
+        let level = baseline.levels()[0];
+        assert!((400..450).contains(&level), "{level}");
+    }
+
+    #[test]
+    fn falls_quickly() {
+        let mut baseline = captured::<1>(1300);
+        for _ in 0..1000 {
+            baseline.update(&[300], 0);
+        }
+        assert_eq!(baseline.levels(), [300]);
+    }
+
+    #[test]
+    fn keeps_time_at_slow_sample_rates() {
+        // As slow as an HX711 with RATE tied low, at 10 samples per second
+        let mut baseline = Baseline::<1>::new(10);
+        baseline.update(&[1000], 0);
+        assert!(!baseline.is_ready());
+        baseline.update(&[1000], 0);
+        assert_eq!(baseline.levels(), [1000]);
+        // A time constant of 64 samples, the power of two below 8 s
+        for _ in 0..64 {
+            baseline.update(&[2000], 0);
+        }
+        // 1 - e^-1 of the way
+        let level = baseline.levels()[0];
+        assert!((1600..1650).contains(&level), "{level}");
+        baseline.update(&[300], 0);
+        assert_eq!(baseline.levels(), [300]);
+    }
+
+    #[test]
+    fn shifts_pressed_channels_too() {
+        let mut baseline = captured::<2>(300);
+        baseline.shift(-100 << FRAC, 0b11);
+        baseline.update(&[2000, 200], 0b01);
+        assert_eq!(baseline.levels(), [200, 200]);
+        baseline.shift(i32::MIN / 2, 0b11);
+        assert_eq!(baseline.levels(), [0, 0]);
+        baseline.shift(i32::MAX, 0b01);
+        assert_eq!(baseline.levels(), [ADC_MAX, 0]);
+    }
+
+    #[test]
+    fn tracks_grams_across_the_whole_range() {
+        let mut baseline = captured::<2>(u16::MAX);
+        assert_eq!(baseline.levels(), [u16::MAX; 2]);
+        for _ in 0..1000 {
+            baseline.update(&[40_000, u16::MAX], 0);
+        }
+        assert_eq!(baseline.levels(), [40_000, u16::MAX]);
+        // Channels in grams are left alone
+        baseline.shift(-100 << FRAC, 0b01);
+        assert_eq!(baseline.levels(), [ADC_MAX, u16::MAX]);
+    }
+
+    #[test]
+    fn pressed_channels_are_not_tracked() {
+        let mut baseline = captured::<2>(300);
+        for _ in 0..10_000 {
+            baseline.update(&[2000, 2000], 0b01);
+        }
+        let [held, tracked] = baseline.levels();
+        assert_eq!(held, 300);
+        assert!(tracked > 1400, "{tracked}");
+    }
+}
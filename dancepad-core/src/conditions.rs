@@ -0,0 +1,167 @@
+//! Supply voltage and die temperature, from the internal channels of the ADC
+
+use abi::Conditions;
+
+use crate::ADC_MAX;
+
+/// Supply voltage the factory calibration values were taken at, and readings are scaled to
+pub const NOMINAL_VDDA_MV: u32 = 3300;
+
+/// Smoothing of the internal channels, as a power-of-two divisor of the difference. Both change
+/// slowly, and the temperature sensor is noisy enough to need it: at 1 kHz this averages over
+/// about a quarter second.
+const SHIFT: u32 = 8;
+
+/// Fractional bits of the smoothed samples
+const FRAC: u32 = 8;
+
+/// Factory calibration of the internal channels, as read from the system memory of the chip
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Calibration {
+    /// VREFINT sample at the nominal supply
+    pub vrefint: u16,
+    /// Temperature sensor samples at 30 °C and 110 °C, at the nominal supply
+    pub ts_30: u16,
+    pub ts_110: u16,
+}
+
+/// Tracks the supply voltage and die temperature from samples of VREFINT and the temperature
+/// sensor
+#[derive(Clone, Debug)]
+pub struct Monitor {
+    cal: Calibration,
+    /// Smoothed samples in 1/2^`FRAC` counts, `None` until the first sample
+    smoothed: Option<(u32, u32)>,
+}
+
+impl Monitor {
+    pub const fn new(cal: Calibration) -> Self {
+        Self {
+            cal,
+            smoothed: None,
+        }
+    }
+
+    /// Takes in a sample of VREFINT and one of the temperature sensor
+    pub fn update(&mut self, vrefint: u16, ts: u16) {
+        let (vrefint, ts) = (u32::from(vrefint) << FRAC, u32::from(ts) << FRAC);
+        let smooth = |acc: u32, target: u32| {
+            // Kept in i64 so that the difference can be negative
+            (i64::from(acc) + ((i64::from(target) - i64::from(acc)) >> SHIFT)) as u32
+        };
+        self.smoothed = Some(match self.smoothed {
+            None => (vrefint, ts),
+            Some((v, t)) => (smooth(v, vrefint), smooth(t, ts)),
+        });
+    }
+
+    /// Supply voltage of the ADC in millivolts, the nominal supply until the first sample
+    pub fn vdda_mv(&self) -> u32 {
+        match self.smoothed {
+            Some((vrefint, _)) if vrefint > 0 => {
+                let nominal = u64::from(NOMINAL_VDDA_MV * u32::from(self.cal.vrefint)) << FRAC;
+                (nominal / u64::from(vrefint)) as u32
+            }
+            _ => NOMINAL_VDDA_MV,
+        }
+    }
+
+    /// Die temperature in tenths of a degree Celsius, 0 until the first sample
+    pub fn temperature(&self) -> i16 {
+        let Some((_, ts)) = self.smoothed else {
+            return 0;
+        };
+        let span = i64::from(self.cal.ts_110) - i64::from(self.cal.ts_30);
+        if span <= 0 {
+            return 0;
+        }
+        // The calibration values were taken at the nominal supply
+        let ts = i64::from(ts) * i64::from(self.vdda_mv()) / i64::from(NOMINAL_VDDA_MV);
+        let above_30 = ts - (i64::from(self.cal.ts_30) << FRAC);
+        let tenths = 300 + above_30 * 800 / (span << FRAC);
+        tenths.clamp(i16::MIN.into(), i16::MAX.into()) as i16
+    }
+
+    pub fn conditions(&self) -> Conditions {
+        Conditions {
+            vdda_mv: self.vdda_mv().min(u16::MAX.into()) as u16,
+            temperature: self.temperature(),
+        }
+    }
+}
+
+/// Scales a `sample` taken at a supply of `vdda_mv` to what it would read at the nominal supply
+pub fn compensate_supply(sample: u16, vdda_mv: u32) -> u16 {
+    (u32::from(sample) * vdda_mv / NOMINAL_VDDA_MV).min(ADC_MAX.into()) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calibration values typical of an STM32F411
+    const CAL: Calibration = Calibration {
+        vrefint: 1500,
+        ts_30: 940,
+        ts_110: 1200,
+    };
+
+    #[test]
+    fn nominal_until_sampled() {
+        let monitor = Monitor::new(CAL);
+        assert_eq!(
+            monitor.conditions(),
+            Conditions {
+                vdda_mv: NOMINAL_VDDA_MV as u16,
+                temperature: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn measures_supply_droop() {
+        let mut monitor = Monitor::new(CAL);
+        monitor.update(1500, 940);
+        assert_eq!(monitor.vdda_mv(), 3300);
+        // At a lower supply the fixed reference reads higher
+        for _ in 0..4000 {
+            monitor.update(1650, 940);
+        }
+        // Within the rounding of the smoothing
+        let vdda = monitor.vdda_mv();
+        assert!((3000..=3001).contains(&vdda), "{vdda}");
+    }
+
+    #[test]
+    fn interpolates_temperature_between_calibration_points() {
+        let mut monitor = Monitor::new(CAL);
+        monitor.update(1500, 940);
+        assert_eq!(monitor.temperature(), 300);
+
+        let mut monitor = Monitor::new(CAL);
+        monitor.update(1500, 1070);
+        assert_eq!(monitor.temperature(), 700);
+
+        // The same voltage reads higher at a lower supply
+        let mut monitor = Monitor::new(CAL);
+        monitor.update(1650, 1034);
+        assert_eq!(monitor.temperature(), 300);
+    }
+
+    #[test]
+    fn smooths_noise() {
+        let mut monitor = Monitor::new(CAL);
+        for idx in 0..4000 {
+            let noise = if idx % 2 == 0 { 13 } else { -13 };
+            monitor.update(1500, (1070 + noise) as u16);
+        }
+        assert!((695..=705).contains(&monitor.temperature()));
+    }
+
+    #[test]
+    fn scales_to_nominal_supply() {
+        assert_eq!(compensate_supply(2000, 3300), 2000);
+        assert_eq!(compensate_supply(2000, 3000), 1818);
+        assert_eq!(compensate_supply(4000, 3600), ADC_MAX);
+    }
+}
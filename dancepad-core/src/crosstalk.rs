@@ -0,0 +1,99 @@
+//! Removing the force that leaks from one panel into the sensors of others
+
+use abi::{AdcValues, Crosstalk, Panel};
+
+/// Force `above` the baseline of every channel, less the share of the force on every panel that
+/// `crosstalk` says leaks into it
+///
+/// The force on a panel is that on all of its sensors together, whatever its rule. Shares are taken
+/// of the force as measured, so that two panels leaking into each other do not cancel out. Entries
+/// for panels missing from `panels` or channels past `N` are ignored.
+pub(crate) fn compensate<const N: usize>(
+    above: &AdcValues<N>,
+    panels: &[Panel],
+    crosstalk: &[Crosstalk],
+) -> AdcValues<N> {
+    let mut leak = [0u32; N];
+    for c in crosstalk {
+        if let (Some(panel), Some(leak)) = (
+            panels.get(usize::from(c.panel)),
+            leak.get_mut(usize::from(c.to)),
+        ) {
+            let force: u32 = (0..N)
+                .filter(|idx| panel.sensors & (1 << idx) != 0)
+                .map(|idx| u32::from(above[idx]))
+                .sum();
+            // Rounded to nearest
+            *leak += (force * u32::from(c.gain) + 128) >> 8;
+        }
+    }
+    core::array::from_fn(|idx| above[idx].saturating_sub(leak[idx].min(u16::MAX.into()) as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use abi::Aggregation;
+
+    use super::*;
+
+    const fn leak(panel: u8, to: u8, gain: u8) -> Crosstalk {
+        Crosstalk { panel, to, gain }
+    }
+
+    fn singles(channels: usize) -> Vec<Panel> {
+        (0..channels).map(Panel::single).collect()
+    }
+
+    #[test]
+    fn subtracts_leaks_from_neighbours() {
+        // A quarter of channel 0 shows up on 1, and an eighth of it on 2
+        let crosstalk = [leak(0, 1, 64), leak(0, 2, 32)];
+        let panels = singles(3);
+        assert_eq!(
+            compensate(&[2000, 520, 250], &panels, &crosstalk),
+            [2000, 20, 0]
+        );
+        assert_eq!(
+            compensate(&[0, 520, 250], &panels, &crosstalk),
+            [0, 520, 250]
+        );
+    }
+
+    #[test]
+    fn leaks_add_up_and_do_not_cancel() {
+        let crosstalk = [leak(0, 1, 128), leak(1, 0, 128), leak(2, 1, 128)];
+        let panels = singles(3);
+        assert_eq!(
+            compensate(&[1000, 1000, 200], &panels, &crosstalk),
+            [500, 400, 200]
+        );
+        assert_eq!(
+            compensate(&[1000, 1000, 200], &panels, &[]),
+            [1000, 1000, 200]
+        );
+    }
+
+    #[test]
+    fn takes_shares_of_whole_panels() {
+        // Three sensors under the first panel, one under the second
+        let panels = [
+            Panel {
+                sensors: 0b0111,
+                rule: Aggregation::Max,
+                ..Panel::single(0)
+            },
+            Panel::single(3),
+        ];
+        let crosstalk = [leak(0, 3, 32)];
+        assert_eq!(
+            compensate(&[1000, 600, 400, 300], &panels, &crosstalk),
+            [1000, 600, 400, 50]
+        );
+    }
+
+    #[test]
+    fn ignores_missing_panels_and_channels() {
+        let crosstalk = [leak(0, 7, 255), leak(7, 0, 255)];
+        assert_eq!(compensate(&[1000, 0], &singles(2), &crosstalk), [1000, 0]);
+    }
+}
@@ -0,0 +1,230 @@
+//! Per-channel smoothing of raw samples
+
+use abi::{Filter, MAX_WINDOW};
+
+/// Fractional bits of the EMA accumulator, so that long time constants do not round away
+const EMA_FRAC: u32 = 8;
+
+/// State of the `Filter` of one channel
+///
+/// The state is reset whenever the filter it is applied with changes, so that a new configuration
+/// starts from the next sample instead of the history of the old one.
+#[derive(Clone, Debug)]
+pub struct FilterState {
+    filter: Filter,
+    /// Last samples, oldest first once `filled` reaches the window length
+    window: [u16; MAX_WINDOW as usize],
+    /// Index in `window` of the next sample
+    next: usize,
+    /// Number of valid samples in `window`
+    filled: usize,
+    /// Running sum of `window` for the moving average, the output in 1/2^`EMA_FRAC` counts for the
+    /// EMA
+    acc: u32,
+    /// Last output, held while a spike is rejected
+    last: u16,
+    /// Whether the previous sample was held back as a spike
+    rejected: bool,
+}
+
+impl FilterState {
+    pub const fn new() -> Self {
+        Self {
+            filter: Filter::None,
+            window: [0; MAX_WINDOW as usize],
+            next: 0,
+            filled: 0,
+            acc: 0,
+            last: 0,
+            rejected: false,
+        }
+    }
+
+    /// Feeds `sample` through `filter` and returns the filtered value
+    ///
+    /// Until the window of a moving average or median is full, the output is computed over the
+    /// samples seen so far.
+    pub fn apply(&mut self, filter: &Filter, sample: u16) -> u16 {
+        if *filter != self.filter {
+            *self = Self::new();
+            self.filter = *filter;
+        }
+
+        let out = match *filter {
+            Filter::None => sample,
+            Filter::MovingAverage { len } => {
+                let evicted = self.push(sample, len);
+                self.acc = self.acc + u32::from(sample) - u32::from(evicted.unwrap_or(0));
+                (self.acc / self.filled as u32) as u16
+            }
+            Filter::Ema { shift } => {
+                let target = u32::from(sample) << EMA_FRAC;
+                if self.filled == 0 {
+                    self.acc = target;
+                    self.filled = 1;
+                }
+                // Kept in i64 so that the difference can be negative
+                let diff = i64::from(target) - i64::from(self.acc);
+                self.acc = (i64::from(self.acc) + (diff >> shift)) as u32;
+                (self.acc >> EMA_FRAC) as u16
+            }
+            Filter::Median { len } => {
+                self.push(sample, len);
+                let mut sorted = [0; MAX_WINDOW as usize];
+                let sorted = &mut sorted[..self.filled];
+                sorted.copy_from_slice(&self.window[..self.filled]);
+                sorted.sort_unstable();
+                sorted[self.filled / 2]
+            }
+            Filter::SpikeReject { max_step } => {
+                let jump = sample.abs_diff(self.last) > max_step;
+                if self.filled == 0 || !jump || self.rejected {
+                    self.filled = 1;
+                    self.rejected = false;
+                    sample
+                } else {
+                    self.rejected = true;
+                    self.last
+                }
+            }
+        };
+        self.last = out;
+        out
+    }
+
+    /// Appends `sample` to a window of `len` samples, returning the sample it replaced, if any
+    fn push(&mut self, sample: u16, len: u8) -> Option<u16> {
+        let len = usize::from(len.clamp(1, MAX_WINDOW));
+        let evicted = (self.filled == len).then(|| self.window[self.next]);
+        self.window[self.next] = sample;
+        self.next = (self.next + 1) % len;
+        self.filled = (self.filled + 1).min(len);
+        evicted
+    }
+}
+
+impl Default for FilterState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::check, ADC_MAX};
+
+    fn run(filter: Filter, trace: &[u16]) -> Vec<u16> {
+        let mut state = FilterState::new();
+        trace.iter().map(|s| state.apply(&filter, *s)).collect()
+    }
+
+    /// Every filter accepted by the configuration
+    fn all_filters() -> impl Iterator<Item = Filter> {
+        let windows = (1..=MAX_WINDOW)
+            .flat_map(|len| [Filter::MovingAverage { len }, Filter::Median { len }]);
+        let emas = (0..=abi::MAX_EMA_SHIFT).map(|shift| Filter::Ema { shift });
+        let spikes = [1, 100, ADC_MAX].map(|max_step| Filter::SpikeReject { max_step });
+        [Filter::None]
+            .into_iter()
+            .chain(windows)
+            .chain(emas)
+            .chain(spikes)
+    }
+
+    /// Samples after the first sample of a full-scale step until the output reaches halfway
+    fn measured_latency(filter: Filter) -> usize {
+        let mut state = FilterState::new();
+        for _ in 0..4 * 256 {
+            state.apply(&filter, 0);
+        }
+        (0..)
+            .position(|_| state.apply(&filter, ADC_MAX) >= ADC_MAX / 2)
+            .unwrap()
+    }
+
+    #[test]
+    fn latency_is_within_documented_bound() {
+        for filter in all_filters() {
+            assert!(filter.is_valid(), "{filter:?}");
+            let latency = measured_latency(filter);
+            assert!(
+                latency <= usize::from(filter.latency()),
+                "{filter:?} took {latency} samples"
+            );
+        }
+    }
+
+    #[test]
+    fn window_latency_is_exact() {
+        for len in 1..=MAX_WINDOW {
+            for filter in [Filter::MovingAverage { len }, Filter::Median { len }] {
+                assert_eq!(measured_latency(filter), usize::from(filter.latency()));
+            }
+        }
+    }
+
+    #[test]
+    fn moving_average() {
+        let trace = [100, 200, 300, 400, 500, 500];
+        assert_eq!(
+            run(Filter::MovingAverage { len: 3 }, &trace),
+            [100, 150, 200, 300, 400, 466]
+        );
+    }
+
+    #[test]
+    fn ema() {
+        let trace = [1000, 0, 0, 0];
+        assert_eq!(run(Filter::Ema { shift: 1 }, &trace), [1000, 500, 250, 125]);
+    }
+
+    #[test]
+    fn median_removes_impulses() {
+        let trace = [300, 300, 4095, 300, 0, 300, 300, 4095, 4095, 300];
+        assert_eq!(
+            run(Filter::Median { len: 3 }, &trace),
+            [300, 300, 300, 300, 300, 300, 300, 300, 4095, 4095]
+        );
+    }
+
+    #[test]
+    fn spike_reject_holds_single_sample_jumps() {
+        let filter = Filter::SpikeReject { max_step: 100 };
+        let trace = [300, 350, 4095, 360, 2000, 2000, 2050];
+        assert_eq!(run(filter, &trace), [300, 350, 350, 360, 360, 2000, 2050]);
+    }
+
+    #[test]
+    fn changing_filter_resets_state() {
+        let mut state = FilterState::new();
+        for _ in 0..16 {
+            state.apply(&Filter::MovingAverage { len: 16 }, 4000);
+        }
+        assert_eq!(state.apply(&Filter::MovingAverage { len: 4 }, 0), 0);
+    }
+
+    #[test]
+    fn constant_input_passes_unchanged() {
+        check(|rng| {
+            let value = rng.range(0, ADC_MAX);
+            for filter in all_filters() {
+                let out = run(filter, &[value; 32]);
+                assert!(out.iter().all(|v| *v == value), "{filter:?}: {out:?}");
+            }
+        });
+    }
+
+    #[test]
+    fn output_stays_within_input_range() {
+        check(|rng| {
+            let trace: Vec<u16> = (0..64).map(|_| rng.range(0, ADC_MAX)).collect();
+            let (lo, hi) = (*trace.iter().min().unwrap(), *trace.iter().max().unwrap());
+            for filter in all_filters() {
+                for out in run(filter, &trace) {
+                    assert!((lo..=hi).contains(&out), "{filter:?}: {out}");
+                }
+            }
+        });
+    }
+}
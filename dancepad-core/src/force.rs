@@ -0,0 +1,149 @@
+//! Force estimation from the voltage divider of an FSR
+//!
+//! The chain runs from a reading in ADC counts to millivolts, to the resistance of the FSR, to its
+//! conductance, and through the curve of the sensor to grams. See `abi::ForceModel` for the
+//! circuit.
+
+use abi::{ForceCurve, ForceModel, ForceTable};
+
+use crate::{conditions::NOMINAL_VDDA_MV, to_millivolts, Samples, ADC_MAX};
+
+/// Resistance in ohms of an FSR above a resistor of `divider_ohms` to ground, with `mv` across
+/// that resistor at a supply of `vdda_mv`, or `None` for an open FSR with nothing across it
+pub fn resistance(mv: u16, vdda_mv: u32, divider_ohms: u32) -> Option<u32> {
+    if mv == 0 {
+        return None;
+    }
+    let across = u64::from(vdda_mv.saturating_sub(mv.into()));
+    let ohms = u64::from(divider_ohms) * across / u64::from(mv);
+    Some(ohms.min(u32::MAX.into()) as u32)
+}
+
+/// Conductance in µS of a resistance of `ohms`, saturating for a short
+pub fn conductance(ohms: u32) -> u32 {
+    1_000_000u32.checked_div(ohms).unwrap_or(u32::MAX)
+}
+
+/// Grams on a sensor of conductance `microsiemens` by `curve`
+///
+/// A table curve missing from `tables` reads no force.
+pub fn grams(microsiemens: u32, curve: &ForceCurve, tables: &[ForceTable]) -> u16 {
+    let grams = match *curve {
+        ForceCurve::Linear { grams_per_ms } => {
+            u64::from(microsiemens) * u64::from(grams_per_ms) / 1000
+        }
+        ForceCurve::Table { table } => match tables.get(usize::from(table)) {
+            Some(table) => interpolate(microsiemens, table),
+            None => 0,
+        },
+    };
+    grams.min(u16::MAX.into()) as u16
+}
+
+/// Grams at `microsiemens` along the points of `table`
+fn interpolate(microsiemens: u32, table: &ForceTable) -> u64 {
+    let mut below = (0, 0);
+    for point in table {
+        let (x0, y0) = below;
+        let (x1, y1) = (u64::from(point.microsiemens), u64::from(point.grams));
+        if u64::from(microsiemens) <= x1 {
+            // Points are strictly increasing in conductance, so the segment is never empty
+            return y0 + (y1 - y0) * (u64::from(microsiemens) - x0) / (x1 - x0).max(1);
+        }
+        below = (x1, y1);
+    }
+    below.1
+}
+
+/// Estimated grams on the sensor of a channel reading `sample`, as modeled by `model`
+///
+/// The sample is taken at the nominal supply, which a divider fed from the supply of the ADC is
+/// independent of.
+pub fn estimate(sample: u16, model: &ForceModel, tables: &[ForceTable]) -> u16 {
+    let mv = to_millivolts(sample, NOMINAL_VDDA_MV);
+    match resistance(mv, NOMINAL_VDDA_MV, model.divider_ohms) {
+        Some(ohms) => grams(conductance(ohms), &model.curve, tables),
+        None => 0,
+    }
+}
+
+/// Largest value of a channel with `model` taking in `samples`, and so its highest press threshold
+pub fn full_scale(model: Option<&ForceModel>, samples: Samples) -> u16 {
+    match (model, samples) {
+        (None, Samples::Counts) => ADC_MAX,
+        _ => u16::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use abi::ForcePoint;
+
+    use super::*;
+    use crate::testing::check;
+
+    const MODEL: ForceModel = ForceModel {
+        divider_ohms: 10_000,
+        curve: ForceCurve::Linear { grams_per_ms: 3000 },
+    };
+
+    #[test]
+    fn follows_the_divider() {
+        // Half the supply across the resistor, so the FSR matches it
+        assert_eq!(resistance(1650, 3300, 10_000), Some(10_000));
+        assert_eq!(resistance(3000, 3300, 10_000), Some(1000));
+        assert_eq!(resistance(3300, 3300, 10_000), Some(0));
+        assert_eq!(resistance(0, 3300, 10_000), None);
+        assert_eq!(conductance(10_000), 100);
+        assert_eq!(conductance(0), u32::MAX);
+    }
+
+    #[test]
+    fn estimates_force() {
+        assert_eq!(estimate(0, &MODEL, &[]), 0);
+        // 10 kΩ, 100 µS
+        assert_eq!(estimate(2048, &MODEL, &[]), 300);
+        // 1 kΩ, 1 mS
+        assert_eq!(estimate(3724, &MODEL, &[]), 3000);
+        assert_eq!(estimate(ADC_MAX, &MODEL, &[]), u16::MAX);
+    }
+
+    #[test]
+    fn interpolates_tables() {
+        let table: ForceTable = [(100, 200), (300, 1000), (1000, 5000)]
+            .into_iter()
+            .map(|(microsiemens, grams)| ForcePoint {
+                microsiemens,
+                grams,
+            })
+            .collect();
+        let tables = [table];
+        let curve = ForceCurve::Table { table: 0 };
+        let grams = |microsiemens| grams(microsiemens, &curve, &tables);
+        assert_eq!(grams(0), 0);
+        assert_eq!(grams(50), 100);
+        assert_eq!(grams(100), 200);
+        assert_eq!(grams(200), 600);
+        assert_eq!(grams(1000), 5000);
+        assert_eq!(grams(u32::MAX), 5000);
+        assert_eq!(
+            super::grams(200, &ForceCurve::Table { table: 1 }, &tables),
+            0
+        );
+    }
+
+    #[test]
+    fn force_grows_with_the_reading() {
+        check(|rng| {
+            let [a, b] = rng.array(0, ADC_MAX);
+            let model = ForceModel {
+                divider_ohms: u32::from(rng.range(1, u16::MAX)),
+                curve: ForceCurve::Linear {
+                    grams_per_ms: rng.range(0, u16::MAX),
+                },
+            };
+            let (lo, hi) = (a.min(b), a.max(b));
+            assert!(estimate(lo, &model, &[]) <= estimate(hi, &model, &[]));
+        });
+    }
+}
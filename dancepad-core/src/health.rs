@@ -0,0 +1,256 @@
+//! Detection of disconnected, shorted and otherwise faulty sensors
+
+use abi::{AdcValues, SensorHealth, Thresholds};
+
+use crate::{samples_in, Samples, ADC_MAX};
+
+/// Time a sensor has to read at a rail before it counts as stuck there, in ms. A foot pressing
+/// hard enough can saturate the ADC too, so this is longer than any hold in a chart.
+pub const STUCK_MS: u32 = 10_000;
+
+/// Time a sensor has to read the same value away from the rails before it counts as flat, in ms.
+/// The noise of a connected sensor changes its reading far more often than that.
+pub const FLAT_MS: u32 = 5000;
+
+/// Distance from a rail, in ADC counts, that still counts as being at the rail
+const RAIL_MARGIN: u16 = 1;
+
+/// Health checks of every channel, fed with the raw samples
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels.
+#[derive(Clone, Debug)]
+pub struct Health<const N: usize> {
+    channels: [Channel; N],
+    /// What the raw samples measure, and so whether full scale is a rail
+    samples: Samples,
+    /// Samples in `STUCK_MS` and `FLAT_MS`
+    stuck: u16,
+    flat: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Channel {
+    last: u16,
+    /// Samples in a row equal to `last`
+    same: u16,
+    /// Samples in a row at the rail `last` is at, if any
+    at_rail: u16,
+    health: SensorHealth,
+}
+
+impl<const N: usize> Health<N> {
+    /// Checks channels sampled at `sample_hz`, taking in `samples`
+    ///
+    /// Load cells reading `Samples::Grams` are never stuck at full scale, which they reach under a
+    /// heavy enough load, and only stuck at 0.
+    pub const fn new(sample_hz: u32, samples: Samples) -> Self {
+        Self {
+            channels: [Channel {
+                last: 0,
+                same: 0,
+                at_rail: 0,
+                health: SensorHealth::Healthy,
+            }; N],
+            samples,
+            stuck: samples_in(STUCK_MS, sample_hz),
+            flat: samples_in(FLAT_MS, sample_hz),
+        }
+    }
+
+    /// Takes in a new raw sample of every channel, and the `baselines` once they are captured
+    ///
+    /// A baseline is out of range if the press threshold of its channel lies beyond the
+    /// `full_scale` of the channel, in the units of its baseline. Channels without an entry in
+    /// `thresholds` are never out of range.
+    pub fn update(
+        &mut self,
+        raw: &AdcValues<N>,
+        baselines: Option<&AdcValues<N>>,
+        thresholds: &[Thresholds],
+        full_scale: &AdcValues<N>,
+    ) {
+        let samples = self.samples;
+        let top = match samples {
+            Samples::Counts => ADC_MAX,
+            Samples::Grams => u16::MAX,
+        };
+        let at_top = |value: u16| value >= top - RAIL_MARGIN;
+        let rail = |value: u16| value <= RAIL_MARGIN || samples == Samples::Counts && at_top(value);
+        for (idx, (channel, sample)) in self.channels.iter_mut().zip(raw).enumerate() {
+            if *sample == channel.last {
+                channel.same = channel.same.saturating_add(1);
+            } else {
+                channel.same = 1;
+            }
+            // A sensor flipping from one rail to the other is not stuck at either
+            if rail(*sample) && (*sample <= RAIL_MARGIN) == (channel.last <= RAIL_MARGIN) {
+                channel.at_rail = channel.at_rail.saturating_add(1);
+            } else {
+                channel.at_rail = u16::from(rail(*sample));
+            }
+            channel.last = *sample;
+
+            let press = thresholds.get(idx).map(|t| t.press);
+            let out_of_range = match (baselines, press) {
+                (Some(baselines), Some(press)) => {
+                    baselines[idx] > full_scale[idx].saturating_sub(press)
+                }
+                _ => false,
+            };
+            channel.health = if channel.at_rail >= self.stuck {
+                if *sample <= RAIL_MARGIN {
+                    SensorHealth::StuckLow
+                } else {
+                    SensorHealth::StuckHigh
+                }
+            } else if channel.same >= self.flat && !rail(*sample) && !at_top(*sample) {
+                SensorHealth::Flat
+            } else if out_of_range {
+                SensorHealth::BaselineOutOfRange
+            } else {
+                SensorHealth::Healthy
+            };
+        }
+    }
+
+    /// Health of every channel
+    pub fn status(&self) -> [SensorHealth; N] {
+        self.channels.map(|channel| channel.health)
+    }
+
+    /// Bit `n` set if channel `n` is faulty
+    pub fn faulty(&self) -> u32 {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter(|(_, channel)| channel.health.is_faulty())
+            .fold(0, |mask, (idx, _)| mask | 1 << idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check;
+
+    const T: [Thresholds; 2] = [Thresholds::DEFAULT; 2];
+
+    const FULL_SCALE: [u16; 2] = [ADC_MAX; 2];
+
+    const SAMPLE_HZ: u32 = 1000;
+
+    const STUCK_SAMPLES: u16 = samples_in(STUCK_MS, SAMPLE_HZ);
+
+    const FLAT_SAMPLES: u16 = samples_in(FLAT_MS, SAMPLE_HZ);
+
+    fn run(health: &mut Health<2>, raw: [u16; 2], samples: u16) {
+        for _ in 0..samples {
+            health.update(&raw, None, &T, &FULL_SCALE);
+        }
+    }
+
+    #[test]
+    fn detects_stuck_sensors() {
+        let mut health = Health::<2>::new(SAMPLE_HZ, Samples::Counts);
+        run(&mut health, [0, ADC_MAX], STUCK_SAMPLES - 1);
+        assert_eq!(health.faulty(), 0);
+        run(&mut health, [1, ADC_MAX - 1], 1);
+        assert_eq!(
+            health.status(),
+            [SensorHealth::StuckLow, SensorHealth::StuckHigh]
+        );
+        assert_eq!(health.faulty(), 0b11);
+
+        // Stepping on the panel proves the sensor is connected after all
+        run(&mut health, [1000, ADC_MAX], 1);
+        assert_eq!(
+            health.status(),
+            [SensorHealth::Healthy, SensorHealth::StuckHigh]
+        );
+        assert_eq!(health.faulty(), 0b10);
+    }
+
+    #[test]
+    fn keeps_time_at_slow_sample_rates() {
+        // As slow as an HX711 with RATE tied low, at 10 samples per second
+        let mut health = Health::<2>::new(10, Samples::Counts);
+        run(&mut health, [0, 2000], 49);
+        assert_eq!(health.faulty(), 0);
+        run(&mut health, [0, 2000], 1);
+        assert_eq!(health.status(), [SensorHealth::Healthy, SensorHealth::Flat]);
+        run(&mut health, [0, 2000], 50);
+        assert_eq!(
+            health.status(),
+            [SensorHealth::StuckLow, SensorHealth::Flat]
+        );
+    }
+
+    #[test]
+    fn load_cells_at_full_scale_are_loaded() {
+        let mut health = Health::<2>::new(SAMPLE_HZ, Samples::Grams);
+        // Far more than 4095 g on the second cell, with noise
+        for idx in 0..STUCK_SAMPLES {
+            health.update(&[u16::MAX, 40_000 + idx % 2], None, &T, &[u16::MAX; 2]);
+        }
+        assert_eq!(health.faulty(), 0);
+        for idx in 0..STUCK_SAMPLES {
+            health.update(&[0, 40_000 + idx % 2], None, &T, &[u16::MAX; 2]);
+        }
+        assert_eq!(
+            health.status(),
+            [SensorHealth::StuckLow, SensorHealth::Healthy]
+        );
+    }
+
+    #[test]
+    fn detects_flat_sensors() {
+        let mut health = Health::<2>::new(SAMPLE_HZ, Samples::Counts);
+        for idx in 0..FLAT_SAMPLES {
+            health.update(&[2000, 300 + idx % 3], None, &T, &FULL_SCALE);
+        }
+        assert_eq!(health.status(), [SensorHealth::Flat, SensorHealth::Healthy]);
+        health.update(&[2001, 300], None, &T, &FULL_SCALE);
+        assert_eq!(health.faulty(), 0);
+    }
+
+    #[test]
+    fn detects_baselines_out_of_range() {
+        let mut health = Health::<2>::new(SAMPLE_HZ, Samples::Counts);
+        let baselines = [ADC_MAX - 512, ADC_MAX - 511];
+        health.update(&[3000, 3000], Some(&baselines), &T, &FULL_SCALE);
+        assert_eq!(
+            health.status(),
+            [SensorHealth::Healthy, SensorHealth::BaselineOutOfRange]
+        );
+        // No thresholds, no press to be out of reach of
+        health.update(&[3000, 3000], Some(&baselines), &[], &FULL_SCALE);
+        assert_eq!(health.faulty(), 0);
+        // Baselines and thresholds in grams, on a scale well past the ADC
+        let baselines = [40_000, 61_000];
+        let thresholds = [Thresholds {
+            press: 5000,
+            release: 4000,
+        }; 2];
+        health.update(&[3000, 3000], Some(&baselines), &thresholds, &[u16::MAX; 2]);
+        assert_eq!(
+            health.status(),
+            [SensorHealth::Healthy, SensorHealth::BaselineOutOfRange]
+        );
+    }
+
+    /// Sensors that are connected read noise, and are never found faulty
+    #[test]
+    fn noise_is_healthy() {
+        check(|rng| {
+            let mut health = Health::<2>::new(SAMPLE_HZ, Samples::Counts);
+            let idle: [u16; 2] = rng.array(RAIL_MARGIN + 1, ADC_MAX - RAIL_MARGIN - 3);
+            for _ in 0..FLAT_SAMPLES {
+                let raw = idle.map(|level| level + rng.range(0, 2));
+                health.update(&raw, Some(&[0, 0]), &T, &FULL_SCALE);
+            }
+            assert_eq!(health.faulty(), 0);
+        });
+    }
+}
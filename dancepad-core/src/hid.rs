@@ -0,0 +1,206 @@
+//! Layout of the HID reports on the wire
+
+use crate::Report;
+
+/// Number of per-sensor pressure axes in the report
+pub const PRESSURE_AXES: usize = 6;
+
+/// Largest number of buttons in the report, one per bit of `Report::buttons`
+pub const MAX_BUTTONS: usize = 32;
+
+/// Length of the packed report with the most buttons, in bytes
+pub const MAX_REPORT_LEN: usize = report_len(MAX_BUTTONS);
+
+/// Length of the packed report with `buttons` buttons in bytes, padded to whole bytes
+pub const fn report_len(buttons: usize) -> usize {
+    2 + PRESSURE_AXES + buttons.div_ceil(8)
+}
+
+/// Items before the buttons: X and Y come first like on any joystick, followed by the six
+/// remaining axes DirectInput knows about for the pressure on each sensor
+#[rustfmt::skip]
+const AXES: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x04,       // Usage (Joystick)
+    0xa1, 0x01,       // Collection (Application)
+    0x09, 0x30,       //   Usage (X)
+    0x09, 0x31,       //   Usage (Y)
+    0x15, 0x81,       //   Logical Minimum (-127)
+    0x25, 0x7f,       //   Logical Maximum (127)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x02,       //   Report Count (2)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x32,       //   Usage (Z)
+    0x09, 0x33,       //   Usage (Rx)
+    0x09, 0x34,       //   Usage (Ry)
+    0x09, 0x35,       //   Usage (Rz)
+    0x09, 0x36,       //   Usage (Slider)
+    0x09, 0x37,       //   Usage (Dial)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x06,       //   Report Count (6)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+];
+
+/// Longest report descriptor, with buttons that need padding
+const MAX_DESCRIPTOR_LEN: usize = AXES.len() + 16 + 6 + 1;
+
+/// HID report descriptor of the joystick, with as many buttons as the pad has panels
+#[derive(Clone, Debug)]
+pub struct ReportDescriptor {
+    bytes: [u8; MAX_DESCRIPTOR_LEN],
+    len: usize,
+}
+
+impl ReportDescriptor {
+    /// The descriptor of a joystick with `buttons` buttons
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buttons` is 0 or greater than `MAX_BUTTONS`.
+    pub const fn new(buttons: usize) -> Self {
+        assert!(
+            buttons > 0 && buttons <= MAX_BUTTONS,
+            "button count must be 1 to MAX_BUTTONS"
+        );
+        let count = buttons as u8;
+        let padding = (buttons.div_ceil(8) * 8 - buttons) as u8;
+        #[rustfmt::skip]
+        let tail: [u8; 23] = [
+            0x05, 0x09,       //   Usage Page (Button)
+            0x19, 0x01,       //   Usage Minimum (1)
+            0x29, count,      //   Usage Maximum (buttons)
+            0x15, 0x00,       //   Logical Minimum (0)
+            0x25, 0x01,       //   Logical Maximum (1)
+            0x75, 0x01,       //   Report Size (1)
+            0x95, count,      //   Report Count (buttons)
+            0x81, 0x02,       //   Input (Data, Variable, Absolute)
+            0x75, 0x01,       //   Report Size (1)
+            0x95, padding,    //   Report Count (padding)
+            0x81, 0x03,       //   Input (Constant), padding to a whole byte
+            0xc0,             // End Collection
+        ];
+        let mut bytes = [0; MAX_DESCRIPTOR_LEN];
+        let mut len = 0;
+        while len < AXES.len() {
+            bytes[len] = AXES[len];
+            len += 1;
+        }
+        let mut idx = 0;
+        while idx < tail.len() {
+            // Whole bytes of buttons need no padding item
+            let is_padding = idx >= 16 && idx < 22;
+            if !(is_padding && padding == 0) {
+                bytes[len] = tail[idx];
+                len += 1;
+            }
+            idx += 1;
+        }
+        Self { bytes, len }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Report {
+    /// Packs the report as laid out by `ReportDescriptor::new(buttons)`, into the first
+    /// `report_len(buttons)` bytes
+    ///
+    /// Buttons past `buttons` are dropped.
+    pub fn to_bytes(&self, buttons: usize) -> [u8; MAX_REPORT_LEN] {
+        let mut bytes = [0; MAX_REPORT_LEN];
+        bytes[0] = self.x as u8;
+        bytes[1] = self.y as u8;
+        bytes[2..2 + PRESSURE_AXES].copy_from_slice(&self.pressure);
+        let mask = u32::MAX
+            .checked_shr((MAX_BUTTONS - buttons) as u32)
+            .unwrap_or(0);
+        let len = report_len(buttons);
+        bytes[2 + PRESSURE_AXES..len]
+            .copy_from_slice(&(self.buttons & mask).to_le_bytes()[..buttons.div_ceil(8)]);
+        bytes
+    }
+}
+
+/// Key codes of the pressed channels for the keyboard personality
+///
+/// `keys` holds the HID keyboard usage ID of every channel. Channels without a key, or with key 0,
+/// press nothing.
+pub fn pressed_keys(buttons: u32, keys: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    keys.iter()
+        .enumerate()
+        .filter(move |(idx, key)| **key != 0 && buttons & (1 << idx) != 0)
+        .map(|(_, key)| *key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sums up the bits of every Input item in a report descriptor
+    fn input_bits(descriptor: &[u8]) -> usize {
+        let (mut size, mut count, mut bits) = (0, 0, 0);
+        let mut items = descriptor;
+        while let [prefix, rest @ ..] = items {
+            let len = match prefix & 0b11 {
+                3 => 4,
+                n => n as usize,
+            };
+            let data = rest[..len]
+                .iter()
+                .rev()
+                .fold(0, |acc, b| acc << 8 | *b as usize);
+            match prefix & !0b11 {
+                0x74 => size = data,
+                0x94 => count = data,
+                0x80 => bits += size * count,
+                _ => {}
+            }
+            items = &rest[len..];
+        }
+        bits
+    }
+
+    #[test]
+    fn maps_pressed_channels_to_keys() {
+        let keys = [0x50, 0, 0x52, 0x4f];
+        let pressed = |buttons| pressed_keys(buttons, &keys).collect::<Vec<_>>();
+        assert_eq!(pressed(0), []);
+        assert_eq!(pressed(0b1011), [0x50, 0x4f]);
+        // Channels past the end of `keys` have no key
+        assert_eq!(pressed(0b1_0100), [0x52]);
+    }
+
+    #[test]
+    fn descriptor_matches_report_length() {
+        for buttons in 1..=MAX_BUTTONS {
+            let descriptor = ReportDescriptor::new(buttons);
+            assert_eq!(
+                input_bits(descriptor.as_bytes()),
+                report_len(buttons) * 8,
+                "{buttons} buttons"
+            );
+        }
+    }
+
+    #[test]
+    fn packs_fields_in_descriptor_order() {
+        let report = Report {
+            buttons: 0x1_8001,
+            x: -127,
+            y: 1,
+            pressure: [1, 2, 3, 4, 5, 255],
+        };
+        let bytes = report.to_bytes(16);
+        assert_eq!(
+            bytes[..report_len(16)],
+            [0x81, 0x01, 1, 2, 3, 4, 5, 255, 0x01, 0x80]
+        );
+        // Buttons past the last are dropped, along with their bytes
+        assert_eq!(report.to_bytes(4)[..report_len(4)], bytes[..9]);
+        assert_eq!(report.to_bytes(32)[10..], [0x01, 0x00]);
+    }
+}
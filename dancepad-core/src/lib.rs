@@ -0,0 +1,126 @@
+//! Hardware-independent signal path of the pad
+//!
+//! Raw ADC samples go in, press state and HID reports come out. Nothing in here knows about the
+//! MCU, so the firmware is a thin adapter around `Pipeline` and the same logic can be tested, or
+//! simulated, on the host.
+#![cfg_attr(not(test), no_std)]
+
+pub mod baseline;
+pub mod conditions;
+mod crosstalk;
+mod filter;
+pub mod force;
+pub mod health;
+pub mod hid;
+pub mod mux;
+mod panel;
+mod pipeline;
+mod reporting;
+pub mod source;
+pub mod switch;
+#[cfg(test)]
+mod testing;
+mod trigger;
+pub mod usb;
+
+pub use filter::FilterState;
+pub use pipeline::{Pipeline, Report};
+pub use reporting::{LatencyStats, ReportQueue};
+pub use trigger::Trigger;
+
+/// Largest value produced by the 12-bit ADC
+pub const ADC_MAX: u16 = 4095;
+
+/// What the raw samples of the sensor channels measure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Samples {
+    /// Counts of the 12-bit ADC, or of another ADC scaled to them, up to `ADC_MAX`
+    Counts,
+    /// Grams weighed by load cells, up to `u16::MAX`. A cell at full scale is loaded rather than
+    /// shorted, and its reading is in grams from the start, without a `ForceModel`.
+    Grams,
+}
+
+/// Converts a raw ADC sample to millivolts, given the supply voltage of the ADC in millivolts
+pub fn to_millivolts(sample: u16, vdda: u32) -> u16 {
+    (u32::from(sample) * vdda / (u32::from(ADC_MAX) + 1)) as u16
+}
+
+/// Number of samples taken in `ms` milliseconds at `sample_hz`, at least one and at most
+/// `u16::MAX`
+///
+/// The time constants of the signal path are given in milliseconds, so that they hold for sources
+/// as slow as an HX711 at 10 samples per second as well as for the internal ADC at 1 kHz.
+pub const fn samples_in(ms: u32, sample_hz: u32) -> u16 {
+    let samples = ms as u64 * sample_hz as u64 / 1000;
+    if samples == 0 {
+        1
+    } else if samples > u16::MAX as u64 {
+        u16::MAX
+    } else {
+        samples as u16
+    }
+}
+
+/// Averages consecutive scans of `N` channels into one value per channel
+///
+/// `scans` holds whole scans back to back, as the ADC writes them through DMA. Oversampling this
+/// way lowers the noise while the pipeline keeps running at its own rate.
+///
+/// # Panics
+///
+/// Panics if `scans` is empty or does not hold a whole number of scans.
+pub fn average_scans<const N: usize>(scans: &[u16]) -> abi::AdcValues<N> {
+    assert!(!scans.is_empty() && scans.len() % N == 0, "partial scan");
+    let count = (scans.len() / N) as u32;
+    core::array::from_fn(|channel| {
+        let sum: u32 = scans
+            .iter()
+            .skip(channel)
+            .step_by(N)
+            .map(|s| u32::from(*s))
+            .sum();
+        // Rounded to nearest
+        ((sum + count / 2) / count) as u16
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check;
+
+    #[test]
+    fn converts_full_scale() {
+        assert_eq!(to_millivolts(0, 3300), 0);
+        assert_eq!(to_millivolts(2048, 3300), 1650);
+        assert_eq!(to_millivolts(ADC_MAX, 3300), 3299);
+    }
+
+    #[test]
+    fn counts_samples_in_a_time() {
+        assert_eq!(samples_in(256, 1000), 256);
+        assert_eq!(samples_in(256, 10), 2);
+        assert_eq!(samples_in(64, 10), 1);
+        assert_eq!(samples_in(10_000, 80), 800);
+        assert_eq!(samples_in(10_000, 84_000_000), u16::MAX);
+    }
+
+    #[test]
+    fn averages_interleaved_scans() {
+        let scans = [100, 4095, 0, 103, 4095, 0, 100, 4095, 1];
+        assert_eq!(average_scans::<3>(&scans), [101, 4095, 0]);
+        assert_eq!(average_scans::<3>(&scans[..3]), [100, 4095, 0]);
+    }
+
+    #[test]
+    fn millivolts_are_monotonic_and_below_vdda() {
+        check(|rng| {
+            let [a, b] = rng.array(0, ADC_MAX);
+            let vdda = u32::from(rng.range(1800, 3600));
+            let (lo, hi) = (a.min(b), a.max(b));
+            assert!(to_millivolts(lo, vdda) <= to_millivolts(hi, vdda));
+            assert!(u32::from(to_millivolts(hi, vdda)) < vdda);
+        });
+    }
+}
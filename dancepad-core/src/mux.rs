@@ -0,0 +1,198 @@
+//! Scanning more sensors than there are ADC pins, through analog multiplexers
+//!
+//! Every ADC pin sits behind a mux, and all muxes share their select lines. Each selected input
+//! gets a run of scans of the ADC pins, of which the first is discarded: the sample capacitor of
+//! the ADC still holds some of the previous input's charge.
+
+use abi::AdcValues;
+use embedded_hal::digital::OutputPin;
+
+use crate::average_scans;
+
+/// An analog multiplexer, from the point of view of the one selecting its inputs
+pub trait Mux {
+    /// Number of inputs
+    const INPUTS: usize;
+
+    /// Connects input `input` to the output. The output needs the settle time of the mux, and of
+    /// whatever drives its inputs, before it can be sampled.
+    fn select(&mut self, input: usize);
+}
+
+/// A mux with `LINES` binary-coded select lines, S0 first, such as the 16-input CD74HC4067 or the
+/// 8-input CD4051
+///
+/// Without select lines, it stands for an ADC pin wired straight to a single sensor.
+pub struct SelectLines<P, const LINES: usize> {
+    lines: [P; LINES],
+}
+
+pub type Cd74hc4067<P> = SelectLines<P, 4>;
+pub type Cd4051<P> = SelectLines<P, 3>;
+
+impl<P: OutputPin, const LINES: usize> SelectLines<P, LINES> {
+    pub fn new(lines: [P; LINES]) -> Self {
+        Self { lines }
+    }
+}
+
+impl<P: OutputPin, const LINES: usize> Mux for SelectLines<P, LINES> {
+    const INPUTS: usize = 1 << LINES;
+
+    fn select(&mut self, input: usize) {
+        for (bit, line) in self.lines.iter_mut().enumerate() {
+            // GPIO writes do not fail on the targets the pad runs on
+            line.set_state((input & 1 << bit != 0).into()).ok();
+        }
+    }
+}
+
+/// Assembles frames of every sensor behind the muxes, one selected input at a time
+///
+/// Channel `pin * INPUTS + input` is the sensor on input `input` of the mux on ADC pin `pin`, so
+/// that the sensors of a mux are numbered consecutively.
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels, the number of ADC pins times the inputs of a mux.
+#[derive(Clone, Debug)]
+pub struct MuxScan<const N: usize> {
+    input: usize,
+    frame: AdcValues<N>,
+}
+
+impl<const N: usize> MuxScan<N> {
+    /// Starts scanning at the first input of `mux`
+    pub fn new<M: Mux>(mux: &mut M) -> Self {
+        mux.select(0);
+        Self {
+            input: 0,
+            frame: [0; N],
+        }
+    }
+
+    /// Takes in the scans of the ADC taken while the current input was selected, back to back
+    /// with `S` values each, and selects the next input
+    ///
+    /// The first value of a scan is that of the first ADC pin, and a scan holds at least
+    /// `N / M::INPUTS` of them. Values past the ADC pins, such as internal channels, are carried
+    /// along for the caller.
+    ///
+    /// Returns the average of the kept scans, and the frame of every sensor once the last input
+    /// has been scanned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no scan to keep, or `N` is not a multiple of `M::INPUTS`.
+    pub fn step<M: Mux, const S: usize>(
+        &mut self,
+        scans: &[u16],
+        mux: &mut M,
+    ) -> ([u16; S], Option<AdcValues<N>>) {
+        assert!(N % M::INPUTS == 0, "every mux needs all of its inputs");
+        // Nothing changed since the last scan without a mux to switch
+        let kept = if M::INPUTS > 1 { &scans[S..] } else { scans };
+        let scan = average_scans::<S>(kept);
+
+        for (pin, value) in scan.iter().take(N / M::INPUTS).enumerate() {
+            self.frame[pin * M::INPUTS + self.input] = *value;
+        }
+        self.input = (self.input + 1) % M::INPUTS;
+        if M::INPUTS > 1 {
+            mux.select(self.input);
+        }
+        (scan, (self.input == 0).then_some(self.frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+
+    /// A mux that records the inputs selected on it
+    #[derive(Default)]
+    struct MockMux<const INPUTS: usize> {
+        selected: Vec<usize>,
+    }
+
+    impl<const INPUTS: usize> Mux for MockMux<INPUTS> {
+        const INPUTS: usize = INPUTS;
+
+        fn select(&mut self, input: usize) {
+            self.selected.push(input);
+        }
+    }
+
+    /// Two scans of three values, the first of which reads what is left of the previous input
+    fn scans(pins: [u16; 2], internal: u16) -> [u16; 6] {
+        [9999, 9999, 9999, pins[0], pins[1], internal]
+    }
+
+    #[test]
+    fn assembles_frames_input_by_input() {
+        let mut mux = MockMux::<4>::default();
+        let mut scan = MuxScan::<8>::new(&mut mux);
+        for input in 0..3 {
+            let pins = [100 + input, 200 + input];
+            let (values, frame) = scan.step::<_, 3>(&scans(pins, 1500), &mut mux);
+            assert_eq!(values, [pins[0], pins[1], 1500]);
+            assert_eq!(frame, None);
+        }
+        let (_, frame) = scan.step::<_, 3>(&scans([103, 203], 1500), &mut mux);
+        assert_eq!(frame, Some([100, 101, 102, 103, 200, 201, 202, 203]));
+        assert_eq!(mux.selected, [0, 1, 2, 3, 0]);
+
+        // And around again
+        let (_, frame) = scan.step::<_, 3>(&scans([0, 0], 1500), &mut mux);
+        assert_eq!(frame, None);
+        assert_eq!(mux.selected, [0, 1, 2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn keeps_every_scan_without_a_mux() {
+        let mut mux = MockMux::<1>::default();
+        let mut scan = MuxScan::<2>::new(&mut mux);
+        let (values, frame) = scan.step::<_, 2>(&[10, 20, 30, 40], &mut mux);
+        assert_eq!(values, [20, 30]);
+        assert_eq!(frame, Some([20, 30]));
+        assert_eq!(mux.selected, [0]);
+    }
+
+    /// A select line that records its level
+    struct Line<'a>(&'a core::cell::Cell<bool>);
+
+    impl ErrorType for Line<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Line<'_> {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.set(true);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drives_select_lines_in_binary() {
+        let levels: [core::cell::Cell<bool>; 4] = Default::default();
+        let mut mux = Cd74hc4067::new([0, 1, 2, 3].map(|idx| Line(&levels[idx])));
+        assert_eq!(<Cd74hc4067<Line> as Mux>::INPUTS, 16);
+        for input in [0b1011, 0b0100, 0b1111, 0] {
+            mux.select(input);
+            let read = levels
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (bit, level)| acc | usize::from(level.get()) << bit);
+            assert_eq!(read, input);
+        }
+    }
+}
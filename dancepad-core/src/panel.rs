@@ -0,0 +1,85 @@
+//! Combining the force on the sensors under a panel
+
+use abi::{Aggregation, Panel};
+
+/// Combined force on the sensors of `panel`, from the force `above` the baseline of every channel
+///
+/// Sensors with their bit set in `masked` drop out, so that a weighted average is taken over the
+/// remaining sensors only. `Aggregation::Any` panels have no combined force, and read 0.
+pub(crate) fn force(panel: &Panel, above: &[u16], weights: &[u8], masked: u32) -> u16 {
+    let sensors = above
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| (panel.sensors & !masked) & (1 << idx) != 0);
+    match panel.rule {
+        Aggregation::Any => 0,
+        Aggregation::Sum => sensors
+            .map(|(_, force)| u32::from(*force))
+            .sum::<u32>()
+            .min(u16::MAX.into()) as u16,
+        Aggregation::Max => sensors.map(|(_, force)| *force).max().unwrap_or(0),
+        Aggregation::WeightedAverage => {
+            let (total, weight) = sensors.fold((0u32, 0u32), |(total, weight), (idx, force)| {
+                let w = u32::from(weights.get(idx).copied().unwrap_or(0));
+                (total + w * u32::from(*force), weight + w)
+            });
+            if weight == 0 {
+                0
+            } else {
+                (total / weight) as u16
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use abi::Thresholds;
+
+    use super::*;
+
+    fn panel(sensors: u32, rule: Aggregation) -> Panel {
+        Panel {
+            sensors,
+            rule,
+            thresholds: Thresholds::DEFAULT,
+            rapid: None,
+        }
+    }
+
+    const ABOVE: [u16; 4] = [100, 400, 300, 4000];
+    const WEIGHTS: [u8; 4] = [1, 1, 2, 1];
+
+    #[test]
+    fn any_has_no_combined_force() {
+        assert_eq!(
+            force(&panel(0b0111, Aggregation::Any), &ABOVE, &WEIGHTS, 0),
+            0
+        );
+    }
+
+    #[test]
+    fn sums_sensors_of_the_panel() {
+        let sum = panel(0b0111, Aggregation::Sum);
+        assert_eq!(force(&sum, &ABOVE, &WEIGHTS, 0), 800);
+        assert_eq!(force(&sum, &ABOVE, &WEIGHTS, 0b0010), 400);
+        assert_eq!(force(&sum, &[u16::MAX; 4], &WEIGHTS, 0), u16::MAX);
+    }
+
+    #[test]
+    fn takes_largest_sensor_of_the_panel() {
+        let max = panel(0b0111, Aggregation::Max);
+        assert_eq!(force(&max, &ABOVE, &WEIGHTS, 0), 400);
+        assert_eq!(force(&max, &ABOVE, &WEIGHTS, 0b0111), 0);
+    }
+
+    #[test]
+    fn weighs_sensors_of_the_panel() {
+        let weighted = panel(0b0111, Aggregation::WeightedAverage);
+        // (100 + 400 + 2 * 300) / 4
+        assert_eq!(force(&weighted, &ABOVE, &WEIGHTS, 0), 275);
+        // A masked sensor does not drag the average down
+        assert_eq!(force(&weighted, &ABOVE, &WEIGHTS, 0b0001), 333);
+        assert_eq!(force(&weighted, &ABOVE, &[0; 4], 0), 0);
+    }
+}
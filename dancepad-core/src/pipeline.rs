@@ -0,0 +1,721 @@
+//! From raw samples to HID reports
+
+use abi::{
+    AdcValues, Aggregation, AnalogMode, FaultPolicy, Filter, PadConfig, Position, RapidTrigger,
+    SensorHealth, Thresholds,
+};
+
+use crate::{
+    baseline::{self, Baseline},
+    crosstalk,
+    filter::FilterState,
+    force,
+    health::Health,
+    hid::PRESSURE_AXES,
+    panel,
+    trigger::Trigger,
+    Samples, ADC_MAX,
+};
+
+/// State of the pad as sent to the host
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Press state, with bit `n` set if panel `n` is pressed
+    pub buttons: u32,
+    /// Center of pressure, see `AnalogMode::CenterOfPressure`
+    pub x: i8,
+    pub y: i8,
+    /// Pressure on the first sensors, see `AnalogMode::Pressure`
+    pub pressure: [u8; PRESSURE_AXES],
+}
+
+/// The signal path of a pad, from raw ADC samples to reports
+///
+/// Sampling and reporting run at their own rates: `sample` is fed from the ADC as samples come
+/// in, while `report` is called whenever the host is due a report and uses the latest values.
+///
+/// Nothing is reported as pressed until the baselines have been captured, which takes
+/// `baseline::CAPTURE_MS` after power-on. Channels found faulty are reported as
+/// released while `config.faults` masks them.
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels, which also bounds the number of panels.
+#[derive(Clone, Debug)]
+pub struct Pipeline<const N: usize> {
+    filters: [FilterState; N],
+    values: AdcValues<N>,
+    baseline: Baseline<N>,
+    /// Press state of every sensor by its own thresholds
+    trigger: Trigger<N>,
+    /// Press state of every panel by the combined force on its sensors
+    panels: Trigger<N>,
+    /// Sensors that are pressed, or under a panel pressed by their combined force, as of the last
+    /// report
+    held: u32,
+    health: Health<N>,
+    /// Last die temperature taken in, in tenths of a degree Celsius
+    temperature: Option<i16>,
+    samples: Samples,
+}
+
+impl<const N: usize> Pipeline<N> {
+    /// A signal path fed `sample_hz` frames per second of `samples`, which its time constants are
+    /// scaled to
+    pub const fn new(sample_hz: u32, samples: Samples) -> Self {
+        Self {
+            filters: [const { FilterState::new() }; N],
+            values: [0; N],
+            baseline: Baseline::new(sample_hz),
+            trigger: Trigger::new(),
+            panels: Trigger::new(),
+            held: 0,
+            health: Health::new(sample_hz, samples),
+            temperature: None,
+            samples,
+        }
+    }
+
+    /// Whether channel `idx` is in grams rather than ADC counts, weighed by a load cell or
+    /// estimated by a `ForceModel`
+    fn in_grams(&self, idx: usize, config: &PadConfig) -> bool {
+        self.samples == Samples::Grams || matches!(config.force.get(idx), Some(Some(_)))
+    }
+
+    /// Takes in a new raw sample of every channel, filters it as configured, and estimates the
+    /// force on the channels with a force model
+    ///
+    /// Channels without an entry in `config.filters` are not filtered.
+    pub fn sample(&mut self, raw: &AdcValues<N>, config: &PadConfig) {
+        for (idx, state) in self.filters.iter_mut().enumerate() {
+            let filter = config.filters.get(idx).unwrap_or(&Filter::None);
+            let value = state.apply(filter, raw[idx]);
+            self.values[idx] = match config.force.get(idx) {
+                Some(Some(model)) => force::estimate(value, model, &config.force_tables),
+                _ => value,
+            };
+        }
+        self.baseline.update(&self.values, self.held);
+        let baselines = self.baselines();
+        let full_scale: AdcValues<N> = core::array::from_fn(|idx| {
+            force::full_scale(config.force.get(idx).and_then(Option::as_ref), self.samples)
+        });
+        self.health
+            .update(raw, baselines.as_ref(), &config.thresholds, &full_scale);
+    }
+
+    /// Takes in the die temperature, in tenths of a degree Celsius, and moves the baselines with
+    /// it as set by `config.compensation`
+    ///
+    /// The coefficient is in ADC counts, so the channels with a force model are left to the
+    /// tracking of their baselines.
+    pub fn track_temperature(&mut self, temperature: i16, config: &PadConfig) {
+        if let Some(last) = self.temperature {
+            let tenths = i64::from(temperature) - i64::from(last);
+            // The coefficient is in 1/16 counts per degree
+            let delta =
+                (tenths * i64::from(config.compensation.temperature)) << (baseline::FRAC - 4);
+            let counts = (0..N)
+                .filter(|idx| !self.in_grams(*idx, config))
+                .fold(0, |mask, idx| mask | 1 << idx);
+            self.baseline.shift(
+                (delta / 10).clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+                counts,
+            );
+        }
+        self.temperature = Some(temperature);
+    }
+
+    /// Latest filtered value of every channel, as used for press detection, in grams for the
+    /// channels with a force model
+    pub fn values(&self) -> &AdcValues<N> {
+        &self.values
+    }
+
+    /// Idle level of every channel, or `None` while it is still being captured
+    pub fn baselines(&self) -> Option<AdcValues<N>> {
+        self.baseline.is_ready().then(|| self.baseline.levels())
+    }
+
+    /// Health of every channel, as judged from the raw samples
+    pub fn health(&self) -> [SensorHealth; N] {
+        self.health.status()
+    }
+
+    /// Bit `n` set if channel `n` is faulty
+    pub fn faulty(&self) -> u32 {
+        self.health.faulty()
+    }
+
+    /// Updates the press state from the latest values and builds a report from it
+    ///
+    /// The force leaking from panels into other channels as set by `config.crosstalk` is taken out
+    /// first.
+    /// The axes are filled in according to `config.analog`, and rest at zero otherwise.
+    pub fn report(&mut self, config: &PadConfig) -> Report {
+        let Some(baselines) = self.baselines() else {
+            return Report::default();
+        };
+        let masked = match config.faults {
+            FaultPolicy::Report => 0,
+            FaultPolicy::Mask => self.health.faulty(),
+        };
+        let above: AdcValues<N> = core::array::from_fn(|idx| {
+            if masked & (1 << idx) != 0 {
+                return 0;
+            }
+            self.values[idx].saturating_sub(baselines[idx])
+        });
+        let panels = &config.panels[..config.panels.len().min(N)];
+        let above = crosstalk::compensate(&above, panels, &config.crosstalk);
+        // The sensors of an `Any` panel take its rapid trigger on their own thresholds
+        let rapid: [Option<RapidTrigger>; N] = core::array::from_fn(|idx| {
+            panels
+                .iter()
+                .find(|p| p.rule == Aggregation::Any && p.sensors & (1 << idx) != 0)
+                .and_then(|p| p.rapid)
+        });
+        let pressed = self.trigger.update(&above, &config.thresholds, &rapid);
+
+        let forces: AdcValues<N> = core::array::from_fn(|idx| {
+            panels
+                .get(idx)
+                .map_or(0, |p| panel::force(p, &above, &config.weights, masked))
+        });
+        let thresholds: [Thresholds; N] = core::array::from_fn(|idx| {
+            panels
+                .get(idx)
+                .map_or(Thresholds::DEFAULT, |p| p.thresholds)
+        });
+        let rapid: [Option<RapidTrigger>; N] =
+            core::array::from_fn(|idx| panels.get(idx).and_then(|p| p.rapid));
+        let combined = self
+            .panels
+            .update(&forces, &thresholds[..panels.len()], &rapid);
+
+        let (mut buttons, mut held) = (0, pressed);
+        for (idx, p) in panels.iter().enumerate() {
+            let down = match p.rule {
+                Aggregation::Any => pressed & p.sensors != 0,
+                _ => combined & (1 << idx) != 0,
+            };
+            if down {
+                buttons |= 1 << idx;
+                if p.rule != Aggregation::Any {
+                    held |= p.sensors;
+                }
+            }
+        }
+        self.held = held;
+
+        let mut report = Report {
+            buttons,
+            ..Default::default()
+        };
+        match config.analog {
+            AnalogMode::Off => {}
+            AnalogMode::Pressure => {
+                for (idx, axis) in report.pressure.iter_mut().enumerate().take(N) {
+                    // Channels in grams reach far past any press, so their axes span a fixed
+                    // range instead of what is left of their scale
+                    let range = if self.in_grams(idx, config) {
+                        ADC_MAX
+                    } else {
+                        ADC_MAX.saturating_sub(baselines[idx])
+                    };
+                    *axis = normalize(above[idx], range);
+                }
+            }
+            AnalogMode::CenterOfPressure => {
+                (report.x, report.y) = center_of_pressure(&above, held, &config.positions);
+            }
+        }
+        report
+    }
+}
+
+/// Scales a value `above` the baseline so that `range` above the baseline spans a whole axis
+fn normalize(above: u16, range: u16) -> u8 {
+    if range == 0 {
+        return 0;
+    }
+    (u32::from(above) * 255 / u32::from(range)).min(255) as u8
+}
+
+/// Force-weighted mean of the `positions` of the `pressed` sensors, or the center if none is
+///
+/// Only pressed channels count, so that the noise of idle sensors does not make it wander.
+fn center_of_pressure(above: &[u16], pressed: u32, positions: &[Position]) -> (i8, i8) {
+    let (mut x, mut y, mut total) = (0i32, 0i32, 0i32);
+    for (idx, (force, position)) in above.iter().zip(positions).enumerate() {
+        if pressed & (1 << idx) == 0 {
+            continue;
+        }
+        let force = i32::from(*force);
+        x += force * i32::from(position.x);
+        y += force * i32::from(position.y);
+        total += force;
+    }
+    if total == 0 {
+        return (0, 0);
+    }
+    // A weighted mean stays within the range of the positions, so it fits in an i8
+    ((x / total) as i8, (y / total) as i8)
+}
+
+#[cfg(test)]
+mod tests {
+    use abi::{Crosstalk, ForceCurve, ForceModel, Panel};
+
+    use super::*;
+    use crate::{baseline::CAPTURE_MS, health::STUCK_MS, samples_in, testing::check};
+
+    const SAMPLE_HZ: u32 = 1000;
+
+    const CAPTURE_SAMPLES: u16 = samples_in(CAPTURE_MS, SAMPLE_HZ);
+
+    const STUCK_SAMPLES: u16 = samples_in(STUCK_MS, SAMPLE_HZ);
+
+    /// A pipeline that has captured a baseline of zero on every channel
+    fn captured<const N: usize>() -> Pipeline<N> {
+        let mut pipeline = Pipeline::new(SAMPLE_HZ, Samples::Counts);
+        for _ in 0..CAPTURE_SAMPLES {
+            pipeline.sample(&[0; N], &PadConfig::new(N));
+        }
+        pipeline
+    }
+
+    #[test]
+    fn reports_latest_sample() {
+        let config = PadConfig::new(2);
+        let mut pipeline = captured::<2>();
+
+        pipeline.sample(&[1000, 0], &config);
+        pipeline.sample(&[0, 1000], &config);
+        assert_eq!(pipeline.values(), &[0, 1000]);
+        assert_eq!(
+            pipeline.report(&config),
+            Report {
+                buttons: 0b10,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn nothing_is_pressed_while_capturing() {
+        let config = PadConfig::new(1);
+        let mut pipeline = Pipeline::<1>::new(SAMPLE_HZ, Samples::Counts);
+        for _ in 0..CAPTURE_SAMPLES - 1 {
+            pipeline.sample(&[4095], &config);
+            assert_eq!(pipeline.report(&config).buttons, 0);
+            assert_eq!(pipeline.baselines(), None);
+        }
+        pipeline.sample(&[4095], &config);
+        assert_eq!(pipeline.baselines(), Some([4095]));
+    }
+
+    /// Replays a sensor whose idle level drifts from 300 to 1100 over a minute, stepped on for
+    /// half a second every five seconds
+    #[test]
+    fn follows_drift() {
+        let config = PadConfig::new(1);
+        let mut pipeline = Pipeline::<1>::new(SAMPLE_HZ, Samples::Counts);
+        let mut presses = 0;
+        let mut pressed = false;
+        for ms in 0..60_000u32 {
+            let idle = 300 + (ms * 800 / 60_000) as u16;
+            let stepped_on = ms >= 1000 && ms % 5000 < 500;
+            let raw = if stepped_on { idle + 900 } else { idle };
+            pipeline.sample(&[raw], &config);
+
+            let now = pipeline.report(&config).buttons == 1;
+            assert_eq!(now, stepped_on, "at {ms} ms");
+            if now && !pressed {
+                presses += 1;
+            }
+            pressed = now;
+        }
+        assert_eq!(presses, 11);
+        // A fixed threshold of 512 would have been passed for good by the idle level alone. The
+        // baseline trails the ramp by about its slope times the tracking time constant.
+        let baseline = pipeline.baselines().unwrap()[0];
+        assert!((950..=1100).contains(&baseline), "{baseline}");
+    }
+
+    #[test]
+    fn baselines_follow_temperature() {
+        let mut config = PadConfig::new(2);
+        // 2 counts per degree
+        config.compensation.temperature = 32;
+        let mut pipeline = captured::<2>();
+        pipeline.sample(&[1000, 1000], &config);
+        pipeline.sample(&[1000, 1000], &config);
+        pipeline.report(&config);
+
+        // Warming up by 10 degrees in steps of a tenth, with both panels held down
+        for tenths in 250..=350 {
+            pipeline.track_temperature(tenths, &config);
+            pipeline.sample(&[1000, 1000], &config);
+            assert_eq!(pipeline.report(&config).buttons, 0b11);
+        }
+        assert_eq!(pipeline.baselines(), Some([20, 20]));
+
+        // Off by default
+        config.compensation.temperature = 0;
+        pipeline.track_temperature(0, &config);
+        assert_eq!(pipeline.baselines(), Some([20, 20]));
+    }
+
+    #[test]
+    fn masks_faulty_channels() {
+        let mut config = PadConfig::new(2);
+        config.analog = AnalogMode::Pressure;
+        let mut pipeline = captured::<2>();
+
+        // Shorted to the supply while released
+        for idx in 0..STUCK_SAMPLES {
+            pipeline.sample(&[ADC_MAX, 1000 + idx % 2], &config);
+            pipeline.report(&config);
+        }
+        assert_eq!(
+            pipeline.health(),
+            [SensorHealth::StuckHigh, SensorHealth::Healthy]
+        );
+        let report = pipeline.report(&config);
+        assert_eq!(report.buttons, 0b10);
+        assert_eq!(report.pressure[0], 0);
+
+        config.faults = FaultPolicy::Report;
+        assert_eq!(pipeline.report(&config).buttons, 0b11);
+    }
+
+    /// Two panels with two sensors each, and the pipeline that reads them
+    fn two_panels(rule: Aggregation, press: u16) -> (PadConfig, Pipeline<4>) {
+        let mut config = PadConfig::new(4);
+        config.panels.clear();
+        for sensors in [0b0011, 0b1100] {
+            config
+                .panels
+                .push(Panel {
+                    sensors,
+                    rule,
+                    thresholds: Thresholds {
+                        press,
+                        release: press - 100,
+                    },
+                    rapid: None,
+                })
+                .unwrap();
+        }
+        (config, captured::<4>())
+    }
+
+    #[test]
+    fn any_sensor_presses_its_panel() {
+        let (config, mut pipeline) = two_panels(Aggregation::Any, 512);
+        let mut buttons = |raw: [u16; 4]| {
+            pipeline.sample(&raw, &config);
+            pipeline.report(&config).buttons
+        };
+        assert_eq!(buttons([0, 600, 0, 0]), 0b01);
+        assert_eq!(buttons([600, 0, 0, 600]), 0b11);
+        assert_eq!(buttons([0, 0, 400, 400]), 0b00);
+    }
+
+    #[test]
+    fn panels_combine_their_sensors() {
+        let (config, mut pipeline) = two_panels(Aggregation::Sum, 800);
+        pipeline.sample(&[400, 400, 700, 0], &config);
+        assert_eq!(pipeline.report(&config).buttons, 0b01);
+
+        let (config, mut pipeline) = two_panels(Aggregation::Max, 800);
+        pipeline.sample(&[400, 400, 700, 900], &config);
+        assert_eq!(pipeline.report(&config).buttons, 0b10);
+
+        let (mut config, mut pipeline) = two_panels(Aggregation::WeightedAverage, 800);
+        config.weights[2] = 7;
+        pipeline.sample(&[1000, 0, 1000, 0], &config);
+        assert_eq!(pipeline.report(&config).buttons, 0b10);
+    }
+
+    /// Replays a trill on the first of two summing panels, whose force never drops below its
+    /// release level in between
+    #[test]
+    fn rapid_trigger_follows_trills() {
+        let (mut config, mut pipeline) = two_panels(Aggregation::Sum, 800);
+        let trace = [
+            0, 900, 1000, 900, 850, 800, 950, 1000, 1100, 900, 750, 950, 600, 0,
+        ];
+        let mut run = |config: &PadConfig| {
+            trace
+                .iter()
+                .map(|&v| {
+                    pipeline.sample(&[v, 0, 0, 0], config);
+                    pipeline.report(config).buttons
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(&config), [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0]);
+
+        config.panels[0].rapid = Some(RapidTrigger {
+            rise: 200,
+            fall: 200,
+        });
+        assert_eq!(run(&config), [0, 1, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn compensates_crosstalk() {
+        let mut config = PadConfig::new(2);
+        let mut pipeline = captured::<2>();
+        // A stomp on the first panel flexes the frame under the second
+        pipeline.sample(&[3000, 700], &config);
+        assert_eq!(pipeline.report(&config).buttons, 0b11);
+
+        config
+            .crosstalk
+            .push(Crosstalk {
+                panel: 0,
+                to: 1,
+                gain: 64,
+            })
+            .unwrap();
+        assert_eq!(pipeline.report(&config).buttons, 0b01);
+        // Both stepped on
+        pipeline.sample(&[3000, 1500], &config);
+        assert_eq!(pipeline.report(&config).buttons, 0b11);
+    }
+
+    #[test]
+    fn panels_fall_back_to_healthy_sensors() {
+        let (config, mut pipeline) = two_panels(Aggregation::WeightedAverage, 800);
+        // The first sensor came loose, while the second still reads a light foot
+        for idx in 0..STUCK_SAMPLES {
+            let noise = 100 + idx % 2;
+            pipeline.sample(&[0, noise, noise, noise], &config);
+            pipeline.report(&config);
+        }
+        assert_eq!(pipeline.faulty(), 0b0001);
+        pipeline.sample(&[0, 1000, 100, 100], &config);
+        assert_eq!(pipeline.report(&config).buttons, 0b01);
+    }
+
+    #[test]
+    fn filters_each_channel_as_configured() {
+        let mut config = PadConfig::new(2);
+        config.filters[1] = Filter::Median { len: 3 };
+        let mut pipeline = captured::<2>();
+
+        pipeline.sample(&[0, 0], &config);
+        pipeline.sample(&[0, 0], &config);
+        pipeline.sample(&[4095, 4095], &config);
+        assert_eq!(pipeline.values(), &[4095, 0]);
+        assert_eq!(pipeline.report(&config).buttons, 0b01);
+    }
+
+    #[test]
+    fn presses_at_a_force() {
+        let mut config = PadConfig::new(2);
+        // 10 kΩ to ground, and 3 g per µS
+        config.force[1] = Some(ForceModel {
+            divider_ohms: 10_000,
+            curve: ForceCurve::Linear { grams_per_ms: 3000 },
+        });
+        config.thresholds[1] = Thresholds {
+            press: 1000,
+            release: 800,
+        };
+        let mut pipeline = captured::<2>();
+
+        // The FSR at 10 kΩ, 300 g
+        pipeline.sample(&[2048, 2048], &config);
+        assert_eq!(pipeline.values(), &[2048, 300]);
+        assert_eq!(pipeline.report(&config).buttons, 0b01);
+        // The FSR at 1 kΩ, 3 kg
+        pipeline.sample(&[0, 3724], &config);
+        assert_eq!(pipeline.values(), &[0, 3000]);
+        assert_eq!(pipeline.report(&config).buttons, 0b10);
+    }
+
+    /// A channel in grams, idling at 300 g with a press threshold past the scale of the ADC
+    #[test]
+    fn presses_past_the_scale_of_the_adc() {
+        let mut config = PadConfig::new(1);
+        config.force[0] = Some(ForceModel {
+            divider_ohms: 10_000,
+            curve: ForceCurve::Linear { grams_per_ms: 3000 },
+        });
+        config.thresholds[0] = Thresholds {
+            press: 6000,
+            release: 5000,
+        };
+        config.compensation.temperature = 32;
+        let mut pipeline = Pipeline::<1>::new(SAMPLE_HZ, Samples::Counts);
+        for _ in 0..CAPTURE_SAMPLES {
+            pipeline.sample(&[2048], &config);
+        }
+        assert_eq!(pipeline.baselines(), Some([300]));
+        // Warming up leaves the baseline in grams alone
+        pipeline.track_temperature(250, &config);
+        pipeline.track_temperature(350, &config);
+        assert_eq!(pipeline.baselines(), Some([300]));
+
+        // The FSR at 242 Ω, about 12.4 kg
+        pipeline.sample(&[4000], &config);
+        assert_eq!(pipeline.health(), [SensorHealth::Healthy]);
+        assert_eq!(pipeline.report(&config).buttons, 1);
+    }
+
+    #[test]
+    fn holds_load_cells_pressed_past_the_scale_of_the_adc() {
+        let mut config = PadConfig::new(1);
+        config.thresholds[0] = Thresholds {
+            press: 1500,
+            release: 1000,
+        };
+        config.compensation.temperature = 32;
+        let mut pipeline = Pipeline::<1>::new(SAMPLE_HZ, Samples::Grams);
+        for _ in 0..CAPTURE_SAMPLES {
+            pipeline.sample(&[256], &config);
+        }
+        pipeline.track_temperature(250, &config);
+        pipeline.track_temperature(350, &config);
+        assert_eq!(pipeline.baselines(), Some([256]));
+
+        // A player standing on the panel for longer than a stuck sensor takes, loading the cell to
+        // full scale
+        for _ in 0..=STUCK_SAMPLES {
+            pipeline.sample(&[u16::MAX], &config);
+            assert_eq!(pipeline.report(&config).buttons, 1);
+        }
+        assert_eq!(pipeline.health(), [SensorHealth::Healthy]);
+    }
+
+    #[test]
+    fn reports_pressure_per_sensor() {
+        let mut config = PadConfig::new(8);
+        config.analog = AnalogMode::Pressure;
+        let mut pipeline = Pipeline::<8>::new(SAMPLE_HZ, Samples::Counts);
+        for _ in 0..CAPTURE_SAMPLES {
+            pipeline.sample(&[0, 0, 0, 2095, 0, 0, 0, 0], &config);
+        }
+
+        pipeline.sample(&[4095, 2048, 0, 4095, 100, 0, 0, 4095], &config);
+        let report = pipeline.report(&config);
+        // Sensors past the sixth have no axis
+        assert_eq!(report.pressure, [255, 127, 0, 255, 6, 0]);
+        assert_eq!((report.x, report.y), (0, 0));
+    }
+
+    #[test]
+    fn reports_pressure_in_grams_over_a_fixed_range() {
+        let mut config = PadConfig::new(1);
+        config.force[0] = Some(ForceModel {
+            divider_ohms: 10_000,
+            curve: ForceCurve::Linear { grams_per_ms: 3000 },
+        });
+        config.analog = AnalogMode::Pressure;
+        let mut pipeline = Pipeline::<1>::new(SAMPLE_HZ, Samples::Counts);
+        for _ in 0..CAPTURE_SAMPLES {
+            pipeline.sample(&[3900], &config);
+        }
+        // Resting well above 4095 g
+        assert_eq!(pipeline.baselines(), Some([5976]));
+
+        // 2130 g more
+        pipeline.sample(&[3950], &config);
+        assert_eq!(pipeline.report(&config).pressure[0], 132);
+        pipeline.sample(&[4000], &config);
+        assert_eq!(pipeline.report(&config).pressure[0], 255);
+    }
+
+    #[test]
+    fn reports_center_of_pressure() {
+        let mut config = PadConfig::new(4);
+        config.analog = AnalogMode::CenterOfPressure;
+        let mut pipeline = captured::<4>();
+        let [left, down, up, right] = [0, 1, 2, 3];
+
+        let mut center = |raw: [u16; 4]| {
+            pipeline.sample(&raw, &config);
+            let report = pipeline.report(&config);
+            assert_eq!(report.pressure, [0; PRESSURE_AXES]);
+            (report.x, report.y)
+        };
+        assert_eq!(center([0; 4]), (0, 0));
+        assert_eq!(center([600, 0, 0, 0]), (-127, 0));
+        assert_eq!(center([3000, 0, 0, 1000]), (-63, 0));
+        assert_eq!(center([0, 1000, 1000, 0]), (0, 0));
+        // Below the press threshold of the down panel
+        let mut raw = [0; 4];
+        raw[right] = 2000;
+        raw[down] = 400;
+        assert_eq!(center(raw), (127, 0));
+        raw[up] = 2000;
+        raw[left] = 0;
+        assert_eq!(center(raw), (63, -63));
+    }
+
+    #[test]
+    fn center_of_pressure_stays_within_positions() {
+        check(|rng| {
+            let mut config = PadConfig::new(4);
+            config.analog = AnalogMode::CenterOfPressure;
+            for position in config.positions.iter_mut() {
+                let [x, y] = rng.array(0, 255);
+                *position = Position {
+                    x: x as u8 as i8,
+                    y: y as u8 as i8,
+                };
+            }
+            let (xs, ys): (Vec<_>, Vec<_>) = config.positions.iter().map(|p| (p.x, p.y)).unzip();
+
+            let mut pipeline = captured::<4>();
+            pipeline.sample(&rng.array(0, ADC_MAX), &config);
+            let report = pipeline.report(&config);
+            if report.buttons == 0 {
+                assert_eq!((report.x, report.y), (0, 0));
+            } else {
+                assert!(
+                    *xs.iter().min().unwrap() <= report.x && report.x <= *xs.iter().max().unwrap()
+                );
+                assert!(
+                    *ys.iter().min().unwrap() <= report.y && report.y <= *ys.iter().max().unwrap()
+                );
+            }
+        });
+    }
+
+    /// Whatever came before, a value at or above `press` over the baseline is pressed and one
+    /// below `release` is not
+    #[test]
+    fn press_state_follows_thresholds() {
+        check(|rng| {
+            let press = rng.range(0, ADC_MAX);
+            let t = Thresholds {
+                press,
+                release: rng.range(0, press),
+            };
+            let mut config = PadConfig::new(4);
+            config.thresholds.fill(t);
+
+            let mut pipeline = captured::<4>();
+            for _ in 0..rng.range(1, 64) {
+                let raw = rng.array(0, ADC_MAX);
+                pipeline.sample(&raw, &config);
+                let baselines = pipeline.baselines().unwrap();
+                let buttons = pipeline.report(&config).buttons;
+                for (idx, value) in raw.into_iter().enumerate() {
+                    let above = value.saturating_sub(baselines[idx]);
+                    let pressed = buttons & (1 << idx) != 0;
+                    if above >= t.press {
+                        assert!(pressed);
+                    } else if above < t.release {
+                        assert!(!pressed);
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,171 @@
+//! When reports go out to the host, and how long they took to get there
+
+use abi::Latency;
+
+use crate::Report;
+
+/// The report waiting to be sent to the host, if the state of the pad changed since the last one
+///
+/// Reports that would repeat what the host already knows are not sent at all, so that the
+/// endpoint is free the moment something changes.
+///
+/// # Type arguments
+///
+/// * `T` - timestamp of the sample that changed the state.
+#[derive(Clone, Debug)]
+pub struct ReportQueue<T> {
+    /// Last report handed to the host, `None` until the first one after (re-)enumeration
+    sent: Option<Report>,
+    pending: Option<(Report, T)>,
+}
+
+impl<T: Copy> ReportQueue<T> {
+    pub const fn new() -> Self {
+        Self {
+            sent: None,
+            pending: None,
+        }
+    }
+
+    /// Takes in the latest report, built from a sample taken at `at`
+    ///
+    /// While a report is pending, the timestamp of the first change the host has not seen yet is
+    /// kept, so that the latency covers the whole time the host lagged behind.
+    pub fn update(&mut self, report: Report, at: T) {
+        if self.sent == Some(report) {
+            self.pending = None;
+            return;
+        }
+        let since = self.pending.map_or(at, |(_, since)| since);
+        self.pending = Some((report, since));
+    }
+
+    /// The report to send, and the timestamp of the change it carries
+    pub fn pending(&self) -> Option<(Report, T)> {
+        self.pending
+    }
+
+    /// Records that the pending report was sent, and returns the timestamp of the change it carried
+    pub fn sent(&mut self) -> Option<T> {
+        let (report, since) = self.pending.take()?;
+        self.sent = Some(report);
+        Some(since)
+    }
+
+    /// Forgets what the host knows, e.g. after it reset the pad, so that the next report is sent
+    /// whatever it holds
+    pub fn reset(&mut self) {
+        self.sent = None;
+    }
+}
+
+impl<T: Copy> Default for ReportQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Running statistics of the latency of the reports
+#[derive(Clone, Debug, Default)]
+pub struct LatencyStats {
+    summary: Latency,
+    total_us: u64,
+}
+
+impl LatencyStats {
+    pub const fn new() -> Self {
+        Self {
+            summary: Latency {
+                reports: 0,
+                min_us: 0,
+                mean_us: 0,
+                max_us: 0,
+            },
+            total_us: 0,
+        }
+    }
+
+    /// Adds the latency of one report
+    pub fn record(&mut self, us: u32) {
+        let summary = &mut self.summary;
+        summary.min_us = if summary.reports == 0 {
+            us
+        } else {
+            summary.min_us.min(us)
+        };
+        summary.max_us = summary.max_us.max(us);
+        summary.reports = summary.reports.saturating_add(1);
+        self.total_us += u64::from(us);
+        summary.mean_us = (self.total_us / u64::from(summary.reports)) as u32;
+    }
+
+    pub fn summary(&self) -> Latency {
+        self.summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(buttons: u32) -> Report {
+        Report {
+            buttons,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sends_only_changes() {
+        let mut queue = ReportQueue::new();
+        // The host knows nothing after enumeration
+        queue.update(Report::default(), 0);
+        assert_eq!(queue.sent(), Some(0));
+
+        for ms in 1..10 {
+            queue.update(Report::default(), ms);
+            assert_eq!(queue.pending(), None);
+        }
+        queue.update(pressed(1), 10);
+        assert_eq!(queue.pending(), Some((pressed(1), 10)));
+        assert_eq!(queue.sent(), Some(10));
+        assert_eq!(queue.sent(), None);
+
+        queue.reset();
+        queue.update(pressed(1), 11);
+        assert_eq!(queue.sent(), Some(11));
+    }
+
+    #[test]
+    fn keeps_first_unsent_change() {
+        let mut queue = ReportQueue::new();
+        queue.update(Report::default(), 0);
+        queue.sent();
+
+        // The host is slow to pick up the report
+        queue.update(pressed(1), 1);
+        queue.update(pressed(3), 2);
+        assert_eq!(queue.pending(), Some((pressed(3), 1)));
+        // Back to what the host already has
+        queue.update(Report::default(), 3);
+        assert_eq!(queue.pending(), None);
+    }
+
+    #[test]
+    fn summarizes_latency() {
+        let mut stats = LatencyStats::new();
+        assert_eq!(stats.summary(), Latency::default());
+        for us in [300, 100, 1100, 500] {
+            stats.record(us);
+        }
+        assert_eq!(
+            stats.summary(),
+            Latency {
+                reports: 4,
+                min_us: 100,
+                mean_us: 500,
+                max_us: 1100,
+            }
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Sample sources other than the internal ADC
+//!
+//! An external ADC, on I2C or SPI, converts one input at a time. `ExternalAdc` cycles it through
+//! its inputs and hands out a frame of every channel once the last one is converted, scaled to the
+//! counts of the internal 12-bit ADC so that thresholds carry over from one source to the other.
+//!
+//! Load cells behind HX711s are a source of their own, as all of them convert at once.
+
+pub mod ads1115;
+pub mod ads1256;
+pub mod hx711;
+
+use abi::AdcValues;
+
+use crate::ADC_MAX;
+
+/// Anything that produces frames of every sensor channel for the `Pipeline`
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels.
+pub trait SampleSource<const N: usize> {
+    type Error;
+
+    /// Returns the next frame once it is complete, and `WouldBlock` until then
+    fn read(&mut self) -> nb::Result<AdcValues<N>, Self::Error>;
+}
+
+/// An ADC converting one single-ended input at a time
+pub trait Converter {
+    type Error;
+
+    /// Number of single-ended inputs
+    const INPUTS: usize;
+
+    /// Starts converting `input`
+    fn start(&mut self, input: usize) -> Result<(), Self::Error>;
+
+    /// Returns the code of the conversion started last, and `WouldBlock` while it is running
+    fn read(&mut self) -> nb::Result<i32, Self::Error>;
+}
+
+/// The inputs of an external ADC read one after the other into frames, sensor `n` being wired to
+/// input `n`
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels, at most the inputs of the ADC.
+#[derive(Clone, Debug)]
+pub struct ExternalAdc<C, const N: usize> {
+    converter: C,
+    supply: i32,
+    input: usize,
+    /// Whether a conversion of `input` is running
+    converting: bool,
+    frame: AdcValues<N>,
+}
+
+impl<C: Converter, const N: usize> ExternalAdc<C, N> {
+    /// Reads the sensors through `converter`, where `supply` is the code of the supply of the
+    /// sensors, which reads `ADC_MAX`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ADC has fewer than `N` inputs, or `supply` is not positive.
+    pub fn new(converter: C, supply: i32) -> Self {
+        assert!(N <= C::INPUTS, "the ADC has fewer inputs than sensors");
+        assert!(supply > 0, "the sensors need a supply");
+        Self {
+            converter,
+            supply,
+            input: 0,
+            converting: false,
+            frame: [0; N],
+        }
+    }
+}
+
+impl<C: Converter, const N: usize> SampleSource<N> for ExternalAdc<C, N> {
+    type Error = C::Error;
+
+    /// Collects whatever conversions have finished, starting the next as soon as one does
+    ///
+    /// After an error the conversion of the current input starts over.
+    fn read(&mut self) -> nb::Result<AdcValues<N>, C::Error> {
+        loop {
+            if !self.converting {
+                self.converter.start(self.input)?;
+                self.converting = true;
+            }
+            let code = match self.converter.read() {
+                Err(nb::Error::Other(e)) => {
+                    self.converting = false;
+                    return Err(nb::Error::Other(e));
+                }
+                result => result?,
+            };
+            self.converting = false;
+            self.frame[self.input] = to_counts(code, self.supply);
+            self.input = (self.input + 1) % N;
+            if self.input == 0 {
+                // Get the next frame going before handing out this one
+                self.converter.start(0)?;
+                self.converting = true;
+                return Ok(self.frame);
+            }
+        }
+    }
+}
+
+/// Scales a `code` of an external ADC to counts of the internal one, where `supply` reads
+/// `ADC_MAX`
+///
+/// Codes below 0 or above `supply` are clamped to the range of the internal ADC.
+pub fn to_counts(code: i32, supply: i32) -> u16 {
+    let code = i64::from(code.clamp(0, supply));
+    let supply = i64::from(supply);
+    // Rounded to nearest
+    ((code * i64::from(ADC_MAX) + supply / 2) / supply) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A converter whose conversions take `busy` reads, and read the input times 1000
+    #[derive(Default)]
+    struct FakeConverter {
+        busy: usize,
+        remaining: usize,
+        input: Option<usize>,
+        started: Vec<usize>,
+        fail: bool,
+    }
+
+    impl Converter for FakeConverter {
+        type Error = ();
+        const INPUTS: usize = 4;
+
+        fn start(&mut self, input: usize) -> Result<(), ()> {
+            self.started.push(input);
+            self.input = Some(input);
+            self.remaining = self.busy;
+            Ok(())
+        }
+
+        fn read(&mut self) -> nb::Result<i32, ()> {
+            if self.fail {
+                return Err(nb::Error::Other(()));
+            }
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                return Err(nb::Error::WouldBlock);
+            }
+            let input = self.input.take().expect("no conversion started");
+            Ok(input as i32 * 1000)
+        }
+    }
+
+    #[test]
+    fn reads_inputs_into_frames() {
+        let converter = FakeConverter {
+            busy: 1,
+            ..Default::default()
+        };
+        let mut adc = ExternalAdc::<_, 3>::new(converter, 4095);
+        for _ in 0..3 {
+            assert_eq!(adc.read(), Err(nb::Error::WouldBlock));
+        }
+        assert_eq!(adc.read(), Ok([0, 1000, 2000]));
+        assert_eq!(adc.converter.started, [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn reads_a_frame_at_once_if_the_converter_keeps_up() {
+        let mut adc = ExternalAdc::<_, 2>::new(FakeConverter::default(), 4095);
+        assert_eq!(adc.read(), Ok([0, 1000]));
+        assert_eq!(adc.read(), Ok([0, 1000]));
+    }
+
+    #[test]
+    fn starts_over_after_an_error() {
+        let mut adc = ExternalAdc::<_, 2>::new(FakeConverter::default(), 4095);
+        adc.converter.fail = true;
+        assert_eq!(adc.read(), Err(nb::Error::Other(())));
+        adc.converter.fail = false;
+        assert_eq!(adc.read(), Ok([0, 1000]));
+        assert_eq!(adc.converter.started, [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn scales_codes_to_internal_counts() {
+        assert_eq!(to_counts(26_400, 26_400), ADC_MAX);
+        assert_eq!(to_counts(13_200, 26_400), 2048);
+        assert_eq!(to_counts(-5, 26_400), 0);
+        assert_eq!(to_counts(32_767, 26_400), ADC_MAX);
+        assert_eq!(to_counts(0x7F_FFFF, 0x7F_FFFF), ADC_MAX);
+    }
+}
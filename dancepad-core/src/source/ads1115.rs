@@ -0,0 +1,110 @@
+//! Texas Instruments ADS1115, a 16-bit ADC with four inputs on I2C
+//!
+//! Conversions are single-shot at 860 samples per second, the fastest the ADC goes, so that a
+//! frame of four sensors takes about 5 ms.
+
+use embedded_hal::i2c::I2c;
+
+use super::Converter;
+
+/// Address of an ADS1115 with its ADDR pin tied to ground
+pub const DEFAULT_ADDRESS: u8 = 0x48;
+
+/// Register pointers
+const CONVERSION: u8 = 0x00;
+const CONFIG: u8 = 0x01;
+
+/// Starts a conversion when written, reads 0 while one is running
+const OS: u16 = 1 << 15;
+/// Input 0 against ground, the other inputs follow
+const MUX_AIN0_GND: u16 = 0b100 << 12;
+/// Full scale of ±4.096 V, the smallest that takes in a 3.3 V supply
+const PGA_4096: u16 = 0b001 << 9;
+const MODE_SINGLE_SHOT: u16 = 1 << 8;
+const DR_860: u16 = 0b111 << 5;
+const COMP_DISABLE: u16 = 0b11;
+
+/// Code of an input at `millivolts`
+pub const fn code(millivolts: u16) -> i32 {
+    // 32768 codes over 4.096 V
+    millivolts as i32 * 8
+}
+
+pub struct Ads1115<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Ads1115<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+}
+
+impl<I2C: I2c> Converter for Ads1115<I2C> {
+    type Error = I2C::Error;
+    const INPUTS: usize = 4;
+
+    fn start(&mut self, input: usize) -> Result<(), I2C::Error> {
+        let config = OS
+            | MUX_AIN0_GND
+            | (input as u16) << 12
+            | PGA_4096
+            | MODE_SINGLE_SHOT
+            | DR_860
+            | COMP_DISABLE;
+        let [high, low] = config.to_be_bytes();
+        self.i2c.write(self.address, &[CONFIG, high, low])
+    }
+
+    fn read(&mut self) -> nb::Result<i32, I2C::Error> {
+        let mut config = [0; 2];
+        self.i2c.write_read(self.address, &[CONFIG], &mut config)?;
+        if u16::from_be_bytes(config) & OS == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        let mut code = [0; 2];
+        self.i2c
+            .write_read(self.address, &[CONVERSION], &mut code)?;
+        Ok(i16::from_be_bytes(code).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+
+    use super::*;
+
+    const ADDRESS: u8 = DEFAULT_ADDRESS;
+
+    #[test]
+    fn starts_single_shot_conversions() {
+        let mut i2c = Mock::new(&[
+            Transaction::write(ADDRESS, vec![CONFIG, 0xC3, 0xE3]),
+            Transaction::write(ADDRESS, vec![CONFIG, 0xF3, 0xE3]),
+        ]);
+        let mut adc = Ads1115::new(&mut i2c, ADDRESS);
+        adc.start(0).unwrap();
+        adc.start(3).unwrap();
+        i2c.done();
+    }
+
+    #[test]
+    fn reads_finished_conversions() {
+        let mut i2c = Mock::new(&[
+            // Still converting
+            Transaction::write_read(ADDRESS, vec![CONFIG], vec![0x43, 0xE3]),
+            Transaction::write_read(ADDRESS, vec![CONFIG], vec![0xC3, 0xE3]),
+            Transaction::write_read(ADDRESS, vec![CONVERSION], vec![0x67, 0x20]),
+            // Slightly below ground
+            Transaction::write_read(ADDRESS, vec![CONFIG], vec![0xC3, 0xE3]),
+            Transaction::write_read(ADDRESS, vec![CONVERSION], vec![0xFF, 0xFE]),
+        ]);
+        let mut adc = Ads1115::new(&mut i2c, ADDRESS);
+        assert_eq!(adc.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(adc.read(), Ok(code(3300)));
+        assert_eq!(adc.read(), Ok(-2));
+        i2c.done();
+    }
+}
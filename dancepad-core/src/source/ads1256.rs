@@ -0,0 +1,168 @@
+//! Texas Instruments ADS1256, a 24-bit ADC with eight inputs on SPI
+//!
+//! The inputs are read against AINCOM, which goes to the ground of the sensors. The SPI bus runs
+//! in mode 1, at no more than a quarter of the 7.68 MHz master clock, and DRDY goes low once a
+//! conversion is ready.
+
+use embedded_hal::{
+    digital::InputPin,
+    spi::{Operation, SpiDevice},
+};
+
+use super::Converter;
+
+/// Commands
+const WAKEUP: u8 = 0x00;
+const RDATA: u8 = 0x01;
+const WREG: u8 = 0x50;
+const SELFCAL: u8 = 0xF0;
+const SYNC: u8 = 0xFC;
+
+/// Registers
+const MUX: u8 = 0x01;
+const DRATE: u8 = 0x03;
+
+/// Negative input of the mux
+const AINCOM: u8 = 0x08;
+/// 7500 samples per second, for a frame of eight sensors in about 2 ms including the settling
+/// after every switch of the mux
+const DRATE_7500: u8 = 0xD0;
+
+/// Time from SYNC to WAKEUP, 24 master clock periods
+const SYNC_NS: u32 = 3_200;
+/// Time from RDATA to the data, 50 master clock periods
+const DATA_NS: u32 = 6_600;
+
+/// Code of an input at `millivolts`
+pub const fn code(millivolts: u16) -> i32 {
+    // 2^23 codes over 5 V, twice the 2.5 V reference
+    (millivolts as i64 * 0x80_0000 / 5000) as i32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<S, P> {
+    Spi(S),
+    /// Reading DRDY failed
+    Pin(P),
+}
+
+pub struct Ads1256<SPI, DRDY> {
+    spi: SPI,
+    drdy: DRDY,
+}
+
+impl<SPI: SpiDevice, DRDY: InputPin> Ads1256<SPI, DRDY> {
+    pub fn new(spi: SPI, drdy: DRDY) -> Self {
+        Self { spi, drdy }
+    }
+
+    /// Sets the data rate and starts a self calibration, which the first conversion waits for
+    pub fn configure(&mut self) -> Result<(), Error<SPI::Error, DRDY::Error>> {
+        self.spi
+            .write(&[WREG | DRATE, 0, DRATE_7500])
+            .and_then(|_| self.spi.write(&[SELFCAL]))
+            .map_err(Error::Spi)
+    }
+}
+
+impl<SPI: SpiDevice, DRDY: InputPin> Converter for Ads1256<SPI, DRDY> {
+    type Error = Error<SPI::Error, DRDY::Error>;
+    const INPUTS: usize = 8;
+
+    fn start(&mut self, input: usize) -> Result<(), Self::Error> {
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[WREG | MUX, 0, (input as u8) << 4 | AINCOM]),
+                Operation::Write(&[SYNC]),
+                Operation::DelayNs(SYNC_NS),
+                Operation::Write(&[WAKEUP]),
+            ])
+            .map_err(Error::Spi)
+    }
+
+    fn read(&mut self) -> nb::Result<i32, Self::Error> {
+        if self.drdy.is_high().map_err(Error::Pin)? {
+            return Err(nb::Error::WouldBlock);
+        }
+        let mut data = [0; 3];
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[RDATA]),
+                Operation::DelayNs(DATA_NS),
+                Operation::Read(&mut data),
+            ])
+            .map_err(Error::Spi)?;
+        let [high, mid, low] = data;
+        // Sign extended from 24 bits
+        Ok(i32::from_be_bytes([high, mid, low, 0]) >> 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::{
+        digital::{Mock as PinMock, State, Transaction as PinTransaction},
+        spi::{Mock as SpiMock, Transaction},
+    };
+
+    use super::*;
+
+    #[test]
+    fn calibrates_at_7500_samples_per_second() {
+        let mut spi = SpiMock::new(&[
+            Transaction::transaction_start(),
+            Transaction::write_vec(vec![0x53, 0x00, 0xD0]),
+            Transaction::transaction_end(),
+            Transaction::transaction_start(),
+            Transaction::write_vec(vec![0xF0]),
+            Transaction::transaction_end(),
+        ]);
+        let mut drdy = PinMock::new(&[]);
+        Ads1256::new(&mut spi, &mut drdy).configure().unwrap();
+        spi.done();
+        drdy.done();
+    }
+
+    #[test]
+    fn switches_the_mux_and_restarts_conversion() {
+        let mut spi = SpiMock::new(&[
+            Transaction::transaction_start(),
+            Transaction::write_vec(vec![0x51, 0x00, 0x58]),
+            Transaction::write_vec(vec![0xFC]),
+            Transaction::delay(SYNC_NS),
+            Transaction::write_vec(vec![0x00]),
+            Transaction::transaction_end(),
+        ]);
+        let mut drdy = PinMock::new(&[]);
+        Ads1256::new(&mut spi, &mut drdy).start(5).unwrap();
+        spi.done();
+        drdy.done();
+    }
+
+    #[test]
+    fn reads_data_once_ready() {
+        let read = |data: Vec<u8>| {
+            [
+                Transaction::transaction_start(),
+                Transaction::write_vec(vec![0x01]),
+                Transaction::delay(DATA_NS),
+                Transaction::read_vec(data),
+                Transaction::transaction_end(),
+            ]
+        };
+        let mut spi =
+            SpiMock::new(&[read(vec![0x54, 0x7A, 0xE1]), read(vec![0xFF, 0xFF, 0xF0])].concat());
+        let mut drdy = PinMock::new(&[
+            PinTransaction::get(State::High),
+            PinTransaction::get(State::Low),
+            PinTransaction::get(State::Low),
+        ]);
+        let mut adc = Ads1256::new(&mut spi, &mut drdy);
+        assert_eq!(adc.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(adc.read(), Ok(code(3300)));
+        // Slightly below AINCOM
+        assert_eq!(adc.read(), Ok(-16));
+        spi.done();
+        drdy.done();
+    }
+}
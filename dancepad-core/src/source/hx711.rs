@@ -0,0 +1,276 @@
+//! Avia Semiconductor HX711, a 24-bit ADC for load cell bridges
+//!
+//! Every load cell has an HX711 of its own. They share the clock line, so that one run of clock
+//! pulses reads all of them at once, each on a data line of its own. The frames come out in grams
+//! above `ZERO` rather than in volts, so that thresholds are in grams too. Tie RATE high for 80
+//! samples per second, as the 10 of RATE low lag a step by up to 100 ms.
+//!
+//! A cell reads full scale from about 65 kg on, past the weight of any player. Its samples are
+//! `Samples::Grams`, which the health checks do not take for stuck at full scale. Finer steps would
+//! have the noise of an unloaded cell round away, and the health checks take it for flat.
+//!
+//! The cells are tared with their first samples, and again on `tare`. Any drift in between is left
+//! to the baselines of the signal path, as for every other sensor.
+
+use abi::AdcValues;
+use embedded_hal::{
+    delay::DelayNs,
+    digital::{InputPin, OutputPin},
+};
+
+use super::SampleSource;
+
+/// Count of an unloaded cell, leaving room for it to drift below its tare without reading 0
+pub const ZERO: u16 = 256;
+
+/// Samples averaged into the tare of every cell
+pub const TARE_SAMPLES: u16 = 16;
+
+/// Data bits of a conversion
+const BITS: u32 = 24;
+
+/// Half the period of the clock. A clock high for more than 60 µs powers the HX711 down.
+const HALF_CLOCK_NS: u32 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<C, D> {
+    /// Driving the clock line failed
+    Clock(C),
+    /// Reading a data line failed
+    Data(D),
+}
+
+/// Load cells behind HX711s on a shared clock line, read through channel A at a gain of 128
+///
+/// # Type arguments
+///
+/// * `N` - number of load cells, one per sensor channel.
+pub struct Hx711<SCK, DOUT, D, const N: usize> {
+    sck: SCK,
+    dout: [DOUT; N],
+    delay: D,
+    counts_per_kg: [i32; N],
+    tare: [i32; N],
+    /// Samples and their sums while taring
+    taring: Option<(u16, [i64; N])>,
+}
+
+impl<SCK, DOUT, D, const N: usize> Hx711<SCK, DOUT, D, N>
+where
+    SCK: OutputPin,
+    DOUT: InputPin,
+    D: DelayNs,
+{
+    /// Reads cell `n` through `dout[n]`, at a scale of `counts_per_kg[n]`, and tares all cells
+    /// with their first samples
+    ///
+    /// # Panics
+    ///
+    /// Panics if a scale is 0.
+    pub fn new(sck: SCK, dout: [DOUT; N], delay: D, counts_per_kg: [i32; N]) -> Self {
+        assert!(
+            counts_per_kg.iter().all(|scale| *scale != 0),
+            "every cell needs a scale"
+        );
+        Self {
+            sck,
+            dout,
+            delay,
+            counts_per_kg,
+            tare: [0; N],
+            taring: Some((0, [0; N])),
+        }
+    }
+
+    /// Takes the next `TARE_SAMPLES` samples of every cell as its weight when unloaded
+    pub fn tare(&mut self) {
+        self.taring = Some((0, [0; N]));
+    }
+
+    /// Weighs cell `n` at a scale of `counts_per_kg[n]` from the next sample on
+    ///
+    /// # Panics
+    ///
+    /// Panics if a scale is 0.
+    pub fn set_scales(&mut self, counts_per_kg: [i32; N]) {
+        assert!(
+            counts_per_kg.iter().all(|scale| *scale != 0),
+            "every cell needs a scale"
+        );
+        self.counts_per_kg = counts_per_kg;
+    }
+
+    /// Raw code of every cell, once all of them have a conversion ready
+    fn read_raw(&mut self) -> nb::Result<[i32; N], Error<SCK::Error, DOUT::Error>> {
+        for dout in &mut self.dout {
+            // DOUT stays high until a conversion is ready
+            if dout.is_high().map_err(Error::Data)? {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+        let mut raw = [0i32; N];
+        for _ in 0..BITS {
+            self.pulse()?;
+            for (value, dout) in raw.iter_mut().zip(&mut self.dout) {
+                *value = *value << 1 | i32::from(dout.is_high().map_err(Error::Data)?);
+            }
+        }
+        // One more pulse picks channel A at a gain of 128 for the next conversion
+        self.pulse()?;
+        // Sign extended from 24 bits
+        Ok(raw.map(|value| value << (32 - BITS) >> (32 - BITS)))
+    }
+
+    /// Clocks out one bit, which is on the data lines afterwards
+    fn pulse(&mut self) -> Result<(), Error<SCK::Error, DOUT::Error>> {
+        self.sck.set_high().map_err(Error::Clock)?;
+        self.delay.delay_ns(HALF_CLOCK_NS);
+        self.sck.set_low().map_err(Error::Clock)?;
+        self.delay.delay_ns(HALF_CLOCK_NS);
+        Ok(())
+    }
+}
+
+impl<SCK, DOUT, D, const N: usize> SampleSource<N> for Hx711<SCK, DOUT, D, N>
+where
+    SCK: OutputPin,
+    DOUT: InputPin,
+    D: DelayNs,
+{
+    type Error = Error<SCK::Error, DOUT::Error>;
+
+    /// Returns the weight on every cell above its tare, and `WouldBlock` until the cells are tared
+    fn read(&mut self) -> nb::Result<AdcValues<N>, Self::Error> {
+        let raw = self.read_raw()?;
+        if let Some((samples, sums)) = &mut self.taring {
+            for (sum, value) in sums.iter_mut().zip(raw) {
+                *sum += i64::from(value);
+            }
+            *samples += 1;
+            if *samples < TARE_SAMPLES {
+                return Err(nb::Error::WouldBlock);
+            }
+            let count = i64::from(*samples);
+            self.tare = sums.map(|sum| (sum / count) as i32);
+            self.taring = None;
+        }
+        Ok(core::array::from_fn(|idx| {
+            let above = i64::from(raw[idx]) - i64::from(self.tare[idx]);
+            let grams = above * 1000 / i64::from(self.counts_per_kg[idx]);
+            to_counts(grams.clamp(i32::MIN.into(), i32::MAX.into()) as i32)
+        }))
+    }
+}
+
+/// Count of a cell carrying `grams` above its tare
+pub fn to_counts(grams: i32) -> u16 {
+    grams.saturating_add(ZERO.into()).clamp(0, u16::MAX.into()) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::{
+        delay::NoopDelay,
+        digital::{Mock, State, Transaction},
+    };
+
+    use super::*;
+
+    /// The clock of one conversion
+    fn clock() -> Vec<Transaction> {
+        (0..=BITS)
+            .flat_map(|_| [Transaction::set(State::High), Transaction::set(State::Low)])
+            .collect()
+    }
+
+    /// A data line going low with `code` ready, and shifting it out
+    fn data(code: i32) -> Vec<Transaction> {
+        let level = |high| Transaction::get(if high { State::High } else { State::Low });
+        [level(false)]
+            .into_iter()
+            .chain((0..BITS).rev().map(|bit| level(code & 1 << bit != 0)))
+            .collect()
+    }
+
+    #[test]
+    fn reads_all_cells_at_once() {
+        let mut sck = Mock::new(&clock());
+        let mut dout = [Mock::new(&data(0x12_3456)), Mock::new(&data(-2))];
+        let mut hx711 = Hx711::new(sck.clone(), dout.clone(), NoopDelay, [1, 1]);
+        assert_eq!(hx711.read_raw(), Ok([0x12_3456, -2]));
+        sck.done();
+        dout.iter_mut().for_each(Mock::done);
+    }
+
+    #[test]
+    fn waits_for_every_cell() {
+        let mut sck = Mock::new(&[]);
+        let mut dout = [
+            Mock::new(&[Transaction::get(State::Low)]),
+            Mock::new(&[Transaction::get(State::High)]),
+        ];
+        let mut hx711 = Hx711::new(sck.clone(), dout.clone(), NoopDelay, [1, 1]);
+        assert_eq!(hx711.read_raw(), Err(nb::Error::WouldBlock));
+        sck.done();
+        dout.iter_mut().for_each(Mock::done);
+    }
+
+    #[test]
+    fn weighs_above_the_tare() {
+        let conversions = TARE_SAMPLES as usize + 1;
+        let mut sck = Mock::new(&(0..conversions).flat_map(|_| clock()).collect::<Vec<_>>());
+        // Idling around 50000, then carrying 2 kg at 20000 counts per kg
+        let codes = (0..TARE_SAMPLES as i32)
+            .map(|idx| 50_000 + idx % 2 * 10)
+            .chain([90_005]);
+        let mut dout = Mock::new(&codes.flat_map(data).collect::<Vec<_>>());
+        let mut hx711 = Hx711::new(sck.clone(), [dout.clone()], NoopDelay, [20_000]);
+        for _ in 1..TARE_SAMPLES {
+            assert_eq!(hx711.read(), Err(nb::Error::WouldBlock));
+        }
+        // The last sample of the tare is weighed against it
+        assert_eq!(hx711.read(), Ok([ZERO]));
+        assert_eq!(hx711.tare, [50_005]);
+        assert_eq!(hx711.read(), Ok([ZERO + 2000]));
+        sck.done();
+        dout.done();
+    }
+
+    #[test]
+    fn tares_again_at_a_new_scale() {
+        let conversions = 2 * TARE_SAMPLES as usize + 1;
+        let mut sck = Mock::new(&(0..conversions).flat_map(|_| clock()).collect::<Vec<_>>());
+        // Idling at 50000, then at 60000 after the cell was moved, then carrying 1 kg at 10000
+        // counts per kg
+        let codes = (0..TARE_SAMPLES)
+            .map(|_| 50_000)
+            .chain((0..TARE_SAMPLES).map(|_| 60_000))
+            .chain([70_000]);
+        let mut dout = Mock::new(&codes.flat_map(data).collect::<Vec<_>>());
+        let mut hx711 = Hx711::new(sck.clone(), [dout.clone()], NoopDelay, [20_000]);
+        for _ in 1..TARE_SAMPLES {
+            assert_eq!(hx711.read(), Err(nb::Error::WouldBlock));
+        }
+        assert_eq!(hx711.read(), Ok([ZERO]));
+        hx711.tare();
+        hx711.set_scales([10_000]);
+        for _ in 1..TARE_SAMPLES {
+            assert_eq!(hx711.read(), Err(nb::Error::WouldBlock));
+        }
+        assert_eq!(hx711.read(), Ok([ZERO]));
+        assert_eq!(hx711.tare, [60_000]);
+        assert_eq!(hx711.read(), Ok([ZERO + 1000]));
+        sck.done();
+        dout.done();
+    }
+
+    #[test]
+    fn converts_grams_to_counts() {
+        assert_eq!(to_counts(0), ZERO);
+        assert_eq!(to_counts(1234), ZERO + 1234);
+        assert_eq!(to_counts(-1000), 0);
+        // A player of 80 kg, well past the 12-bit ADC
+        assert_eq!(to_counts(80_000), u16::MAX);
+        assert_eq!(to_counts(i32::MAX), u16::MAX);
+    }
+}
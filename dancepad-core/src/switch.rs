@@ -0,0 +1,211 @@
+//! Digital switches next to the sensors, such as tape switches, microswitches and arcade buttons
+//!
+//! Debouncing is eager: a switch follows its pin at once, then ignores it for the debounce time of
+//! the switch. A press costs no latency this way, unlike waiting for the pin to settle first.
+
+use abi::Switch;
+
+/// Press state of up to `N` switches
+///
+/// # Type arguments
+///
+/// * `N` - number of switches.
+#[derive(Clone, Debug)]
+pub struct Switches<const N: usize> {
+    switches: [State; N],
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct State {
+    pressed: bool,
+    /// Time of the last change, in ms, until the debounce time after it is over
+    changed: Option<u32>,
+}
+
+impl<const N: usize> Switches<N> {
+    pub const fn new() -> Self {
+        Self {
+            switches: [State {
+                pressed: false,
+                changed: None,
+            }; N],
+        }
+    }
+
+    /// Takes in the level of every pin at `now_ms`, bit `n` set if the pin of switch `n` is high,
+    /// and returns the switches pressed, bit `n` set if switch `n` is
+    ///
+    /// Switches without an entry in `config` are never pressed. `now_ms` may wrap around from
+    /// `u32::MAX` to 0, as a `Millis` does. A clock that jumps back anywhere else restarts the
+    /// debounce time of switches that changed before the jump.
+    pub fn update(&mut self, high: u32, now_ms: u32, config: &[Switch]) -> u32 {
+        for (idx, state) in self.switches.iter_mut().enumerate() {
+            let Some(switch) = config.get(idx) else {
+                *state = State::default();
+                continue;
+            };
+            let active = (high & 1 << idx != 0) != switch.active_low;
+            let settled = match state.changed {
+                None => true,
+                // Later than `now_ms` rather than more than 24 days before it
+                Some(changed) if now_ms.wrapping_sub(changed) > i32::MAX as u32 => {
+                    state.changed = Some(now_ms);
+                    false
+                }
+                Some(changed) => now_ms.wrapping_sub(changed) >= u32::from(switch.debounce_ms),
+            };
+            if active != state.pressed && settled {
+                state.pressed = active;
+                state.changed = Some(now_ms);
+            } else if settled {
+                // Nothing to compare against once settled, so that a clock far ahead is not taken
+                // for one that jumped back
+                state.changed = None;
+            }
+        }
+        self.pressed()
+    }
+
+    /// Bit `n` set if switch `n` is pressed
+    pub fn pressed(&self) -> u32 {
+        self.switches
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.pressed)
+            .fold(0, |mask, (idx, _)| mask | 1 << idx)
+    }
+}
+
+/// Milliseconds since the first `update`, running on past the wrap around of a faster tick counter
+///
+/// # Type arguments
+///
+/// * `HZ` - rate of the ticks, a multiple of 1 kHz.
+#[derive(Clone, Debug)]
+pub struct Millis<const HZ: u32> {
+    last: Option<u32>,
+    /// Ticks since the last whole millisecond
+    ticks: u32,
+    ms: u32,
+}
+
+impl<const HZ: u32> Millis<HZ> {
+    const TICKS_PER_MS: u32 = {
+        assert!(
+            HZ >= 1000 && HZ % 1000 == 0,
+            "ticks must add up to whole milliseconds"
+        );
+        HZ / 1000
+    };
+
+    pub const fn new() -> Self {
+        Self {
+            last: None,
+            ticks: 0,
+            ms: 0,
+        }
+    }
+
+    /// Takes in the tick counter, which may wrap around, and returns the milliseconds so far
+    ///
+    /// The counter has to be taken in at least once per wrap around, or the ticks in between are
+    /// lost.
+    pub fn update(&mut self, ticks: u32) -> u32 {
+        if let Some(last) = self.last {
+            let elapsed = self.ticks + ticks.wrapping_sub(last) % Self::TICKS_PER_MS;
+            let whole = ticks.wrapping_sub(last) / Self::TICKS_PER_MS;
+            self.ms = self.ms.wrapping_add(whole + elapsed / Self::TICKS_PER_MS);
+            self.ticks = elapsed % Self::TICKS_PER_MS;
+        }
+        self.last = Some(ticks);
+        self.ms
+    }
+}
+
+impl<const HZ: u32> Default for Millis<HZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Default for Switches<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use abi::Pull;
+
+    use super::*;
+
+    const SWITCHES: [Switch; 2] = [
+        Switch::DEFAULT,
+        Switch {
+            pull: Pull::Down,
+            active_low: false,
+            debounce_ms: 0,
+        },
+    ];
+
+    #[test]
+    fn follows_polarity() {
+        let mut switches = Switches::<2>::new();
+        assert_eq!(switches.update(0b11, 0, &SWITCHES), 0b10);
+        assert_eq!(switches.update(0b00, 10, &SWITCHES), 0b01);
+    }
+
+    #[test]
+    fn presses_at_once_and_ignores_bounce() {
+        let mut switches = Switches::<1>::new();
+        // Pulled up and idle
+        assert_eq!(switches.update(1, 100, &SWITCHES), 0);
+        assert_eq!(switches.update(0, 101, &SWITCHES), 1);
+        // Bouncing for a while
+        for now in 102..106 {
+            assert_eq!(switches.update(now & 1, now, &SWITCHES), 1);
+        }
+        assert_eq!(switches.update(1, 106, &SWITCHES), 0);
+        assert_eq!(switches.update(1, 200, &SWITCHES), 0);
+        // Pressed again, through the wrap around of the clock
+        assert_eq!(switches.update(0, u32::MAX, &SWITCHES), 1);
+        assert_eq!(switches.update(1, 3, &SWITCHES), 1);
+        assert_eq!(switches.update(1, 4, &SWITCHES), 0);
+    }
+
+    #[test]
+    fn ignores_bounce_across_a_clock_jumping_back() {
+        let mut switches = Switches::<1>::new();
+        assert_eq!(switches.update(0, 51_128, &SWITCHES), 1);
+        // A clock wrapping around early, as an 84 MHz tick counter in ms does after 51.1 s
+        assert_eq!(switches.update(1, 51_130, &SWITCHES), 1);
+        assert_eq!(switches.update(1, 0, &SWITCHES), 1);
+        assert_eq!(switches.update(1, 4, &SWITCHES), 1);
+        assert_eq!(switches.update(1, 5, &SWITCHES), 0);
+    }
+
+    #[test]
+    fn counts_milliseconds_past_the_wrap_around_of_ticks() {
+        let mut millis = Millis::<84_000_000>::new();
+        let mut ticks = 0u32;
+        assert_eq!(millis.update(ticks), 0);
+        // 2 minutes in steps of 1.5 ms, through two wrap arounds of the ticks
+        for _ in 0..80_000 {
+            ticks = ticks.wrapping_add(126_000);
+            millis.update(ticks);
+        }
+        assert_eq!(millis.update(ticks), 120_000);
+        ticks = ticks.wrapping_add(83_999);
+        assert_eq!(millis.update(ticks), 120_000);
+        ticks = ticks.wrapping_add(1);
+        assert_eq!(millis.update(ticks), 120_001);
+    }
+
+    #[test]
+    fn ignores_unconfigured_switches() {
+        let mut switches = Switches::<2>::new();
+        assert_eq!(switches.update(0b00, 0, &SWITCHES[..1]), 0b01);
+        assert_eq!(switches.update(0b11, 10, &[]), 0);
+    }
+}
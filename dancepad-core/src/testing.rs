@@ -0,0 +1,52 @@
+//! Randomized checks of invariants
+//!
+//! Property-testing crates pull `std` and `alloc` features into dependencies that the firmware
+//! shares, which feature unification then forces onto the `thumbv7em-none-eabihf` build. A small
+//! seeded generator covers the same ground without that.
+
+/// Number of random cases each property is checked against
+pub const CASES: usize = 256;
+
+/// Xorshift generator, seeded so that failures reproduce
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// A value in `lo..=hi`
+    pub fn range(&mut self, lo: u16, hi: u16) -> u16 {
+        let span = u32::from(hi - lo) + 1;
+        lo + (self.next_u32() % span) as u16
+    }
+
+    /// An array with every element in `lo..=hi`
+    pub fn array<const N: usize>(&mut self, lo: u16, hi: u16) -> [u16; N] {
+        core::array::from_fn(|_| self.range(lo, hi))
+    }
+}
+
+/// Runs `property` on `CASES` generators with distinct seeds
+///
+/// Panics from `property` are reported along with the seed of the failing case.
+pub fn check(property: impl Fn(&mut Rng)) {
+    for seed in 1..=CASES as u32 {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            property(&mut Rng::new(seed))
+        }));
+        if let Err(e) = result {
+            eprintln!("property failed with seed {seed}");
+            std::panic::resume_unwind(e);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Press detection from sensor values
+
+use abi::{AdcValues, RapidTrigger, Thresholds};
+
+/// Tracks the press state of every channel across samples
+///
+/// # Type arguments
+///
+/// * `N` - number of sensor channels.
+#[derive(Clone, Debug)]
+pub struct Trigger<const N: usize> {
+    channels: [State; N],
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct State {
+    pressed: bool,
+    /// Released by a rapid trigger fall, so that only a rise presses again
+    rapid: bool,
+    /// Peak of the value while pressed, trough while released
+    extreme: u16,
+}
+
+impl<const N: usize> Trigger<N> {
+    pub const fn new() -> Self {
+        Self {
+            channels: [State {
+                pressed: false,
+                rapid: false,
+                extreme: 0,
+            }; N],
+        }
+    }
+
+    /// Updates the press state with a new sample of every channel
+    ///
+    /// Channels without an entry in `thresholds` are never pressed, and those without an entry in
+    /// `rapid` have none.
+    ///
+    /// Returns the press state as a bit mask, with bit `n` set if channel `n` is pressed.
+    pub fn update(
+        &mut self,
+        vals: &AdcValues<N>,
+        thresholds: &[Thresholds],
+        rapid: &[Option<RapidTrigger>],
+    ) -> u32 {
+        for (idx, state) in self.channels.iter_mut().enumerate() {
+            let Some(t) = thresholds.get(idx) else {
+                *state = State::default();
+                continue;
+            };
+            let rapid = rapid.get(idx).copied().flatten();
+            let val = vals[idx];
+            *state = if val < t.release {
+                // Fully released, whether a rapid trigger was on or not
+                State {
+                    pressed: false,
+                    rapid: false,
+                    extreme: val,
+                }
+            } else if state.pressed {
+                let peak = state.extreme.max(val);
+                match rapid {
+                    Some(r) if val <= peak.saturating_sub(r.fall) => State {
+                        pressed: false,
+                        rapid: true,
+                        extreme: val,
+                    },
+                    _ => State {
+                        extreme: peak,
+                        ..*state
+                    },
+                }
+            } else {
+                let trough = state.extreme.min(val);
+                let pressed = match rapid {
+                    Some(r) if state.rapid => val >= trough.saturating_add(r.rise),
+                    _ => val >= t.press,
+                };
+                State {
+                    pressed,
+                    rapid: state.rapid && !pressed,
+                    extreme: if pressed { val } else { trough },
+                }
+            };
+        }
+        self.buttons()
+    }
+
+    /// The press state as a bit mask, with bit `n` set if channel `n` is pressed
+    pub fn buttons(&self) -> u32 {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.pressed)
+            .fold(0, |buttons, (idx, _)| buttons | 0b1 << idx)
+    }
+}
+
+impl<const N: usize> Default for Trigger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: Thresholds = Thresholds {
+        press: 500,
+        release: 400,
+    };
+
+    const RAPID: RapidTrigger = RapidTrigger {
+        rise: 100,
+        fall: 150,
+    };
+
+    /// Feeds `trace` through a fresh `Trigger` and collects the resulting bit masks
+    fn run<const N: usize>(trace: &[AdcValues<N>], thresholds: &[Thresholds]) -> Vec<u32> {
+        run_rapid(trace, thresholds, &[])
+    }
+
+    /// Like `run`, with a rapid trigger on the channels with an entry in `rapid`
+    fn run_rapid<const N: usize>(
+        trace: &[AdcValues<N>],
+        thresholds: &[Thresholds],
+        rapid: &[Option<RapidTrigger>],
+    ) -> Vec<u32> {
+        let mut trigger = Trigger::<N>::new();
+        trace
+            .iter()
+            .map(|vals| trigger.update(vals, thresholds, rapid))
+            .collect()
+    }
+
+    #[test]
+    fn presses_at_press_level() {
+        assert_eq!(run(&[[499], [500], [501]], &[T]), [0, 1, 1]);
+    }
+
+    #[test]
+    fn holds_between_release_and_press() {
+        // A foot resting around the press level must not chatter
+        let trace = [450, 510, 490, 505, 420, 400, 399, 450, 499].map(|v| [v]);
+        assert_eq!(run(&trace, &[T]), [0, 1, 1, 1, 1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn channels_are_independent() {
+        let thresholds = [
+            T,
+            Thresholds {
+                press: 1000,
+                release: 900,
+            },
+        ];
+        let trace = [[600, 600], [450, 1000], [350, 950], [350, 899]];
+        assert_eq!(run(&trace, &thresholds), [0b01, 0b11, 0b10, 0b00]);
+    }
+
+    #[test]
+    fn equal_levels_disable_hysteresis() {
+        let t = Thresholds {
+            press: 500,
+            release: 500,
+        };
+        assert_eq!(run(&[[500], [499], [500]], &[t]), [1, 0, 1]);
+    }
+
+    #[test]
+    fn unconfigured_channels_never_press() {
+        assert_eq!(run(&[[4095, 4095]], &[T]), [0b01]);
+    }
+
+    #[test]
+    fn rapid_trigger_follows_peaks_and_troughs() {
+        // A trill between 1500 and 1200, never below the release level of 400
+        let trace = [
+            0, 600, 1500, 1400, 1350, 1200, 1250, 1300, 1500, 1360, 1340, 1250,
+        ];
+        assert_eq!(
+            run_rapid(&trace.map(|v| [v]), &[T], &[Some(RAPID)]),
+            [0, 1, 1, 1, 0, 0, 0, 1, 1, 1, 0, 0]
+        );
+        // Held down by the fixed thresholds alone
+        assert_eq!(
+            run(&trace.map(|v| [v]), &[T]),
+            [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_presses_below_the_press_level() {
+        // Released by the fall, then pressed again by the rise, between the thresholds
+        let trace = [600, 440, 450, 540, 390, 480, 499, 500];
+        assert_eq!(
+            run_rapid(&trace.map(|v| [v]), &[T], &[Some(RAPID)]),
+            [1, 0, 0, 1, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_ends_below_the_release_level() {
+        // Falling below the release level disarms the rise
+        let trace = [1000, 800, 399, 450, 499, 500];
+        assert_eq!(
+            run_rapid(&trace.map(|v| [v]), &[T], &[Some(RAPID)]),
+            [1, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_is_per_channel() {
+        let trace = [[1000, 1000], [800, 800], [950, 950]];
+        assert_eq!(
+            run_rapid(&trace, &[T, T], &[None, Some(RAPID)]),
+            [0b11, 0b01, 0b11]
+        );
+    }
+}
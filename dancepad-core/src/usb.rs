@@ -0,0 +1,175 @@
+//! How the pad identifies itself over USB, and how it recovers from errors on the bus
+
+use abi::{UsbErrors, UsbString};
+
+/// Length of the unique device ID of the chip in bytes
+pub const UID_LEN: usize = 12;
+
+/// Default USB serial number of a pad with the unique device ID `uid`
+///
+/// The ID is written out as upper-case hex in the order it is laid out in memory, so that it can
+/// be checked against what a debug probe reads back.
+pub fn serial_number(uid: &[u8; UID_LEN]) -> UsbString {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut serial = UsbString::new();
+    for byte in uid {
+        for nibble in [byte >> 4, byte & 0xf] {
+            // 24 characters always fit
+            serial.push(HEX[usize::from(nibble)] as char).unwrap();
+        }
+    }
+    serial
+}
+
+/// Consecutive failed report writes after which the HID class is reset
+pub const CLASS_RESET_STREAK: u16 = 8;
+
+/// Consecutive failed report writes after which the pad detaches from the bus
+///
+/// Reports are only written when the state of the pad changes, but one that fails stays queued and
+/// is retried at every start of frame. The streak of a bus that keeps failing therefore grows by
+/// one a millisecond, and this gives up on the class after 64 ms. A write that goes through ends
+/// the streak, however far apart the writes are.
+pub const DEVICE_RESET_STREAK: u16 = 64;
+
+/// A failed transfer on the USB bus
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The host had not picked up the previous report yet
+    Busy,
+    /// The USB peripheral failed to send a report
+    Bus,
+    /// A report did not fit the report descriptor
+    Serialization,
+    /// A read or write on the configuration channel failed
+    Serial,
+}
+
+/// What to do to recover from a `Fault`, from the least to the most drastic
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    /// Carry on, the next transfer may well succeed
+    None,
+    /// Reset the state of the HID class
+    ResetClass,
+    /// Detach from the bus and attach again, so that the host enumerates the pad afresh
+    ResetDevice,
+}
+
+/// Counts USB errors and escalates recovery while report writes keep failing
+///
+/// Only failures of the USB peripheral escalate. A host that does not pick up reports, e.g.
+/// because it suspended the pad, is no reason to reset anything.
+#[derive(Clone, Debug, Default)]
+pub struct Recovery {
+    errors: UsbErrors,
+    /// Report writes failed since the last one that succeeded
+    streak: u16,
+}
+
+impl Recovery {
+    pub const fn new() -> Self {
+        Self {
+            errors: UsbErrors {
+                dropped_reports: 0,
+                bus: 0,
+                serialization: 0,
+                serial: 0,
+                class_resets: 0,
+                device_resets: 0,
+            },
+            streak: 0,
+        }
+    }
+
+    /// Records that a report was sent
+    pub fn sent(&mut self) {
+        self.streak = 0;
+    }
+
+    /// Records `fault` and returns how to recover from it
+    pub fn fault(&mut self, fault: Fault) -> Action {
+        let errors = &mut self.errors;
+        match fault {
+            Fault::Busy => errors.dropped_reports = errors.dropped_reports.saturating_add(1),
+            Fault::Serialization => errors.serialization = errors.serialization.saturating_add(1),
+            Fault::Serial => errors.serial = errors.serial.saturating_add(1),
+            Fault::Bus => {
+                errors.bus = errors.bus.saturating_add(1);
+                self.streak += 1;
+                if self.streak >= DEVICE_RESET_STREAK {
+                    self.streak = 0;
+                    errors.device_resets = errors.device_resets.saturating_add(1);
+                    return Action::ResetDevice;
+                }
+                if self.streak % CLASS_RESET_STREAK == 0 {
+                    errors.class_resets = errors.class_resets.saturating_add(1);
+                    return Action::ResetClass;
+                }
+            }
+        }
+        Action::None
+    }
+
+    /// Errors counted since power-on
+    pub fn errors(&self) -> &UsbErrors {
+        &self.errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_number_is_uid_in_hex() {
+        let uid = [
+            0x2b, 0x00, 0x3f, 0x00, 0x0c, 0x51, 0x33, 0x30, 0x34, 0x37, 0x36, 0xff,
+        ];
+        assert_eq!(serial_number(&uid), "2B003F000C513330343736FF");
+    }
+
+    #[test]
+    fn escalates_while_writes_keep_failing() {
+        let mut recovery = Recovery::new();
+        let actions: Vec<_> = (0..DEVICE_RESET_STREAK)
+            .map(|_| recovery.fault(Fault::Bus))
+            .collect();
+        let resets = |action| actions.iter().filter(|a| **a == action).count() as u16;
+        assert_eq!(
+            resets(Action::ResetClass),
+            DEVICE_RESET_STREAK / CLASS_RESET_STREAK - 1
+        );
+        assert_eq!(actions.last(), Some(&Action::ResetDevice));
+
+        let errors = recovery.errors();
+        assert_eq!(errors.bus, u32::from(DEVICE_RESET_STREAK));
+        assert_eq!(errors.class_resets, u32::from(resets(Action::ResetClass)));
+        assert_eq!(errors.device_resets, 1);
+    }
+
+    #[test]
+    fn sent_report_ends_streak() {
+        let mut recovery = Recovery::new();
+        for _ in 0..10 * CLASS_RESET_STREAK {
+            for _ in 0..CLASS_RESET_STREAK - 1 {
+                assert_eq!(recovery.fault(Fault::Bus), Action::None);
+            }
+            recovery.sent();
+        }
+        assert_eq!(recovery.errors().class_resets, 0);
+    }
+
+    #[test]
+    fn busy_host_is_only_counted() {
+        let mut recovery = Recovery::new();
+        for _ in 0..10 * DEVICE_RESET_STREAK {
+            assert_eq!(recovery.fault(Fault::Busy), Action::None);
+            assert_eq!(recovery.fault(Fault::Serial), Action::None);
+        }
+        let errors = recovery.errors();
+        assert_eq!(errors.dropped_reports, 10 * u32::from(DEVICE_RESET_STREAK));
+        assert_eq!(errors.serial, 10 * u32::from(DEVICE_RESET_STREAK));
+        assert_eq!(errors.class_resets + errors.device_resets, 0);
+    }
+}
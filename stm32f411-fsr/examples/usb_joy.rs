@@ -1,6 +1,6 @@
 #![no_main]
 #![no_std]
-#![allow(static_mut_refs)]
+#![allow(static_mut_refs, clippy::empty_loop, clippy::needless_if)]
 
 // Print panic message to probe console
 use panic_probe as _;
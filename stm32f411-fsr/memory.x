@@ -1,7 +1,9 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 512K
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 384K
+  /* Sector 7 holds the persisted pad configuration, see `CONFIG_STORE` in src/main.rs */
+  CONFIG (r) : ORIGIN = 0x08060000, LENGTH = 128K
   RAM (rwx) : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
@@ -0,0 +1,299 @@
+//! Where the board wires its sensors and switches
+//!
+//! `SENSORS`, `MUX_SELECT` and `SWITCHES` are the one place to change for a board that wires its
+//! inputs differently. The channel count, the ADC scan and the buttons of the HID report all
+//! follow from them.
+
+use abi::{Pull, Switch};
+use stm32f4xx_hal::{
+    hal::digital::{ErrorType, OutputPin},
+    pac,
+};
+
+use crate::external;
+
+/// Pins the sensors are wired to, in channel order
+pub const SENSORS: &[AdcPin] = &[AdcPin::Pa5, AdcPin::Pa6, AdcPin::Pa7, AdcPin::Pb0];
+
+const _: () = {
+    assert!(
+        matches!(SENSORS.len(), 1..=16),
+        "a board has 1 to 16 sensors"
+    );
+    let mut idx = 0;
+    while idx < SENSORS.len() {
+        let mut other = idx + 1;
+        while other < SENSORS.len() {
+            assert!(
+                SENSORS[idx] as u8 != SENSORS[other] as u8,
+                "every sensor needs a pin of its own"
+            );
+            other += 1;
+        }
+        idx += 1;
+    }
+};
+
+/// Pins the rest of the firmware takes: USB on PA11 and PA12, the debug probe on PA13 and PA14,
+/// the status LED on PC13, and those of the external ADC, if any
+const RESERVED: &[GpioPin] = &[
+    GpioPin::new(Port::A, 11),
+    GpioPin::new(Port::A, 12),
+    GpioPin::new(Port::A, 13),
+    GpioPin::new(Port::A, 14),
+    GpioPin::new(Port::C, 13),
+];
+
+/// Select lines of the analog muxes in front of the pins in `SENSORS`, S0 first, shared by every
+/// mux. None for sensors wired straight to the pins, four for CD74HC4067s and three for CD4051s.
+///
+/// Each line doubles the channels, sensor `input` of the mux on `SENSORS[pin]` being channel
+/// `pin << MUX_SELECT.len() | input`, and divides the frame rate by two.
+pub const MUX_SELECT: &[GpioPin] = &[];
+
+/// Time the muxes take to settle on a newly selected input, in µs
+pub const MUX_SETTLE_US: u32 = 1;
+
+const _: () = {
+    assert!(
+        SENSORS.len() << MUX_SELECT.len() <= abi::MAX_CHANNELS,
+        "a board has at most 32 sensor channels"
+    );
+    let mut idx = 0;
+    while idx < MUX_SELECT.len() {
+        let line = MUX_SELECT[idx];
+        assert!(
+            !line.is_reserved(),
+            "select lines cannot be pins the firmware takes"
+        );
+        let mut sensor = 0;
+        while sensor < SENSORS.len() {
+            assert!(
+                !line.is(SENSORS[sensor].gpio()),
+                "select lines cannot be sensor pins"
+            );
+            sensor += 1;
+        }
+        idx += 1;
+    }
+};
+
+/// Pins of the digital switches, such as tape switches, microswitches or arcade buttons for the
+/// menu, in the order of their buttons, which follow those of the panels. Their pull, polarity and
+/// debounce are part of the `PadConfig`.
+pub const SWITCHES: &[GpioPin] = &[];
+
+const _: () = {
+    let mut idx = 0;
+    while idx < SWITCHES.len() {
+        let switch = SWITCHES[idx];
+        assert!(
+            !switch.is_reserved(),
+            "switches cannot be pins the firmware takes"
+        );
+        let mut sensor = 0;
+        while sensor < SENSORS.len() {
+            assert!(
+                !switch.is(SENSORS[sensor].gpio()),
+                "switches cannot be sensor pins"
+            );
+            sensor += 1;
+        }
+        let mut line = 0;
+        while line < MUX_SELECT.len() {
+            assert!(
+                !switch.is(MUX_SELECT[line]),
+                "switches cannot be select lines"
+            );
+            line += 1;
+        }
+        let mut other = idx + 1;
+        while other < SWITCHES.len() {
+            assert!(
+                !switch.is(SWITCHES[other]),
+                "every switch needs a pin of its own"
+            );
+            other += 1;
+        }
+        idx += 1;
+    }
+};
+
+/// Reads the pins of `SWITCHES`, bit `n` set if the pin of switch `n` is high
+///
+/// The pulls of `config` are applied to the pins first. That costs next to nothing, and keeps them
+/// in step with the configuration without a hook for its changes. Switches without an entry in
+/// `config` are left floating.
+pub fn read_switches(config: &[Switch]) -> u32 {
+    SWITCHES.iter().enumerate().fold(0, |high, (idx, pin)| {
+        pin.set_pull(config.get(idx).map_or(Pull::None, |switch| switch.pull));
+        high | u32::from(pin.is_high()) << idx
+    })
+}
+
+/// A pin of the STM32F411 wired to an input of ADC1, numbered after that input
+// Every board leaves some of them unused
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AdcPin {
+    Pa0 = 0,
+    Pa1,
+    Pa2,
+    Pa3,
+    Pa4,
+    Pa5,
+    Pa6,
+    Pa7,
+    Pb0,
+    Pb1,
+    Pc0,
+    Pc1,
+    Pc2,
+    Pc3,
+    Pc4,
+    Pc5,
+}
+
+impl AdcPin {
+    /// Number of the ADC input the pin is wired to
+    pub const fn input(self) -> u8 {
+        self as u8
+    }
+
+    /// The GPIO pin behind the ADC input
+    pub const fn gpio(self) -> GpioPin {
+        match self.input() {
+            input @ 0..=7 => GpioPin::new(Port::A, input),
+            input @ 8..=9 => GpioPin::new(Port::B, input - 8),
+            input => GpioPin::new(Port::C, input - 10),
+        }
+    }
+
+    /// Switches the pin to analog mode, which takes it off the digital input buffer
+    ///
+    /// The HAL only does this for pins that are types of their own. The clock of the GPIO port
+    /// must be running.
+    pub fn into_analog(self) {
+        self.gpio().set_mode(0b11);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Port {
+    A,
+    B,
+    C,
+}
+
+/// A pin of the STM32F411 picked by its port and number, rather than by a type of its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpioPin {
+    pub port: Port,
+    pub pin: u8,
+}
+
+impl GpioPin {
+    pub const fn new(port: Port, pin: u8) -> Self {
+        assert!(pin < 16, "a port has 16 pins");
+        Self { port, pin }
+    }
+
+    /// Whether both are the same pin
+    pub const fn is(self, other: GpioPin) -> bool {
+        self.port as u8 == other.port as u8 && self.pin == other.pin
+    }
+
+    /// Whether the pin is one of `RESERVED` or `external::PINS`
+    pub const fn is_reserved(self) -> bool {
+        let mut idx = 0;
+        while idx < RESERVED.len() {
+            if self.is(RESERVED[idx]) {
+                return true;
+            }
+            idx += 1;
+        }
+        let mut idx = 0;
+        while idx < external::PINS.len() {
+            if self.is(external::PINS[idx]) {
+                return true;
+            }
+            idx += 1;
+        }
+        false
+    }
+
+    /// Switches the pin to an input, which it is after reset unless it has another function
+    pub fn into_input(self) {
+        self.set_mode(0b00);
+    }
+
+    pub fn is_high(self) -> bool {
+        self.port().idr().read().bits() & 1 << self.pin != 0
+    }
+
+    pub fn set_pull(self, pull: Pull) {
+        let bits = match pull {
+            Pull::None => 0b00,
+            Pull::Up => 0b01,
+            Pull::Down => 0b10,
+        };
+        let shift = 2 * u32::from(self.pin);
+        // SAFETY: only the pull bits of the pin are touched, and no other driver uses the pins of
+        // the board description
+        unsafe {
+            self.port()
+                .pupdr()
+                .modify(|r, w| w.bits(r.bits() & !(0b11 << shift) | bits << shift))
+        };
+    }
+
+    /// Switches the pin to a push-pull output, driven low. The clock of the GPIO port must be
+    /// running.
+    pub fn into_output(mut self) -> Self {
+        self.set_low().ok();
+        self.set_mode(0b01);
+        self
+    }
+
+    /// Registers of the port, which all ports lay out the same as port A
+    fn port(self) -> &'static pac::gpioa::RegisterBlock {
+        let ptr = match self.port {
+            Port::A => pac::GPIOA::ptr(),
+            Port::B => pac::GPIOB::ptr().cast(),
+            Port::C => pac::GPIOC::ptr().cast(),
+        };
+        // SAFETY: the registers are memory mapped for as long as the program runs
+        unsafe { &*ptr }
+    }
+
+    fn set_mode(self, mode: u32) {
+        let shift = 2 * u32::from(self.pin);
+        // SAFETY: only the mode bits of the pin are touched, and no other driver uses the pins of
+        // the board description
+        unsafe {
+            self.port()
+                .moder()
+                .modify(|r, w| w.bits(r.bits() & !(0b11 << shift) | mode << shift))
+        };
+    }
+}
+
+impl ErrorType for GpioPin {
+    type Error = core::convert::Infallible;
+}
+
+impl OutputPin for GpioPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        // SAFETY: writes to BSRR only affect the pins whose bits are set
+        unsafe { self.port().bsrr().write(|w| w.bits(1 << (self.pin + 16))) };
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        // SAFETY: writes to BSRR only affect the pins whose bits are set
+        unsafe { self.port().bsrr().write(|w| w.bits(1 << self.pin)) };
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Sensors read through an external ADC, with the `ads1115`, `ads1256` or `hx711` feature
+//!
+//! Without any of them, there is no external ADC and `Source` is left empty.
+//!
+//! ADC1 keeps scanning VREFINT and the temperature sensor, but the frames fed through the signal
+//! path come from the external ADC, which `external` polls. Its reference does not follow VDDA, so
+//! supply compensation is left out.
+//!
+//! FSRs go to the inputs of an ADS1115 or ADS1256 against ground, sensor `n` to input `n`:
+//!
+//! * ADS1115: I2C1 with SCL on PB8 and SDA on PB9, ADDR tied to ground.
+//! * ADS1256: SPI2 with SCK on PB13, MISO (DOUT) on PB14, MOSI (DIN) on PB15, CS on PB12 and DRDY
+//!   on PB10.
+//!
+//! Load cells each go to an HX711 instead, which share their clock (PD_SCK) on PB12. The data line
+//! (DOUT) of cell `n` is the `n`th of PB13, PB14, PB15 and PB10, and RATE is tied high.
+
+#[cfg(any(
+    all(feature = "ads1115", feature = "ads1256"),
+    all(feature = "ads1115", feature = "hx711"),
+    all(feature = "ads1256", feature = "hx711"),
+))]
+compile_error!("the `ads1115`, `ads1256` and `hx711` features exclude each other");
+
+#[cfg(feature = "external-adc")]
+use stm32f4xx_hal::{gpio::gpiob, pac, prelude::*, rcc::Clocks};
+
+use crate::board::GpioPin;
+#[cfg(feature = "external-adc")]
+use crate::board::Port;
+
+/// Supply of the FSRs, which reads `ADC_MAX`
+#[cfg(any(feature = "ads1115", feature = "ads1256"))]
+const SUPPLY_MV: u16 = 3300;
+
+#[cfg(feature = "ads1115")]
+mod adc {
+    use dancepad_core::{
+        source::{
+            ads1115::{self, Ads1115},
+            ExternalAdc,
+        },
+        Samples,
+    };
+    use stm32f4xx_hal::{i2c::I2c, pac::I2C1};
+
+    use super::*;
+
+    /// Number of sensor channels, one per input of the ADC
+    pub const CHANNELS: usize = 4;
+
+    /// Period `external` polls the ADC at, about a conversion
+    pub const POLL_US: u32 = 1_000;
+
+    /// Frames per second, as the inputs share the 860 conversions per second of the ADC
+    pub const FRAME_HZ: u32 = 860 / CHANNELS as u32;
+
+    /// Samples of the FSRs, scaled to the counts of ADC1
+    pub const SAMPLES: Samples = Samples::Counts;
+
+    /// Pins of I2C1
+    pub const PINS: &[GpioPin] = &[GpioPin::new(Port::B, 8), GpioPin::new(Port::B, 9)];
+
+    pub type Source = ExternalAdc<Ads1115<I2c<I2C1>>, CHANNELS>;
+
+    pub fn init(dp: Peripherals, gpiob: gpiob::Parts, clocks: &Clocks) -> Source {
+        let i2c = I2c::new(dp.i2c1, (gpiob.pb8, gpiob.pb9), 400.kHz(), clocks);
+        let adc = Ads1115::new(i2c, ads1115::DEFAULT_ADDRESS);
+        ExternalAdc::new(adc, ads1115::code(SUPPLY_MV))
+    }
+}
+
+#[cfg(feature = "ads1256")]
+mod adc {
+    use dancepad_core::{
+        source::{
+            ads1256::{self, Ads1256},
+            ExternalAdc,
+        },
+        Samples,
+    };
+    use embedded_hal_bus::spi::ExclusiveDevice;
+    use stm32f4xx_hal::{
+        gpio::{Input, Output, PinState, PB10, PB12},
+        pac::{SPI2, TIM5},
+        spi::{self, Spi},
+        timer::Delay,
+    };
+
+    use super::*;
+
+    /// Number of sensor channels, one per input of the ADC
+    pub const CHANNELS: usize = 8;
+
+    /// Period `external` polls the ADC at, about a conversion
+    pub const POLL_US: u32 = 200;
+
+    /// Frames per second, as the inputs share the conversions taken at every poll
+    pub const FRAME_HZ: u32 = 1_000_000 / POLL_US / CHANNELS as u32;
+
+    /// Samples of the FSRs, scaled to the counts of ADC1
+    pub const SAMPLES: Samples = Samples::Counts;
+
+    /// Pins of SPI2, CS and DRDY
+    pub const PINS: &[GpioPin] = &[
+        GpioPin::new(Port::B, 10),
+        GpioPin::new(Port::B, 12),
+        GpioPin::new(Port::B, 13),
+        GpioPin::new(Port::B, 14),
+        GpioPin::new(Port::B, 15),
+    ];
+
+    pub type Source = ExternalAdc<
+        Ads1256<ExclusiveDevice<Spi<SPI2>, PB12<Output>, Delay<TIM5, 1_000_000>>, PB10<Input>>,
+        CHANNELS,
+    >;
+
+    pub fn init(dp: Peripherals, gpiob: gpiob::Parts, clocks: &Clocks) -> Source {
+        let mode = spi::Mode {
+            polarity: spi::Polarity::IdleLow,
+            phase: spi::Phase::CaptureOnSecondTransition,
+        };
+        let bus = dp
+            .spi2
+            .spi((gpiob.pb13, gpiob.pb14, gpiob.pb15), mode, 1.MHz(), clocks);
+        let cs = gpiob.pb12.into_push_pull_output_in_state(PinState::High);
+        let delay = dp.tim5.delay_us(clocks);
+        let Ok(spi) = ExclusiveDevice::new(bus, cs, delay);
+        let mut adc = Ads1256::new(spi, gpiob.pb10.into_pull_up_input());
+        if let Err(e) = adc.configure() {
+            rtt_target::rprintln!("failed to configure the ADS1256: {:?}", e);
+        }
+        ExternalAdc::new(adc, ads1256::code(SUPPLY_MV))
+    }
+}
+
+#[cfg(feature = "hx711")]
+mod adc {
+    use abi::DEFAULT_COUNTS_PER_KG;
+    use dancepad_core::{source::hx711::Hx711, Samples};
+    use stm32f4xx_hal::{
+        gpio::{ErasedPin, Input, Output, PB12},
+        pac::TIM5,
+        timer::Delay,
+    };
+
+    use super::*;
+
+    /// Number of sensor channels, one per load cell
+    pub const CHANNELS: usize = 4;
+
+    /// Period `external` polls the cells at
+    pub const POLL_US: u32 = 1_000;
+
+    /// Frames per second, with the RATE pin of every HX711 tied high. Tied low, they convert at
+    /// only 10 samples per second, far too slow to play on.
+    pub const FRAME_HZ: u32 = 80;
+
+    /// Samples of the load cells, in grams
+    pub const SAMPLES: Samples = Samples::Grams;
+
+    /// Pins of the clock and data lines
+    pub const PINS: &[GpioPin] = &[
+        GpioPin::new(Port::B, 10),
+        GpioPin::new(Port::B, 12),
+        GpioPin::new(Port::B, 13),
+        GpioPin::new(Port::B, 14),
+        GpioPin::new(Port::B, 15),
+    ];
+
+    pub type Source = Hx711<PB12<Output>, ErasedPin<Input>, Delay<TIM5, 1_000_000>, CHANNELS>;
+
+    /// Starts taring the cells, which must be unloaded until the first frame comes out. `external`
+    /// weighs them at the scales of the configuration from its first poll on.
+    pub fn init(dp: Peripherals, gpiob: gpiob::Parts, clocks: &Clocks) -> Source {
+        let sck = gpiob.pb12.into_push_pull_output();
+        let dout = [
+            gpiob.pb13.into_floating_input().erase(),
+            gpiob.pb14.into_floating_input().erase(),
+            gpiob.pb15.into_floating_input().erase(),
+            gpiob.pb10.into_floating_input().erase(),
+        ];
+        let scales = [DEFAULT_COUNTS_PER_KG; CHANNELS];
+        Hx711::new(sck, dout, dp.tim5.delay_us(clocks), scales)
+    }
+}
+
+#[cfg(not(feature = "external-adc"))]
+pub type Source = ();
+/// Pins taken by the external ADC
+#[cfg(not(feature = "external-adc"))]
+pub const PINS: &[GpioPin] = &[];
+#[cfg(feature = "external-adc")]
+pub use adc::{init, Source, CHANNELS, FRAME_HZ, PINS, POLL_US, SAMPLES};
+
+/// Peripherals taken by the external ADC
+#[cfg(feature = "external-adc")]
+pub struct Peripherals {
+    #[cfg(feature = "ads1115")]
+    pub i2c1: pac::I2C1,
+    #[cfg(feature = "ads1256")]
+    pub spi2: pac::SPI2,
+    #[cfg(any(feature = "ads1256", feature = "hx711"))]
+    pub tim5: pac::TIM5,
+}
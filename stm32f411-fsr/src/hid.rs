@@ -0,0 +1,159 @@
+//! The HID classes of the pad's personalities
+//!
+//! The joystick is the pad's own, with one axis per sensor on top of X, Y and the buttons. The
+//! keyboard is the stock NKRO boot keyboard.
+
+use abi::Personality;
+use dancepad_core::{
+    hid::{pressed_keys, report_len, ReportDescriptor},
+    Report,
+};
+use stm32f4xx_hal::prelude::*;
+use usb_device::{bus::UsbBus, class::UsbClass, class_prelude::UsbBusAllocator};
+use usbd_human_interface_device::{
+    device::keyboard::{
+        NKROBootKeyboard, NKROBootKeyboardConfig, NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR,
+    },
+    page::Keyboard,
+    usb_class::prelude::*,
+};
+
+use crate::{CHANNELS, SWITCHES};
+
+/// Number of buttons of the joystick, enough for a panel per sensor and the switches
+const BUTTONS: usize = CHANNELS + SWITCHES;
+
+static REPORT_DESCRIPTOR: ReportDescriptor = ReportDescriptor::new(BUTTONS);
+
+const _: () = assert!(
+    report_len(BUTTONS) <= 16,
+    "the report must fit in `InBytes16`"
+);
+
+pub struct PadJoystick<'a, B: UsbBus> {
+    interface: Interface<'a, B, InBytes16, OutNone, ReportSingle>,
+}
+
+impl<B: UsbBus> PadJoystick<'_, B> {
+    pub fn write_report(&mut self, report: &Report) -> Result<(), UsbHidError> {
+        self.interface
+            .write_report(&report.to_bytes(BUTTONS)[..report_len(BUTTONS)])
+            .map(|_| ())
+            .map_err(UsbHidError::from)
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for PadJoystick<'a, B> {
+    type I = Interface<'a, B, InBytes16, OutNone, ReportSingle>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct PadJoystickConfig<'a> {
+    interface: InterfaceConfig<'a, InBytes16, OutNone, ReportSingle>,
+}
+
+impl Default for PadJoystickConfig<'_> {
+    fn default() -> Self {
+        Self {
+            interface: InterfaceBuilder::new(REPORT_DESCRIPTOR.as_bytes())
+                .unwrap()
+                .boot_device(InterfaceProtocol::None)
+                .description("Dance pad")
+                // Reports are queued as the state changes, as often as every millisecond, so that
+                // none waits more than a frame for the host to pick it up
+                .in_endpoint(1.millis())
+                .unwrap()
+                .without_out_endpoint()
+                .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for PadJoystickConfig<'a> {
+    type Allocated = PadJoystick<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        Self::Allocated {
+            interface: Interface::new(usb_alloc, self.interface),
+        }
+    }
+}
+
+/// The HID class of the personality the pad enumerated with
+pub enum Hid<'a, B: UsbBus> {
+    Joystick(UsbHidClass<'a, B, frunk::HList!(PadJoystick<'a, B>)>),
+    Keyboard(UsbHidClass<'a, B, frunk::HList!(NKROBootKeyboard<'a, B>)>),
+}
+
+impl<'a, B: UsbBus> Hid<'a, B> {
+    pub fn new(personality: Personality, usb_alloc: &'a UsbBusAllocator<B>) -> Self {
+        match personality {
+            Personality::Joystick => Hid::Joystick(
+                UsbHidClassBuilder::new()
+                    .add_device(PadJoystickConfig::default())
+                    .build(usb_alloc),
+            ),
+            Personality::Keyboard => Hid::Keyboard(
+                UsbHidClassBuilder::new()
+                    .add_device(keyboard_config())
+                    .build(usb_alloc),
+            ),
+        }
+    }
+
+    /// Sends `report`, or the `keys` of its pressed buttons for the keyboard
+    pub fn write_report(&mut self, report: &Report, keys: &[u8]) -> Result<(), UsbHidError> {
+        match self {
+            Hid::Joystick(class) => class.device().write_report(report),
+            Hid::Keyboard(class) => class
+                .device()
+                .write_report(pressed_keys(report.buttons, keys).map(Keyboard::from)),
+        }
+    }
+
+    /// Keeps time for the keyboard's idle reports, to be called every millisecond
+    pub fn tick(&mut self) -> Result<(), UsbHidError> {
+        match self {
+            Hid::Joystick(class) => class.tick(),
+            Hid::Keyboard(class) => {
+                // The LED state is of no use to a pad, but it must be read out for the host to
+                // be able to send the next one
+                class.device().read_report().ok();
+                class.tick()
+            }
+        }
+    }
+
+    pub fn class(&mut self) -> &mut dyn UsbClass<B> {
+        match self {
+            Hid::Joystick(class) => class,
+            Hid::Keyboard(class) => class,
+        }
+    }
+}
+
+/// The stock keyboard configuration, polled as often as reports are produced
+fn keyboard_config<'a>() -> NKROBootKeyboardConfig<'a> {
+    NKROBootKeyboardConfig::new(ManagedIdleInterfaceConfig::new(
+        InterfaceBuilder::new(NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR)
+            .unwrap()
+            .description("Dance pad keyboard")
+            .boot_device(InterfaceProtocol::Keyboard)
+            .idle_default(500.millis())
+            .unwrap()
+            .in_endpoint(1.millis())
+            .unwrap()
+            .with_out_endpoint(100.millis())
+            .unwrap()
+            .build(),
+    ))
+}
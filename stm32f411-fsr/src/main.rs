@@ -1,76 +1,228 @@
 #![no_std]
 #![no_main]
+#![allow(static_mut_refs)]
+
+mod board;
+mod external;
+
+/// Number of ADC1 pins the sensors are wired to, directly or through a mux
+const PINS: usize = board::SENSORS.len();
+
+/// Number of select lines of the muxes, and of inputs each mux selects among
+const MUX_LINES: usize = board::MUX_SELECT.len();
+const MUX_INPUTS: usize = 1 << MUX_LINES;
+
+/// Number of sensor channels sampled through ADC1, one per mux input on every pin in
+/// `board::SENSORS`
+const INTERNAL_CHANNELS: usize = PINS * MUX_INPUTS;
+
+/// Number of sensor channels fed through the signal path, those of ADC1 unless an external ADC
+/// takes its place
+#[cfg(not(feature = "external-adc"))]
+const CHANNELS: usize = INTERNAL_CHANNELS;
+#[cfg(feature = "external-adc")]
+const CHANNELS: usize = external::CHANNELS;
+type AdcValues = abi::AdcValues<CHANNELS>;
+
+/// Number of digital switches, whose buttons follow those of the panels
+const SWITCHES: usize = board::SWITCHES.len();
+const _: () = assert!(
+    CHANNELS + SWITCHES <= abi::MAX_CHANNELS,
+    "a pad has at most 32 buttons"
+);
+
+/// The frame of ADC1 to feed through the signal path, none with an external ADC
+#[cfg(not(feature = "external-adc"))]
+fn internal_frame(frame: abi::AdcValues<INTERNAL_CHANNELS>) -> Option<AdcValues> {
+    Some(frame)
+}
+#[cfg(feature = "external-adc")]
+fn internal_frame(_: abi::AdcValues<INTERNAL_CHANNELS>) -> Option<AdcValues> {
+    None
+}
 
-type AdcValues = abi::AdcValues<4>;
+/// Longest regular sequence of ADC1
+const MAX_SCAN_LEN: usize = 16;
+
+/// Number of channels in a scan: the sensor pins, followed by VREFINT and the temperature sensor
+/// if the sequence has room for them. Boards with more than 14 sensor pins go without, and assume
+/// nominal conditions.
+const SCAN_LEN: usize = if PINS + 2 <= MAX_SCAN_LEN {
+    PINS + 2
+} else {
+    PINS
+};
+
+/// Clock of the ADC, PCLK2 divided by 2
+const ADC_HZ: u32 = 42_000_000;
+
+/// ADC clock cycles per channel: 480 of sampling, which the temperature sensor needs at least 10
+/// µs of, and 12 of conversion
+const CONVERSION_CYCLES: u32 = 480 + 12;
+
+/// Rate at which TIM3 triggers a scan of all channels: 8 kHz, or as fast as the scan allows in
+/// whole multiples of `FRAME_HZ`. A scan of six channels takes about 70 µs.
+const SAMPLE_HZ: u32 = {
+    let max = ADC_HZ / (SCAN_LEN as u32 * CONVERSION_CYCLES);
+    let hz = if max < 8_000 { max } else { 8_000 };
+    hz / FRAME_HZ * FRAME_HZ
+};
+
+/// Rate at which scans are averaged into the frames fed through the signal path. Muxes divide it
+/// by the number of their inputs.
+const FRAME_HZ: u32 = 1_000;
+
+const _: () = assert!(
+    SAMPLE_HZ >= FRAME_HZ && SAMPLE_HZ % FRAME_HZ == 0,
+    "SAMPLE_HZ must be a multiple of FRAME_HZ"
+);
+
+/// Scans taken while one mux input is selected: a frame's worth without muxes, and with them at
+/// least two. `dma` switches the muxes over while the first is taken, which is thrown away, and
+/// they settle before the next.
+const SCANS_PER_STEP: usize = {
+    assert!(
+        MUX_LINES == 0 || board::MUX_SETTLE_US < 1_000_000 / SAMPLE_HZ,
+        "the muxes must settle between two scans"
+    );
+    let scans = (SAMPLE_HZ / FRAME_HZ) as usize / MUX_INPUTS;
+    if MUX_INPUTS == 1 || scans >= 2 {
+        scans
+    } else {
+        2
+    }
+};
+
+/// Frames per second fed through the signal path, whose time constants are scaled to it. Those of
+/// ADC1 come fewer than `FRAME_HZ` if the muxes have too many inputs to take two scans of each in
+/// a frame.
+#[cfg(not(feature = "external-adc"))]
+const PIPELINE_HZ: u32 = SAMPLE_HZ / (SCANS_PER_STEP * MUX_INPUTS) as u32;
+#[cfg(feature = "external-adc")]
+const PIPELINE_HZ: u32 = external::FRAME_HZ;
+
+/// What the frames fed through the signal path measure
+#[cfg(not(feature = "external-adc"))]
+const PIPELINE_SAMPLES: dancepad_core::Samples = dancepad_core::Samples::Counts;
+#[cfg(feature = "external-adc")]
+const PIPELINE_SAMPLES: dancepad_core::Samples = external::SAMPLES;
+
+/// Scans of one mux input, or of a frame without muxes, as written by the DMA
+type ScanBuffer = [u16; SCAN_LEN * SCANS_PER_STEP];
 use panic_probe as _;
-use usbd_human_interface_device::device::joystick::JoystickReport;
 
-static mut EP_MEMORY: [u32; 1024] = [0; 1024];
-
-fn get_report(vals: &AdcValues) -> JoystickReport {
-    // Read out 8 buttons first
-    let mut buttons = 0;
-
-    const THRESH: u16 = 512;
-    for (idx, v) in vals.iter().enumerate() {
-        if *v >= THRESH {
-            buttons |= 0b1 << idx;
-        }
-    }
+mod hid;
 
-    // Always return center for the analog value
-    let (x, y) = (0, 0);
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
 
-    JoystickReport { buttons, x, y }
-}
+/// A command frame received over the serial channel, not yet decoded
+type Frame = heapless::Vec<u8, { abi::MAX_FRAME_LEN }>;
 
 #[rtic::app(device = stm32f4xx_hal::pac, dispatchers = [EXTI0])]
 mod app {
     use core::ptr;
 
-    use crate::AdcValues;
+    use crate::{
+        board::{self, GpioPin},
+        hid::Hid,
+        internal_frame, AdcValues, Frame, ScanBuffer, CHANNELS, INTERNAL_CHANNELS, MUX_LINES, PINS,
+        PIPELINE_HZ, PIPELINE_SAMPLES, SAMPLE_HZ, SCANS_PER_STEP, SCAN_LEN, SWITCHES,
+    };
+    use abi::{
+        Channels, Command, ConfigStore, Error, FirmwareInfo, FrameBuffer, FrameError, PadConfig,
+        Personality, Response, UsbString, MAX_FRAME_LEN, PROTOCOL_VERSION, USB_PID, USB_VID,
+    };
+    use dancepad_core::{
+        conditions::{compensate_supply, Calibration, Monitor},
+        force,
+        mux::{MuxScan, SelectLines},
+        switch::{Millis, Switches},
+        usb::{self, Action, Fault, Recovery},
+        LatencyStats, Pipeline, ReportQueue,
+    };
     use dwt_systick_monotonic::DwtSystick;
+    use rtic::{mutex_prelude::*, Mutex};
     use rtt_target::{rprintln, rtt_init_print};
     use stm32f4xx_hal::{
         adc::{
-            config::{AdcConfig, Dma, SampleTime, Scan, Sequence},
-            Adc,
+            config::{AdcConfig, Dma, ExternalTrigger, SampleTime, Scan, Sequence, TriggerMode},
+            Adc, Temperature, Vref,
         },
         dma::{config::DmaConfig, PeripheralToMemory, Stream0, StreamsTuple, Transfer},
+        flash::{FlashExt, LockedFlash},
+        gpio::{Output, PinState, PushPull, PC13},
         otg_fs::{UsbBus, USB},
         pac::{self, ADC1, DMA2},
         prelude::*,
-        timer::{CounterHz, Event, Timer},
+        signature::{Uid, VrefCal, VtempCal110, VtempCal30},
+        timer::Timer,
     };
     use usb_device::{
         bus::UsbBusAllocator,
-        device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid},
+        device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
+        UsbError,
     };
-    use usbd_human_interface_device::{device::joystick::Joystick, prelude::*};
+    use usbd_human_interface_device::prelude::*;
+    use usbd_serial::SerialPort;
 
     static mut USB_BUS_ALLOCATOR: Option<UsbBusAllocator<UsbBus<USB>>> = None;
 
     const MONO_HZ: u32 = 84_000_000;
 
+    /// Half the period the status LED blinks at while a sensor is faulty
+    const BLINK_MS: u32 = 250;
+
+    /// Configuration log in flash sector 7, which `memory.x` keeps out of the program area
+    const CONFIG_STORE: ConfigStore = ConfigStore::new(0x6_0000, 0x8_0000);
+
+    /// Room for a few encoded responses waiting to be written to the serial port
+    const TX_LEN: usize = 2 * MAX_FRAME_LEN;
+
     #[monotonic(binds = SysTick, default = true)]
     type MyMono = DwtSystick<MONO_HZ>;
+    type Instant = <MyMono as rtic::Monotonic>::Instant;
 
     type DMATransfer =
-        Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, &'static mut [u16; 4]>;
+        Transfer<Stream0<DMA2>, 0, Adc<ADC1>, PeripheralToMemory, &'static mut ScanBuffer>;
 
     #[shared]
     struct Shared {
         transfer: DMATransfer,
-        adc_values: AdcValues,
+        pipeline: Pipeline<CHANNELS>,
+        config: PadConfig,
+        /// Encoded responses, drained into `serial` by `usb_report`
+        tx: heapless::Deque<u8, TX_LEN>,
+        recovery: Recovery,
+        /// Report for `usb_report` to send
+        reports: ReportQueue<Instant>,
+        latency: LatencyStats,
+        conditions: Monitor,
+        switches: Switches<SWITCHES>,
+        /// Milliseconds for the debounce of the switches, which the ticks of `MyMono` wrap around
+        /// too early for
+        millis: Millis<MONO_HZ>,
+        /// Set by `command` for `external` to tare the load cells
+        tare: bool,
     }
 
     #[local]
     struct Local {
-        buffer: Option<&'static mut [u16; 4]>,
         usb_dev: UsbDevice<'static, UsbBus<USB>>,
-        timer: CounterHz<pac::TIM2>,
-        joy: UsbHidClass<'static, UsbBus<USB>, frunk::HList!(Joystick<'static, UsbBus<USB>>)>,
+        /// Configuration the pad enumerated with
+        enumerated: &'static PadConfig,
+        hid: Hid<'static, UsbBus<USB>>,
+        serial: SerialPort<'static, UsbBus<USB>>,
+        rx: FrameBuffer<MAX_FRAME_LEN>,
+        flash: LockedFlash,
         dma_counter: usize,
+        /// Status LED, lit while the pin is low
+        led: PC13<Output<PushPull>>,
+        /// Channels found faulty when `status` last looked
+        faulty: u32,
+        mux: SelectLines<GpioPin, MUX_LINES>,
+        mux_scan: MuxScan<INTERNAL_CHANNELS>,
+        /// External ADC for `external` to poll, if any
+        source: crate::external::Source,
     }
 
     #[init]
@@ -97,20 +249,56 @@ mod app {
 
         let mono = DwtSystick::new(&mut dcb, dwt, systick, MONO_HZ);
 
-        // Configure TIM2 as a periodic timer
-        let mut timer = Timer::new(dp.TIM2, &clocks).counter_hz();
-        timer.start(1_000.Hz()).unwrap();
-        timer.listen(Event::Update);
+        let mut flash = LockedFlash::new(dp.FLASH);
+        let config = match CONFIG_STORE.load(&mut flash) {
+            Ok(Some(config)) if config.has_inputs(CHANNELS, SWITCHES) => config,
+            Ok(_) => {
+                rprintln!("no stored configuration, using defaults");
+                PadConfig::new(CHANNELS).with_switches(SWITCHES)
+            }
+            Err(e) => {
+                rprintln!("failed to read configuration, using defaults: {:?}", e);
+                PadConfig::new(CHANNELS).with_switches(SWITCHES)
+            }
+        };
 
+        // The USB descriptors are built from this copy, so changing it takes a restart
+        let enumerated: &'static PadConfig =
+            cortex_m::singleton!(: PadConfig = config.clone()).unwrap();
+        let uid = Uid::get();
+        let mut uid_bytes = [0; usb::UID_LEN];
+        uid_bytes[..2].copy_from_slice(&uid.x().to_le_bytes());
+        uid_bytes[2..4].copy_from_slice(&uid.y().to_le_bytes());
+        uid_bytes[4] = uid.waf_num();
+        uid_bytes[5..].copy_from_slice(uid.lot_num().as_bytes());
+        let default_serial: &'static UsbString =
+            cortex_m::singleton!(: UsbString = usb::serial_number(&uid_bytes)).unwrap();
+        rprintln!("serial number {}", default_serial);
+
+        // Splitting the ports starts their clocks, which the sensor pins need on any of them
         let gpioa = dp.GPIOA.split();
+        #[cfg(not(feature = "external-adc"))]
+        let _ = dp.GPIOB.split();
+        #[cfg(feature = "external-adc")]
         let gpiob = dp.GPIOB.split();
-        let v1 = gpioa.pa5.into_analog();
-        let v2 = gpioa.pa6.into_analog();
-        let v3 = gpioa.pa7.into_analog();
-        let v4 = gpiob.pb0.into_analog();
+        let led = dp
+            .GPIOC
+            .split()
+            .pc13
+            .into_push_pull_output_in_state(PinState::High);
+        for pin in board::SENSORS {
+            pin.into_analog();
+        }
+        for pin in board::SWITCHES {
+            pin.into_input();
+        }
+        let mut mux = SelectLines::new(core::array::from_fn(|idx| {
+            board::MUX_SELECT[idx].into_output()
+        }));
+        let mux_scan = MuxScan::new(&mut mux);
 
         // USB
-        let (usb_dev, joy) = {
+        let (usb_dev, hid, serial) = {
             let usb = USB::new(
                 (dp.OTG_FS_GLOBAL, dp.OTG_FS_DEVICE, dp.OTG_FS_PWRCLK),
                 (gpioa.pa11, gpioa.pa12),
@@ -120,154 +308,695 @@ mod app {
             let usb_bus = UsbBus::new(usb, unsafe { &mut *ptr::addr_of_mut!(crate::EP_MEMORY) });
             unsafe { USB_BUS_ALLOCATOR.replace(usb_bus) };
 
-            let joy = UsbHidClassBuilder::new()
-                .add_device(
-                    usbd_human_interface_device::device::joystick::JoystickConfig::default(),
-                )
-                .build(unsafe { USB_BUS_ALLOCATOR.as_ref().unwrap() });
+            let hid = Hid::new(enumerated.personality, unsafe {
+                USB_BUS_ALLOCATOR.as_ref().unwrap()
+            });
+
+            // Configuration channel for `abi::Command`s
+            let serial = SerialPort::new(unsafe { USB_BUS_ALLOCATOR.as_ref().unwrap() });
 
             //https://pid.codes
             let usb_dev = UsbDeviceBuilder::new(
                 unsafe { USB_BUS_ALLOCATOR.as_ref().unwrap() },
-                UsbVidPid(0x1209, 0x0001),
+                UsbVidPid(USB_VID, USB_PID),
             )
+            // CDC-ACM needs interface association descriptors to share the device with HID
+            .composite_with_iads()
             .strings(&[StringDescriptors::default()
-                .manufacturer("Hegza")
-                .product("Rusty Joystick")
-                .serial_number("TEST")])
+                .manufacturer(enumerated.usb.manufacturer.as_deref().unwrap_or("Hegza"))
+                .product(enumerated.usb.product.as_deref().unwrap_or(
+                    match enumerated.personality {
+                        Personality::Joystick => "Rusty Joystick",
+                        Personality::Keyboard => "Rusty Keyboard",
+                    },
+                ))
+                .serial_number(
+                    enumerated
+                        .usb
+                        .serial_number
+                        .as_deref()
+                        .unwrap_or(default_serial),
+                )])
             .unwrap()
             .build();
 
-            (usb_dev, joy)
+            // Interrupt at every start of frame too, which the driver has no use for, to send
+            // reports in step with the host's polls
+            // SAFETY: the driver is done setting up the interrupt mask
+            unsafe {
+                (*pac::OTG_FS_GLOBAL::ptr())
+                    .gintmsk()
+                    .modify(|_, w| w.sofm().set_bit())
+            };
+
+            (usb_dev, hid, serial)
         };
 
+        // Every update of TIM3 starts a scan of all channels, with no software involved
+        let mut sample_timer = Timer::new(dp.TIM3, &clocks).counter_hz();
+        sample_timer.start(SAMPLE_HZ.Hz()).unwrap();
+        // SAFETY: the HAL has no API for the master mode, which nothing else touches
+        unsafe { (*pac::TIM3::ptr()).cr2().modify(|_, w| w.mms().update()) };
+
         let adc_config = AdcConfig::default()
             .dma(Dma::Continuous)
-            .scan(Scan::Enabled);
+            .scan(Scan::Enabled)
+            .external_trigger(TriggerMode::RisingEdge, ExternalTrigger::Tim_3_trgo);
 
         let mut adc = Adc::adc1(dp.ADC1, true, adc_config);
-        adc.configure_channel(&v1, Sequence::One, SampleTime::Cycles_480);
-        adc.configure_channel(&v2, Sequence::Two, SampleTime::Cycles_480);
-        adc.configure_channel(&v3, Sequence::Three, SampleTime::Cycles_480);
-        adc.configure_channel(&v4, Sequence::Four, SampleTime::Cycles_480);
-        adc.enable_temperature_and_vref();
+        for (rank, pin) in board::SENSORS.iter().enumerate() {
+            configure_input(pin.input(), rank);
+        }
+        if SCAN_LEN > PINS {
+            let rank = PINS as u8;
+            adc.configure_channel(&Vref, Sequence::from(rank), SampleTime::Cycles_480);
+            adc.configure_channel(
+                &Temperature,
+                Sequence::from(rank + 1),
+                SampleTime::Cycles_480,
+            );
+            adc.enable_temperature_and_vref();
+        }
+        // SAFETY: the ADC is not converting yet
+        unsafe {
+            (*pac::ADC1::ptr())
+                .sqr1()
+                .modify(|_, w| w.l().bits(SCAN_LEN as u8 - 1))
+        };
 
         let dma = StreamsTuple::new(dp.DMA2);
+        // In double buffer mode the DMA runs circularly, switching to the other buffer whenever
+        // one is full, so `dma` gets a whole frame time to read the finished one
         let dma_config = DmaConfig::default()
             .transfer_complete_interrupt(true)
             .memory_increment(true)
-            .double_buffer(false);
+            .double_buffer(true);
 
         // These buffers need to be 'static to use safely with the DMA - we can't allow
         // them to be dropped while the DMA is accessing them. The easiest way
         // to satisfy that is to make them static, and the safest way to do that is with
         // `cortex_m::singleton!`
-        let first_buffer = cortex_m::singleton!(: [u16; 4] = [0; 4]).unwrap();
-        let second_buffer = Some(cortex_m::singleton!(: [u16; 4] = [0; 4]).unwrap());
-        // Give the first buffer to the DMA. The second buffer is held in an Option in
-        // `local.buffer` until the transfer is complete
-        let transfer =
-            Transfer::init_peripheral_to_memory(dma.0, adc, first_buffer, None, dma_config);
-
-        adc_poll::spawn_after(1.millis()).ok();
+        let first_buffer =
+            cortex_m::singleton!(: ScanBuffer = [0; SCAN_LEN * SCANS_PER_STEP]).unwrap();
+        let second_buffer =
+            cortex_m::singleton!(: ScanBuffer = [0; SCAN_LEN * SCANS_PER_STEP]).unwrap();
+        let mut transfer = Transfer::init_peripheral_to_memory(
+            dma.0,
+            adc,
+            first_buffer,
+            Some(second_buffer),
+            dma_config,
+        );
+        transfer.start(|adc| adc.enable());
+        status::spawn().ok();
+
+        #[cfg(not(feature = "external-adc"))]
+        let source = ();
+        #[cfg(feature = "external-adc")]
+        let source = {
+            let peripherals = crate::external::Peripherals {
+                #[cfg(feature = "ads1115")]
+                i2c1: dp.I2C1,
+                #[cfg(feature = "ads1256")]
+                spi2: dp.SPI2,
+                #[cfg(any(feature = "ads1256", feature = "hx711"))]
+                tim5: dp.TIM5,
+            };
+            external::spawn().ok();
+            crate::external::init(peripherals, gpiob, &clocks)
+        };
 
         (
             Shared {
                 transfer,
-                adc_values: Default::default(),
+                pipeline: Pipeline::new(PIPELINE_HZ, PIPELINE_SAMPLES),
+                switches: Switches::new(),
+                millis: Millis::new(),
+                tare: false,
+                config,
+                tx: heapless::Deque::new(),
+                recovery: Recovery::new(),
+                reports: ReportQueue::new(),
+                latency: LatencyStats::new(),
+                conditions: Monitor::new(Calibration {
+                    vrefint: VrefCal::get().read(),
+                    ts_30: VtempCal30::get().read(),
+                    ts_110: VtempCal110::get().read(),
+                }),
             },
             Local {
-                buffer: second_buffer,
                 usb_dev,
-                joy,
-                timer,
+                enumerated,
+                hid,
+                serial,
+                rx: FrameBuffer::new(),
+                flash,
                 dma_counter: 0,
+                led,
+                faulty: 0,
+                mux,
+                mux_scan,
+                source,
             },
             init::Monotonics(mono),
         )
     }
 
-    #[task(shared = [transfer])]
-    fn adc_poll(mut cx: adc_poll::Context) {
-        cx.shared.transfer.lock(|transfer| {
-            transfer.start(|adc| {
-                adc.start_conversion();
-            });
+    /// Runs whenever the DMA has filled a buffer with the scans of a step, and feeds complete
+    /// frames through the signal path
+    ///
+    /// It runs above every other task, as it has to be done with a buffer before the DMA comes back
+    /// to it, and the muxes have to be switched over in step with the scans.
+    #[task(
+        binds = DMA2_STREAM0,
+        priority = 3,
+        shared = [transfer, pipeline, config, reports, conditions, switches, millis],
+        local = [dma_counter, mux, mux_scan]
+    )]
+    fn dma(cx: dma::Context) {
+        let dma::Context { mut shared, local } = cx;
+        let (mux, mux_scan) = (local.mux, local.mux_scan);
+        let step = shared.transfer.lock(|transfer| {
+            // SAFETY: the finished buffer is only read while the DMA fills the other one. Only the
+            // monotonic timer preempts `dma`, and the critical sections of the tasks below it are
+            // short, so it is done with the buffer long before the DMA has filled the other one, a
+            // whole step later
+            unsafe {
+                transfer.next_transfer_with(|buffer, _| {
+                    let step = mux_scan.step::<_, SCAN_LEN>(&buffer[..], mux);
+                    (buffer, step)
+                })
+            }
         });
+        let Ok((scan, frame)) = step else {
+            // Not a transfer complete interrupt
+            return;
+        };
+        let Some(frame) = frame else {
+            // The muxes have inputs left to scan
+            return;
+        };
+        let now = monotonics::now();
+        let (vdda, temperature) = shared.conditions.lock(|conditions| {
+            if let &[vrefint, ts] = &scan[PINS..] {
+                conditions.update(vrefint, ts);
+            }
+            (conditions.vdda_mv(), conditions.temperature())
+        });
+        let Some(raw) = internal_frame(frame) else {
+            // `external` feeds the signal path
+            return;
+        };
+        feed(
+            raw,
+            Some(vdda),
+            temperature,
+            now,
+            (
+                shared.pipeline,
+                shared.config,
+                shared.reports,
+                shared.switches,
+                shared.millis,
+            ),
+        );
 
-        adc_poll::spawn_after(1.millis()).ok();
+        // Print periodically
+        *local.dma_counter = (*local.dma_counter + 1) % 500;
+        if *local.dma_counter == 0 {
+            // From the samples as taken, before any supply compensation
+            let millivolts: [u16; CHANNELS] =
+                raw.map(|sample| dancepad_core::to_millivolts(sample, vdda));
+            rprintln!("millivolts: {:?}", millivolts);
+        }
     }
 
-    #[task(binds = DMA2_STREAM0, shared = [transfer, adc_values], local = [buffer, dma_counter])]
-    fn dma(cx: dma::Context) {
-        let dma::Context { mut shared, local } = cx;
-        let (buffer, sample_to_millivolts) = shared.transfer.lock(|transfer| {
-            // When the DMA completes it will return the buffer we gave it last time - we
-            // now store that as `buffer` We still have our other buffer waiting
-            // in `local.buffer`, so `take` that and give it to the `transfer`
-            let (buffer, _) = transfer
-                .next_transfer(local.buffer.take().unwrap())
-                .unwrap();
-
-            let sample_to_millivolts = transfer.peripheral().make_sample_to_millivolts();
-            (buffer, sample_to_millivolts)
+    /// Polls the external ADC, and feeds its frames through the signal path
+    ///
+    /// Only spawned with an external ADC. RTIC has no place for tasks that are configured out.
+    #[task(
+        shared = [pipeline, config, reports, conditions, switches, millis, tare],
+        local = [source]
+    )]
+    fn external(cx: external::Context) {
+        #[cfg(not(feature = "external-adc"))]
+        let _ = cx;
+        #[cfg(feature = "external-adc")]
+        poll_external(cx);
+    }
+
+    #[cfg(feature = "external-adc")]
+    fn poll_external(cx: external::Context) {
+        use dancepad_core::source::SampleSource;
+
+        let external::Context { mut shared, local } = cx;
+        #[cfg(feature = "hx711")]
+        {
+            // Cheap enough to take over at every poll, so that new scales apply right away
+            let scales = shared
+                .config
+                .lock(|config| core::array::from_fn(|idx| config.scales[idx]));
+            local.source.set_scales(scales);
+            if shared.tare.lock(core::mem::take) {
+                local.source.tare();
+            }
+        }
+        match local.source.read() {
+            Ok(raw) => {
+                let now = monotonics::now();
+                let temperature = shared
+                    .conditions
+                    .lock(|conditions| conditions.temperature());
+                feed(
+                    raw,
+                    None,
+                    temperature,
+                    now,
+                    (
+                        shared.pipeline,
+                        shared.config,
+                        shared.reports,
+                        shared.switches,
+                        shared.millis,
+                    ),
+                );
+            }
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(e)) => rprintln!("external ADC failed: {:?}", e),
+        }
+        external::spawn_after(crate::external::POLL_US.micros()).ok();
+    }
+
+    /// Feeds a frame of every sensor through the signal path, samples the switches, and queues the
+    /// report if it changed
+    ///
+    /// Samples are compensated for `vdda` if it is the reference of the ADC they were taken with,
+    /// and supply compensation is on.
+    fn feed(
+        mut raw: AdcValues,
+        vdda: Option<u32>,
+        temperature: i16,
+        now: Instant,
+        (mut pipeline, mut config, mut reports, mut switches, mut millis): (
+            impl Mutex<T = Pipeline<CHANNELS>>,
+            impl Mutex<T = PadConfig>,
+            impl Mutex<T = ReportQueue<Instant>>,
+            impl Mutex<T = Switches<SWITCHES>>,
+            impl Mutex<T = Millis<MONO_HZ>>,
+        ),
+    ) {
+        let now_ms = millis.lock(|millis| millis.update(now.ticks()));
+        let report =
+            (&mut pipeline, &mut config, &mut switches).lock(|pipeline, config, switches| {
+                if let (Some(vdda), true) = (vdda, config.compensation.supply) {
+                    raw = raw.map(|sample| compensate_supply(sample, vdda));
+                }
+                pipeline.track_temperature(temperature, config);
+                pipeline.sample(&raw, config);
+                let mut report = pipeline.report(config);
+                let high = board::read_switches(&config.switches);
+                let pressed = switches.update(high, now_ms, &config.switches);
+                // There are no switches when the panels take all buttons
+                report.buttons |= pressed.checked_shl(CHANNELS as u32).unwrap_or(0);
+                report
+            });
+        let changed = reports.lock(|reports| {
+            reports.update(report, now);
+            reports.pending().is_some()
         });
+        if changed {
+            rtic::pend(pac::Interrupt::OTG_FS);
+        }
+    }
+
+    /// Runs on every USB event, at every start of frame, and whenever `dma` queues a report
+    ///
+    /// Queued reports are written to the endpoint right away, so that the host picks them up with
+    /// its next poll. Should the endpoint still hold the previous report, the write is retried at
+    /// the next start of frame.
+    #[task(
+        binds = OTG_FS,
+        priority = 2,
+        local = [usb_dev, hid, serial, rx],
+        shared = [config, tx, recovery, reports, latency]
+    )]
+    fn usb_report(mut cx: usb_report::Context) {
+        // SAFETY: the USB driver leaves the start of frame flag alone, it is only cleared here
+        let sof = unsafe {
+            let otg = &*pac::OTG_FS_GLOBAL::ptr();
+            let sof = otg.gintsts().read().sof().bit_is_set();
+            if sof {
+                otg.gintsts().write(|w| w.sof().set_bit());
+            }
+            sof
+        };
 
-        shared.adc_values.lock(|vals| {
-            *vals = *buffer;
+        if cx.local.usb_dev.state() != UsbDeviceState::Configured {
+            // The host starts from scratch once it configures the pad
+            cx.shared.reports.lock(|reports| reports.reset());
+        } else {
+            let pending = cx.shared.reports.lock(|reports| reports.pending());
+            let written = pending.map(|(report, _)| {
+                let keys = cx.shared.config.lock(|config| config.keys.clone());
+                cx.local.hid.write_report(&report, &keys)
+            });
+            // Keeps time for the keyboard's idle reports
+            let ticked = if sof { cx.local.hid.tick() } else { Ok(()) };
+
+            let mut action = match written {
+                None => Action::None,
+                // A keyboard report that did not change since the last one
+                Some(Ok(()) | Err(UsbHidError::Duplicate)) => {
+                    cx.shared.recovery.lock(|recovery| recovery.sent());
+                    if let Some(since) = cx.shared.reports.lock(|reports| reports.sent()) {
+                        let latency = (monotonics::now() - since).to_micros();
+                        cx.shared.latency.lock(|stats| stats.record(latency));
+                    }
+                    Action::None
+                }
+                Some(Err(e)) => cx
+                    .shared
+                    .recovery
+                    .lock(|recovery| recovery.fault(hid_fault(e))),
+            };
+            match ticked {
+                Ok(()) | Err(UsbHidError::Duplicate) => {}
+                Err(e) => {
+                    let escalated = cx
+                        .shared
+                        .recovery
+                        .lock(|recovery| recovery.fault(hid_fault(e)));
+                    action = action.max(escalated);
+                }
+            }
+            match action {
+                Action::None => {}
+                Action::ResetClass => {
+                    rprintln!("HID writes keep failing, resetting the class");
+                    cx.local.hid.class().reset();
+                }
+                Action::ResetDevice => {
+                    rprintln!("HID writes keep failing, detaching from the bus");
+                    set_attached(false);
+                    reattach::spawn_after(10.millis()).ok();
+                }
+            }
+        }
+
+        cx.local
+            .usb_dev
+            .poll(&mut [cx.local.hid.class(), cx.local.serial]);
+
+        // Hand complete command frames over to `command`, which runs at a lower priority so that
+        // handling them can never delay the next report
+        let mut buf = [0u8; 64];
+        let read = cx.local.serial.read(&mut buf);
+        count_serial_fault(&read, &mut cx.shared.recovery);
+        if let Ok(count) = read {
+            let mut bytes = &buf[..count];
+            while let Some((frame, rest)) = cx.local.rx.push(bytes) {
+                // An oversized frame is forwarded empty, so that it gets answered with
+                // `Error::Malformed`
+                let frame = frame
+                    .ok()
+                    .and_then(|frame| Frame::from_slice(frame).ok())
+                    .unwrap_or_default();
+                if command::spawn(frame).is_err() {
+                    rprintln!("command dropped: previous commands still pending");
+                }
+                bytes = rest;
+            }
+        }
+
+        // Write out as much of the pending responses as the endpoint accepts, without waiting
+        cx.shared.tx.lock(|tx| {
+            let (pending, _) = tx.as_slices();
+            if pending.is_empty() {
+                return;
+            }
+            let written = cx.local.serial.write(pending);
+            count_serial_fault(&written, &mut cx.shared.recovery);
+            if let Ok(count) = written {
+                for _ in 0..count {
+                    tx.pop_front();
+                }
+            }
         });
+    }
 
-        // Pull the ADC data out of the buffer that the DMA transfer gave us
-        let raw_volt1 = buffer[0];
-        let raw_volt2 = buffer[1];
-        let raw_volt3 = buffer[2];
-        let raw_volt4 = buffer[3];
+    /// Puts ADC1 input `input` at `rank` of the regular sequence, counted from 0, with the
+    /// longest sampling time
+    ///
+    /// `Adc::configure_channel` only takes inputs that are types of their own, while the sensors
+    /// of the board are a list.
+    fn configure_input(input: u8, rank: usize) {
+        let (input, rank) = (u32::from(input), rank as u32);
+        let replace = |bits: u32, width: u32, offset: u32, value: u32| {
+            let shift = width * offset;
+            bits & !(((1 << width) - 1) << shift) | value << shift
+        };
+        // Sampling time of 480 cycles
+        let sample_time = 0b111;
+        // SAFETY: the ADC is not converting yet, and nothing else touches its configuration
+        unsafe {
+            let adc = &*pac::ADC1::ptr();
+            match rank {
+                0..=5 => adc
+                    .sqr3()
+                    .modify(|r, w| w.bits(replace(r.bits(), 5, rank, input))),
+                6..=11 => adc
+                    .sqr2()
+                    .modify(|r, w| w.bits(replace(r.bits(), 5, rank - 6, input))),
+                _ => adc
+                    .sqr1()
+                    .modify(|r, w| w.bits(replace(r.bits(), 5, rank - 12, input))),
+            };
+            match input {
+                0..=9 => adc
+                    .smpr2()
+                    .modify(|r, w| w.bits(replace(r.bits(), 3, input, sample_time))),
+                _ => adc
+                    .smpr1()
+                    .modify(|r, w| w.bits(replace(r.bits(), 3, input - 10, sample_time))),
+            };
+        }
+    }
 
-        // Now that we're finished with this buffer, put it back in `local.buffer` so
-        // it's ready for the next transfer If we don't do this before the next
-        // transfer, we'll get a panic
-        *local.buffer = Some(buffer);
+    fn hid_fault(e: UsbHidError) -> Fault {
+        match e {
+            UsbHidError::WouldBlock | UsbHidError::Duplicate => Fault::Busy,
+            UsbHidError::UsbError(_) => Fault::Bus,
+            UsbHidError::SerializationError => Fault::Serialization,
+        }
+    }
 
-        // Print periodically
-        *local.dma_counter = (*local.dma_counter + 1) % 500;
-        if *local.dma_counter == 0 {
-            let voltage1 = sample_to_millivolts(raw_volt1);
-            let voltage2 = sample_to_millivolts(raw_volt2);
-            let voltage3 = sample_to_millivolts(raw_volt3);
-            let voltage4 = sample_to_millivolts(raw_volt4);
-
-            rprintln!(
-                "voltage 1: {:<4}, voltage 2: {:<4}, voltage 3: {:<4}, voltage 4: {:<4}",
-                voltage1,
-                voltage2,
-                voltage3,
-                voltage4
-            );
+    /// Counts a failed transfer on the configuration channel. `WouldBlock` only means that there
+    /// was nothing to transfer.
+    fn count_serial_fault<T>(
+        result: &Result<T, UsbError>,
+        recovery: &mut impl Mutex<T = Recovery>,
+    ) {
+        if matches!(result, Err(e) if *e != UsbError::WouldBlock) {
+            recovery.lock(|recovery| recovery.fault(Fault::Serial));
         }
     }
 
-    #[task(binds = TIM2, local = [timer, usb_dev, joy], shared = [adc_values])]
-    fn usb_report(mut cx: usb_report::Context) {
-        let timer = cx.local.timer;
-
-        let values = cx.shared.adc_values.lock(|vals| vals.clone());
-        // Poll every 1ms
-        match cx
-            .local
-            .joy
-            .device()
-            .write_report(&crate::get_report(&values))
-        {
-            Err(UsbHidError::WouldBlock) => {}
-            Ok(_) => {}
-            Err(e) => {
-                core::panic!("Failed to write joystick report: {:?}", e)
+    #[task(
+        capacity = 2,
+        local = [flash, enumerated],
+        shared = [pipeline, config, tx, recovery, latency, conditions, tare]
+    )]
+    fn command(mut cx: command::Context, mut frame: Frame) {
+        let response = match abi::decode::<Command>(&mut frame) {
+            Ok(Command::GetInfo) => Response::Info(FirmwareInfo {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: env!("CARGO_PKG_VERSION").try_into().unwrap_or_default(),
+                channels: CHANNELS as u8,
+            }),
+            Ok(Command::GetThresholds) => cx
+                .shared
+                .config
+                .lock(|config| Response::Thresholds(config.thresholds.clone())),
+            Ok(Command::SetThresholds {
+                channel,
+                thresholds,
+            }) => cx.shared.config.lock(|config| {
+                let model = config.force.get(channel as usize).and_then(Option::as_ref);
+                let full_scale = force::full_scale(model, PIPELINE_SAMPLES);
+                match config.thresholds.get_mut(channel as usize) {
+                    Some(_) if !thresholds.is_valid() || thresholds.press > full_scale => {
+                        Response::Error(Error::InvalidValue)
+                    }
+                    Some(t) => {
+                        *t = thresholds;
+                        Response::Ok
+                    }
+                    None => Response::Error(Error::InvalidChannel),
+                }
+            }),
+            Ok(Command::GetValues) => cx.shared.pipeline.lock(|pipeline| {
+                Response::Values(Channels::from_slice(pipeline.values()).unwrap())
+            }),
+            Ok(Command::GetBaselines) => cx.shared.pipeline.lock(|pipeline| {
+                Response::Baselines(match pipeline.baselines() {
+                    Some(baselines) => Channels::from_slice(&baselines).unwrap(),
+                    None => Channels::new(),
+                })
+            }),
+            Ok(Command::GetUsbErrors) => cx
+                .shared
+                .recovery
+                .lock(|recovery| Response::UsbErrors(*recovery.errors())),
+            Ok(Command::GetLatency) => cx
+                .shared
+                .latency
+                .lock(|stats| Response::Latency(stats.summary())),
+            Ok(Command::ResetLatency) => {
+                cx.shared.latency.lock(|stats| *stats = LatencyStats::new());
+                Response::Ok
+            }
+            Ok(Command::GetConditions) => cx
+                .shared
+                .conditions
+                .lock(|conditions| Response::Conditions(conditions.conditions())),
+            Ok(Command::GetHealth) => cx.shared.pipeline.lock(|pipeline| {
+                Response::Health(Channels::from_slice(&pipeline.health()).unwrap())
+            }),
+            Ok(Command::GetConfig) => cx
+                .shared
+                .config
+                .lock(|config| Response::Config(config.clone())),
+            Ok(Command::SetConfig(new)) => {
+                if !new.has_inputs(CHANNELS, SWITCHES) {
+                    Response::Error(Error::InvalidChannel)
+                } else if !new.is_valid()
+                    || new.thresholds.iter().zip(&new.force).any(|(t, model)| {
+                        t.press > force::full_scale(model.as_ref(), PIPELINE_SAMPLES)
+                    })
+                {
+                    Response::Error(Error::InvalidValue)
+                } else if !new.enumerates_like(cx.local.enumerated) {
+                    // The host only learns about the new descriptors by enumerating the pad
+                    // again, so the configuration is saved to survive the restart
+                    match CONFIG_STORE.save(&mut cx.local.flash.unlocked(), &new) {
+                        Ok(()) => {
+                            reboot::spawn_after(100.millis()).ok();
+                            Response::Ok
+                        }
+                        Err(e) => {
+                            rprintln!("failed to save configuration: {:?}", e);
+                            Response::Error(Error::Storage)
+                        }
+                    }
+                } else {
+                    cx.shared.config.lock(|config| *config = new);
+                    Response::Ok
+                }
             }
+            Ok(Command::SaveConfig) => {
+                let config = cx.shared.config.lock(|config| config.clone());
+                // Programming, and once in a while erasing, stalls the CPU while it runs from
+                // flash. The log keeps erases rare enough for this not to matter in practice.
+                match CONFIG_STORE.save(&mut cx.local.flash.unlocked(), &config) {
+                    Ok(()) => Response::Ok,
+                    Err(e) => {
+                        rprintln!("failed to save configuration: {:?}", e);
+                        Response::Error(Error::Storage)
+                    }
+                }
+            }
+            Ok(Command::LoadConfig) => match CONFIG_STORE.load(cx.local.flash) {
+                Ok(Some(stored)) if stored.has_inputs(CHANNELS, SWITCHES) => {
+                    if !stored.enumerates_like(cx.local.enumerated) {
+                        reboot::spawn_after(100.millis()).ok();
+                    }
+                    cx.shared.config.lock(|config| *config = stored);
+                    Response::Ok
+                }
+                Ok(_) => Response::Error(Error::Storage),
+                Err(e) => {
+                    rprintln!("failed to load configuration: {:?}", e);
+                    Response::Error(Error::Storage)
+                }
+            },
+            Ok(Command::Reboot) => {
+                // Give `usb_report` time to flush the response before going down
+                reboot::spawn_after(100.millis()).ok();
+                Response::Ok
+            }
+            Ok(Command::Tare) if cfg!(feature = "hx711") => {
+                cx.shared.tare.lock(|tare| *tare = true);
+                Response::Ok
+            }
+            Ok(Command::Tare) => Response::Error(Error::Unsupported),
+            Err(FrameError::Version(_)) => Response::Error(Error::UnsupportedVersion),
+            Err(_) => Response::Error(Error::Malformed),
+        };
+
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let Ok(bytes) = abi::encode(&response, &mut buf) else {
+            rprintln!("failed to encode response: {:?}", response);
+            return;
+        };
+        cx.shared.tx.lock(|tx| {
+            if tx.capacity() - tx.len() < bytes.len() {
+                rprintln!("response dropped: host is not reading");
+                return;
+            }
+            for b in bytes.iter() {
+                tx.push_back(*b).ok();
+            }
+        });
+        // Have `usb_report` write it out now rather than at the next start of frame
+        rtic::pend(pac::Interrupt::OTG_FS);
+    }
+
+    /// Blinks the status LED while any sensor is faulty, and keeps it dark otherwise
+    #[task(shared = [pipeline], local = [led, faulty])]
+    fn status(mut cx: status::Context) {
+        let (health, faulty) = cx
+            .shared
+            .pipeline
+            .lock(|pipeline| (pipeline.health(), pipeline.faulty()));
+        if faulty != *cx.local.faulty {
+            rprintln!("sensor health: {:?}", health);
+            *cx.local.faulty = faulty;
+        }
+        if faulty == 0 {
+            cx.local.led.set_high();
+        } else {
+            cx.local.led.toggle();
         }
+        status::spawn_after(BLINK_MS.millis()).ok();
+    }
 
-        if cx.local.usb_dev.poll(&mut [cx.local.joy]) {}
+    /// Attaches to or detaches from the bus through the soft disconnect bit
+    fn set_attached(attached: bool) {
+        // SAFETY: only the soft disconnect bit is touched, which the USB driver leaves alone
+        unsafe {
+            (*pac::OTG_FS_DEVICE::ptr())
+                .dctl()
+                .modify(|_, w| w.sdis().bit(!attached))
+        };
+    }
+
+    /// Attaches to the bus again after recovering from USB errors. The host sees the pad come
+    /// back and enumerates it afresh.
+    #[task]
+    fn reattach(_: reattach::Context) {
+        set_attached(true);
+    }
+
+    /// Detaches from the bus before restarting, so that the host sees the pad go away and
+    /// enumerates it afresh instead of talking to it with stale descriptors
+    #[task]
+    fn reboot(_: reboot::Context) {
+        set_attached(false);
+        reset::spawn_after(100.millis()).ok();
+    }
 
-        // Clear the timer interrupt flag
-        timer.clear_all_flags();
+    #[task]
+    fn reset(_: reset::Context) {
+        cortex_m::peripheral::SCB::sys_reset();
     }
 }